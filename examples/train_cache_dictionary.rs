@@ -0,0 +1,99 @@
+//! Train a `zstd` dictionary for compressing cache entries.
+//!
+//! This reads the CSV output of a previous `geocode-csv` run on standard
+//! input, and encodes the columns for `--prefix` exactly as our cache would
+//! store them. The trained dictionary is written to `--output`. For example:
+//!
+//! ```sh
+//! mkdir -p src/geocoders/cache/dictionaries
+//! cargo run --release --example train_cache_dictionary -- \
+//!     --prefix=gc --output=src/geocoders/cache/dictionaries/v3.dict \
+//!     < geocoded.csv
+//! ```
+//!
+//! To use the new dictionary, add it to the end of `DICTIONARIES` in
+//! `src/geocoders/cache/compression.rs`, using the next unused ASCII digit as
+//! its ID. Never remove or change an existing dictionary, because we need it
+//! to read old cache entries.
+
+use std::{fs, io, path::PathBuf};
+
+use anyhow::{format_err, Context, Result};
+use clap::Parser;
+
+/// Command-line options.
+#[derive(Debug, Parser)]
+#[command(about = "Train a zstd dictionary for geocode-csv cache entries")]
+struct Opt {
+    /// The prefix of the geocoded columns to train on.
+    #[arg(long = "prefix", default_value = "gc")]
+    prefix: String,
+
+    /// Where to write our dictionary.
+    #[arg(long = "output")]
+    output: PathBuf,
+
+    /// The maximum size of our dictionary, in bytes.
+    #[arg(long = "max-size", default_value = "32768")]
+    max_size: usize,
+
+    /// The maximum number of rows to train on.
+    #[arg(long = "max-samples", default_value = "100000")]
+    max_samples: usize,
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::parse();
+
+    // Find our geocoded columns, skipping any cache keys.
+    let mut rdr = csv::Reader::from_reader(io::stdin().lock());
+    let column_prefix = format!("{}_", opt.prefix);
+    let cache_key_column = format!("{}cache_key", column_prefix);
+    let indices = rdr
+        .headers()
+        .context("cannot read CSV headers")?
+        .iter()
+        .enumerate()
+        .filter(|(_, name)| {
+            name.starts_with(&column_prefix) && *name != cache_key_column
+        })
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    if indices.is_empty() {
+        return Err(format_err!("no columns start with {:?}", column_prefix));
+    }
+
+    // Encode each row the same way `Cache` does, using `None` for addresses we
+    // couldn't geocode.
+    let bincode_config = bincode::config::standard()
+        .with_little_endian()
+        .with_variable_int_encoding();
+    let mut samples = vec![];
+    for row in rdr.records().take(opt.max_samples) {
+        let row = row.context("cannot read CSV row")?;
+        let values = indices
+            .iter()
+            .map(|&idx| row[idx].to_owned())
+            .collect::<Vec<_>>();
+        let value = if values.iter().all(|v| v.is_empty()) {
+            None
+        } else {
+            Some(values)
+        };
+        samples.push(
+            bincode::encode_to_vec(&value, bincode_config)
+                .context("could not encode sample")?,
+        );
+    }
+
+    let dictionary = zstd::dict::from_samples(&samples, opt.max_size)
+        .context("could not train dictionary")?;
+    fs::write(&opt.output, &dictionary)
+        .with_context(|| format!("cannot write {}", opt.output.display()))?;
+    eprintln!(
+        "trained {} byte dictionary on {} samples",
+        dictionary.len(),
+        samples.len(),
+    );
+    Ok(())
+}
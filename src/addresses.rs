@@ -2,7 +2,7 @@
 
 use anyhow::{format_err, Context};
 use csv::StringRecord;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
     borrow::Cow,
     collections::{HashMap, HashSet},
@@ -298,7 +298,16 @@ impl<Key: Default + Eq> AddressColumnSpec<Key> {
 fn find_columns_to_remove() {
     use std::iter::FromIterator;
 
-    use crate::geocoders::{shared_http_client, smarty::Smarty, MatchStrategy};
+    use crate::geocoders::{
+        shared_http_client,
+        smarty::{client, structure::Structure, Smarty},
+        MatchStrategy,
+    };
+    use std::sync::Arc;
+    use url::Url;
+
+    use crate::pipeline::DEFAULT_BATCH_SIZE;
+    use crate::rate_limiter::AdaptiveRateLimiter;
 
     let address_column_spec_json = r#"{
         "home": {
@@ -317,8 +326,11 @@ fn find_columns_to_remove() {
     let geocoder = Smarty::new(
         MatchStrategy::Strict,
         "us-standard-cloud".to_owned(),
-        None,
-        shared_http_client(1),
+        Structure::complete().unwrap(),
+        1,
+        Url::parse(client::DEFAULT_URL).unwrap(),
+        Arc::new(AdaptiveRateLimiter::new(None, DEFAULT_BATCH_SIZE)),
+        Some(shared_http_client(1)),
     )
     .unwrap();
     let header =
@@ -327,7 +339,7 @@ fn find_columns_to_remove() {
     assert_eq!(indices, vec![("home_addressee", 1), ("work_addressee", 2)]);
 }
 
-impl AddressColumnSpec<String> {
+impl<Key: Default + Eq + DeserializeOwned> AddressColumnSpec<Key> {
     /// Load an `AddressColumnSpec` from a file.
     pub fn from_path(path: &Path) -> Result<Self> {
         let f = File::open(path)
@@ -335,6 +347,74 @@ impl AddressColumnSpec<String> {
         serde_json::from_reader(f)
             .with_context(|| format_err!("error parsing {}", path.display()))
     }
+}
+
+impl AddressColumnSpec<usize> {
+    /// Convert an `AddressColumnSpec` using column indices into one using
+    /// column names. This is used for input files without headers, where
+    /// `name_for` makes up a name for each column.
+    pub fn convert_to_names<F>(&self, name_for: F) -> AddressColumnSpec<String>
+    where
+        F: Fn(usize) -> String,
+    {
+        let address_columns_by_prefix = self
+            .address_columns_by_prefix
+            .iter()
+            .map(|(prefix, keys)| {
+                let street = match &keys.street {
+                    ColumnKeyOrKeys::Key(key) => ColumnKeyOrKeys::Key(name_for(*key)),
+                    ColumnKeyOrKeys::Keys(keys) => ColumnKeyOrKeys::Keys(
+                        keys.iter().map(|key| name_for(*key)).collect(),
+                    ),
+                };
+                let converted = AddressColumnKeys {
+                    street,
+                    city: keys.city.map(&name_for),
+                    state: keys.state.map(&name_for),
+                    zipcode: keys.zipcode.map(&name_for),
+                };
+                (prefix.to_owned(), converted)
+            })
+            .collect();
+        AddressColumnSpec {
+            address_columns_by_prefix,
+        }
+    }
+}
+
+#[test]
+fn convert_address_column_spec_to_names() {
+    let indexed: AddressColumnSpec<usize> =
+        serde_json::from_str(r#"{"home": {"address": [0, 1], "postcode": 3}}"#)
+            .unwrap();
+    let named: AddressColumnSpec<String> = serde_json::from_str(
+        r#"{"home": {"address": ["c0", "c1"], "postcode": "c3"}}"#,
+    )
+    .unwrap();
+    assert_eq!(indexed.convert_to_names(|idx| format!("c{}", idx)), named);
+}
+
+impl AddressColumnSpec<String> {
+    /// All the column names used by this spec, sorted and without duplicates.
+    pub fn column_names(&self) -> Vec<&str> {
+        let mut names = vec![];
+        for keys in self.address_columns_by_prefix.values() {
+            match &keys.street {
+                ColumnKeyOrKeys::Key(key) => names.push(&key[..]),
+                ColumnKeyOrKeys::Keys(keys) => {
+                    names.extend(keys.iter().map(|k| &k[..]))
+                }
+            }
+            names.extend(
+                [&keys.city, &keys.state, &keys.zipcode]
+                    .iter()
+                    .filter_map(|k| k.as_deref()),
+            );
+        }
+        names.sort_unstable();
+        names.dedup();
+        names
+    }
 
     /// Given an `AddressColumnSpec` using strings, and the header row of a CSV
     /// file, convert it into a `AddressColumnSpec<usize>` containing the column
@@ -407,6 +487,27 @@ fn convert_address_column_spec_to_indices() {
     );
 }
 
+#[test]
+fn address_column_spec_column_names() {
+    let address_column_spec_json = r#"{
+   "home": {
+       "house_number_and_street": ["home_number", "home_street"],
+       "city": "city",
+       "postcode": "zip"
+   },
+   "work": {
+       "address": "work_address",
+       "city": "city"
+   }
+}"#;
+    let address_column_spec: AddressColumnSpec<String> =
+        serde_json::from_str(address_column_spec_json).unwrap();
+    assert_eq!(
+        address_column_spec.column_names(),
+        &["city", "home_number", "home_street", "work_address", "zip"],
+    );
+}
+
 /// A value which can be converted from using string indices to numeric indices.
 trait ConvertToIndices {
     type Output;
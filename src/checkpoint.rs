@@ -0,0 +1,260 @@
+//! Checkpoints, which allow us to resume interrupted runs.
+//!
+//! Every so often, our output writer finishes any compressed data, flushes
+//! the output file, and records how many input rows have been written and how
+//! long the output is. If we're interrupted, `--resume` truncates the output to
+//! the last checkpoint, skips the input rows that were already written, and
+//! appends the rest. We do the same for our quarantine and rejects files.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use anyhow::{format_err, Context};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, info};
+
+use crate::geocoders::Geocoder;
+use crate::io_util::{Location, OutputStream};
+use crate::pipeline::Chunk;
+use crate::Result;
+
+/// A record of how far we've gotten.
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Checkpoint {
+    /// A fingerprint of our input and configuration. We refuse to resume if
+    /// this has changed.
+    pub fingerprint: String,
+
+    /// The number of input rows that have been written to our output,
+    /// including any bad rows that we skipped.
+    pub input_rows: u64,
+
+    /// The number of bytes of output that had been written.
+    pub output_bytes: u64,
+
+    /// The number of bytes written to our quarantine file, if we have one.
+    #[serde(default)]
+    pub quarantine_bytes: Option<u64>,
+
+    /// The number of bytes written to our rejects file, if we have one.
+    #[serde(default)]
+    pub rejects_bytes: Option<u64>,
+}
+
+impl Checkpoint {
+    /// Load a checkpoint from `path`.
+    pub fn load(path: &Path) -> Result<Checkpoint> {
+        let data = fs::read(path)
+            .with_context(|| format_err!("cannot read {}", path.display()))?;
+        serde_json::from_slice(&data)
+            .with_context(|| format_err!("cannot parse {}", path.display()))
+    }
+
+    /// Save this checkpoint to `path`. We write a temporary file and rename
+    /// it, so that we never leave a partial checkpoint behind.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        fs::write(&tmp_path, serde_json::to_vec(self)?)
+            .with_context(|| format_err!("cannot write {}", tmp_path.display()))?;
+        fs::rename(&tmp_path, path)
+            .with_context(|| format_err!("cannot write {}", path.display()))
+    }
+}
+
+#[test]
+fn checkpoint_save_and_load() {
+    let path = std::env::temp_dir().join(format!(
+        "geocode-csv-checkpoint-{}.json",
+        std::process::id()
+    ));
+    let checkpoint = Checkpoint {
+        fingerprint: "abc".to_owned(),
+        input_rows: 100,
+        output_bytes: 2000,
+        quarantine_bytes: Some(15),
+        rejects_bytes: None,
+    };
+    checkpoint.save(&path).unwrap();
+    assert_eq!(Checkpoint::load(&path).unwrap(), checkpoint);
+    fs::remove_file(&path).unwrap();
+}
+
+/// Compute a fingerprint of our `input` file, our `geocoder`, and any other
+/// `config` which affects our output. The input file is identified by its
+/// path, size and modification time, so that we don't need to read it.
+pub fn fingerprint(
+    input: &Location,
+    geocoder: &dyn Geocoder,
+    config: &str,
+) -> Result<String> {
+    let path = input
+        .path
+        .as_ref()
+        .ok_or_else(|| format_err!("cannot checkpoint standard input"))?;
+    let metadata = fs::metadata(path)
+        .with_context(|| format_err!("cannot read metadata for {}", path.display()))?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{:?}\0", input));
+    hasher.update(metadata.len().to_le_bytes());
+    hasher.update(modified.as_nanos().to_le_bytes());
+    hasher.update(geocoder.cache_prefix());
+    hasher.update([0]);
+    hasher.update(config);
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Decides when to record checkpoints while writing output.
+pub struct Checkpointer {
+    /// Where to write our checkpoints. If this is `None`, we don't record
+    /// checkpoints.
+    path: Option<PathBuf>,
+
+    /// The fingerprint of our input and configuration.
+    fingerprint: String,
+
+    /// How many input rows to write between checkpoints.
+    every: u64,
+
+    /// The checkpoint we're resuming from, if any.
+    resume_from: Option<Checkpoint>,
+
+    /// The number of input rows written at our last checkpoint.
+    last_input_rows: u64,
+
+    /// The number of input rows written so far, including any bad rows.
+    input_rows: u64,
+
+    /// The length of our quarantine file after the last chunk we wrote.
+    quarantine_bytes: Option<u64>,
+
+    /// The length of our rejects file after the last chunk we wrote.
+    rejects_bytes: Option<u64>,
+}
+
+impl Checkpointer {
+    /// A `Checkpointer` which never records anything.
+    pub fn disabled() -> Checkpointer {
+        Checkpointer {
+            path: None,
+            fingerprint: String::new(),
+            every: u64::MAX,
+            resume_from: None,
+            last_input_rows: 0,
+            input_rows: 0,
+            quarantine_bytes: None,
+            rejects_bytes: None,
+        }
+    }
+
+    /// Record checkpoints to `path` every `every` input rows. If `resume` is
+    /// true, load the existing checkpoint and make sure it matches
+    /// `fingerprint`.
+    pub fn new(
+        path: PathBuf,
+        fingerprint: String,
+        every: u64,
+        resume: bool,
+    ) -> Result<Checkpointer> {
+        let resume_from = if resume {
+            let checkpoint = Checkpoint::load(&path)?;
+            if checkpoint.fingerprint != fingerprint {
+                return Err(format_err!(
+                    "cannot resume from {}, because the input or options have changed",
+                    path.display(),
+                ));
+            }
+            info!(
+                "resuming after {} input rows ({} bytes of output)",
+                checkpoint.input_rows, checkpoint.output_bytes,
+            );
+            Some(checkpoint)
+        } else {
+            None
+        };
+        let last_input_rows = resume_from.as_ref().map_or(0, |c| c.input_rows);
+        let quarantine_bytes = resume_from.as_ref().and_then(|c| c.quarantine_bytes);
+        let rejects_bytes = resume_from.as_ref().and_then(|c| c.rejects_bytes);
+        Ok(Checkpointer {
+            path: Some(path),
+            fingerprint,
+            every: every.max(1),
+            resume_from,
+            last_input_rows,
+            input_rows: last_input_rows,
+            quarantine_bytes,
+            rejects_bytes,
+        })
+    }
+
+    /// Are we recording checkpoints?
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Are we resuming an earlier run?
+    pub fn is_resuming(&self) -> bool {
+        self.resume_from.is_some()
+    }
+
+    /// How many input rows were written before we resumed?
+    pub fn skip_rows(&self) -> u64 {
+        self.resume_from.as_ref().map_or(0, |c| c.input_rows)
+    }
+
+    /// The checkpoint we're resuming from, if any.
+    pub fn resume_from(&self) -> Option<&Checkpoint> {
+        self.resume_from.as_ref()
+    }
+
+    /// Open `output` for writing. If we're resuming, we append to the output
+    /// from our checkpoint, discarding anything written after it.
+    pub fn create_writer(&self, output: &Location) -> Result<OutputStream> {
+        match &self.resume_from {
+            Some(checkpoint) => output.append_writer(checkpoint.output_bytes),
+            None => output.create_writer(),
+        }
+    }
+
+    /// Note that we've written `chunk` to our output.
+    pub fn wrote_chunk(&mut self, chunk: &Chunk) {
+        self.input_rows = chunk.input_end;
+        self.quarantine_bytes = chunk.quarantine_end;
+        self.rejects_bytes = chunk.rejects_end;
+    }
+
+    /// Should we record a checkpoint now?
+    pub fn is_due(&self) -> bool {
+        self.is_enabled()
+            && self.input_rows.saturating_sub(self.last_input_rows) >= self.every
+    }
+
+    /// Record a checkpoint after the last chunk we wrote to `output`. The
+    /// caller must make sure that any buffered data has been written to
+    /// `output`.
+    pub fn save(&mut self, output: &mut OutputStream) -> Result<()> {
+        if let Some(path) = &self.path {
+            let checkpoint = Checkpoint {
+                fingerprint: self.fingerprint.clone(),
+                input_rows: self.input_rows,
+                output_bytes: output.checkpoint()?,
+                quarantine_bytes: self.quarantine_bytes,
+                rejects_bytes: self.rejects_bytes,
+            };
+            debug!("saving checkpoint {:?}", checkpoint);
+            checkpoint.save(path)?;
+            self.last_input_rows = self.input_rows;
+        }
+        Ok(())
+    }
+}
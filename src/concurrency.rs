@@ -0,0 +1,381 @@
+//! Adaptive limits on how many geocoding requests we run at once.
+//!
+//! Rather than asking the user to guess a good concurrency, we start with a
+//! few requests in flight, and look at how long each batch of requests takes.
+//! Like TCP congestion control, we double our limit while latency stays flat
+//! and errors are rare ("slow start"), and then add one request at a time.
+//! When latency rises well above the best we've seen, or too many requests
+//! fail with temporary errors, we cut our limit by a quarter.
+//!
+//! We only measure the time spent talking to the remote service, which
+//! geocoders report using [`record_upstream_latency`]. Time spent waiting for
+//! a rate limiter or reading from a cache would otherwise make us back off
+//! for reasons that have nothing to do with how many requests we send.
+
+use std::{
+    cmp::{max, min},
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use metrics::{describe_gauge, gauge};
+use tokio::sync::Notify;
+use tracing::debug;
+
+/// How many requests should we allow at first, if we're adapting?
+const INITIAL_LIMIT: usize = 4;
+
+/// The minimum number of requests in each measurement window.
+const MIN_WINDOW: usize = 8;
+
+/// If average latency in a window is more than this multiple of our baseline,
+/// we back off.
+const LATENCY_TOLERANCE: f64 = 1.5;
+
+/// If more than this fraction of requests in a window fail with temporary
+/// errors, we back off.
+const MAX_ERROR_RATE: f64 = 0.05;
+
+/// How quickly our baseline latency drifts up towards the latency we actually
+/// see. This allows us to adjust if the remote service gets slower for
+/// everyone, and not just because of us.
+const BASELINE_DRIFT: f64 = 0.05;
+
+tokio::task_local! {
+    /// The upstream latency reported by the request we're currently running,
+    /// if any.
+    static UPSTREAM_LATENCY: Arc<Mutex<Option<Duration>>>;
+}
+
+/// Report that the current request spent `latency` waiting on the remote
+/// service. This does nothing outside of [`ConcurrencyPermit::run`].
+pub fn record_upstream_latency(latency: Duration) {
+    let _ = UPSTREAM_LATENCY.try_with(|total| {
+        let mut total = total.lock().expect("lock poisoned");
+        *total = Some(total.unwrap_or_default() + latency);
+    });
+}
+
+/// Limits the number of geocoding requests in flight, and adjusts that limit
+/// based on latency and errors.
+pub struct ConcurrencyController {
+    /// The smallest limit we'll use.
+    min_limit: usize,
+
+    /// The largest limit we'll use.
+    max_limit: usize,
+
+    /// Our current state.
+    state: Mutex<State>,
+
+    /// Notified whenever a request finishes or our limit rises.
+    notify: Notify,
+}
+
+/// The mutable state of a `ConcurrencyController`.
+struct State {
+    /// How many requests may be in flight at once?
+    limit: usize,
+
+    /// How many requests are in flight now?
+    in_flight: usize,
+
+    /// Are we still in "slow start", doubling our limit after each window?
+    slow_start: bool,
+
+    /// The lowest average latency we've seen, drifting slowly upwards.
+    baseline: Option<Duration>,
+
+    /// Requests in our current measurement window.
+    samples: usize,
+
+    /// Requests in our current window which failed with temporary errors.
+    errors: usize,
+
+    /// Total latency of the requests in our current window.
+    total_latency: Duration,
+
+    /// Did we use our entire limit at some point during this window? If not,
+    /// our measurements don't tell us whether we could go faster.
+    saturated: bool,
+}
+
+impl ConcurrencyController {
+    /// Create a controller which adapts its limit between 1 and `max_limit`.
+    pub fn adaptive(max_limit: usize) -> ConcurrencyController {
+        Self::new(1, min(INITIAL_LIMIT, max_limit), max_limit)
+    }
+
+    /// Create a controller which always allows `limit` requests.
+    pub fn fixed(limit: usize) -> ConcurrencyController {
+        Self::new(limit, limit, limit)
+    }
+
+    /// Create a new controller.
+    fn new(min_limit: usize, limit: usize, max_limit: usize) -> ConcurrencyController {
+        describe_gauge!(
+            "geocodecsv.concurrency.limit",
+            "Current limit on geocoding requests in flight"
+        );
+        gauge!("geocodecsv.concurrency.limit", limit as f64);
+        ConcurrencyController {
+            min_limit,
+            max_limit,
+            state: Mutex::new(State {
+                limit,
+                in_flight: 0,
+                slow_start: true,
+                baseline: None,
+                samples: 0,
+                errors: 0,
+                total_latency: Duration::ZERO,
+                saturated: false,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Our current limit.
+    #[cfg(test)]
+    fn limit(&self) -> usize {
+        self.state.lock().expect("lock poisoned").limit
+    }
+
+    /// Wait until we're allowed to send another request. The request counts
+    /// as in flight until the returned permit is dropped.
+    pub async fn acquire(&self) -> ConcurrencyPermit<'_> {
+        loop {
+            // Create this before checking, so that we don't miss wakeups.
+            let notified = self.notify.notified();
+            {
+                let mut state = self.state.lock().expect("lock poisoned");
+                if state.in_flight < state.limit {
+                    state.in_flight += 1;
+                    if state.in_flight == state.limit {
+                        state.saturated = true;
+                    }
+                    return ConcurrencyPermit {
+                        controller: self,
+                        upstream_latency: Arc::default(),
+                    };
+                }
+            }
+            notified.await;
+        }
+    }
+
+    /// Record a finished request, and adjust our limit if we've seen a full
+    /// window of requests. `temporary_error` should be true if the request
+    /// failed in a way that suggests we're overloading the remote service.
+    fn record(&self, latency: Duration, temporary_error: bool) {
+        // If we're not adapting, don't bother measuring.
+        if self.min_limit == self.max_limit {
+            return;
+        }
+
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.samples += 1;
+        state.total_latency += latency;
+        if temporary_error {
+            state.errors += 1;
+        }
+        if state.samples < max(state.limit, MIN_WINDOW) {
+            return;
+        }
+
+        // Summarize our window.
+        let average = state.total_latency / state.samples as u32;
+        let error_rate = state.errors as f64 / state.samples as f64;
+        let saturated = state.saturated;
+        state.samples = 0;
+        state.errors = 0;
+        state.total_latency = Duration::ZERO;
+        state.saturated = false;
+
+        // Compare against our baseline, and then update it.
+        let baseline = state.baseline.unwrap_or(average);
+        state.baseline = Some(if average < baseline {
+            average
+        } else {
+            baseline + (average - baseline).mul_f64(BASELINE_DRIFT)
+        });
+
+        let old_limit = state.limit;
+        if error_rate > MAX_ERROR_RATE || average > baseline.mul_f64(LATENCY_TOLERANCE)
+        {
+            state.slow_start = false;
+            state.limit = max(state.limit - state.limit / 4, self.min_limit);
+            if state.limit == old_limit && state.limit > self.min_limit {
+                state.limit -= 1;
+            }
+        } else if saturated {
+            let increase = if state.slow_start { state.limit } else { 1 };
+            state.limit = min(state.limit + increase, self.max_limit);
+        }
+        if state.limit != old_limit {
+            debug!(
+                "changing concurrency from {} to {} (latency {:?}, baseline {:?}, errors {:.1}%)",
+                old_limit,
+                state.limit,
+                average,
+                baseline,
+                error_rate * 100.0,
+            );
+            gauge!("geocodecsv.concurrency.limit", state.limit as f64);
+            if state.limit > old_limit {
+                self.notify.notify_waiters();
+            }
+        }
+    }
+}
+
+/// Permission to send a single request. Dropping this without calling
+/// [`ConcurrencyPermit::finish`] releases it without recording anything.
+pub struct ConcurrencyPermit<'a> {
+    /// The controller we came from.
+    controller: &'a ConcurrencyController,
+
+    /// The upstream latency reported by our request.
+    upstream_latency: Arc<Mutex<Option<Duration>>>,
+}
+
+impl ConcurrencyPermit<'_> {
+    /// Run `request`, collecting any latency it reports using
+    /// [`record_upstream_latency`].
+    pub async fn run<F: Future>(&self, request: F) -> F::Output {
+        UPSTREAM_LATENCY
+            .scope(self.upstream_latency.clone(), request)
+            .await
+    }
+
+    /// Record that our request finished. See [`ConcurrencyController::record`].
+    ///
+    /// Requests which never reached the remote service, such as cache hits,
+    /// tell us nothing about how hard we're pushing it, so we ignore them.
+    pub fn finish(self, temporary_error: bool) {
+        let latency = *self.upstream_latency.lock().expect("lock poisoned");
+        if let Some(latency) = latency {
+            self.controller.record(latency, temporary_error);
+        }
+    }
+}
+
+impl Drop for ConcurrencyPermit<'_> {
+    fn drop(&mut self) {
+        self.controller
+            .state
+            .lock()
+            .expect("lock poisoned")
+            .in_flight -= 1;
+        self.controller.notify.notify_waiters();
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_start_then_back_off() {
+    let controller = ConcurrencyController::adaptive(48);
+    assert_eq!(controller.limit(), INITIAL_LIMIT);
+
+    // Run a full window of requests with steady latency.
+    let run_window = |latency: Duration, temporary_error: bool| {
+        let controller = &controller;
+        async move {
+            let limit = controller.limit();
+            for _ in 0..max(limit, MIN_WINDOW) / limit {
+                let mut permits = vec![];
+                for _ in 0..limit {
+                    permits.push(controller.acquire().await);
+                }
+                for permit in permits {
+                    permit.run(async { record_upstream_latency(latency) }).await;
+                    permit.finish(temporary_error);
+                }
+            }
+        }
+    };
+
+    // While latency is flat, we double our limit, up to our maximum.
+    let fast = Duration::from_millis(100);
+    run_window(fast, false).await;
+    assert_eq!(controller.limit(), 8);
+    run_window(fast, false).await;
+    assert_eq!(controller.limit(), 16);
+
+    // When latency rises, we back off, and leave slow start.
+    run_window(Duration::from_millis(500), false).await;
+    assert_eq!(controller.limit(), 12);
+    run_window(fast, false).await;
+    assert_eq!(controller.limit(), 13);
+
+    // Errors also make us back off.
+    run_window(fast, true).await;
+    assert_eq!(controller.limit(), 10);
+}
+
+#[tokio::test(start_paused = true)]
+async fn only_upstream_latency_counts() {
+    use tokio::time::sleep;
+
+    let controller = ConcurrencyController::adaptive(48);
+    let fast = Duration::from_millis(100);
+    let run_window = |wait: Duration, latency: Option<Duration>| {
+        let controller = &controller;
+        async move {
+            for _ in 0..MIN_WINDOW {
+                let permit = controller.acquire().await;
+                permit
+                    .run(async {
+                        // Waiting before our request, say for a rate limiter,
+                        // doesn't count.
+                        sleep(wait).await;
+                        if let Some(latency) = latency {
+                            record_upstream_latency(latency);
+                        }
+                    })
+                    .await;
+                permit.finish(false);
+            }
+        }
+    };
+
+    // Establish our baseline.
+    run_window(Duration::ZERO, Some(fast)).await;
+
+    // Long waits before fast requests don't make us back off.
+    run_window(Duration::from_secs(5), Some(fast)).await;
+
+    // Neither do requests which never reach the remote service, whether they
+    // were quick or slow.
+    run_window(Duration::ZERO, None).await;
+    run_window(Duration::from_secs(5), None).await;
+
+    // None of this saturated our limit, so it never changed.
+    assert_eq!(controller.limit(), INITIAL_LIMIT);
+
+    // But slow upstream requests do make us back off.
+    run_window(Duration::ZERO, Some(Duration::from_millis(500))).await;
+    assert_eq!(controller.limit(), INITIAL_LIMIT - 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn acquire_waits_for_limit() {
+    use std::sync::Arc;
+    use tokio::time::{sleep, timeout};
+
+    let controller = Arc::new(ConcurrencyController::fixed(1));
+    let permit = controller.acquire().await;
+
+    // A second request has to wait for the first.
+    let controller2 = controller.clone();
+    let waiting = tokio::spawn(async move {
+        controller2.acquire().await.finish(false);
+    });
+    sleep(Duration::from_secs(1)).await;
+    assert!(!waiting.is_finished());
+    permit.finish(false);
+    timeout(Duration::from_secs(1), waiting)
+        .await
+        .expect("should acquire after release")
+        .unwrap();
+}
@@ -1,5 +1,7 @@
 //! Error-handling utilities.
 
+use std::{error, fmt, time::Duration};
+
 use anyhow::Error;
 
 /// Display an error, plus all the underlying "causes" (ie, wrapped errors), plus a
@@ -19,3 +21,44 @@ pub(crate) fn hyper_error_description_for_metrics(err: &hyper::Error) -> String
     let cut_at = display_err.find(':').unwrap_or(display_err.len());
     display_err[..cut_at].to_owned()
 }
+
+/// A remote service asked us to slow down. Unlike other errors, this doesn't
+/// mean anything is broken, so we can keep retrying once we've waited.
+#[derive(Debug)]
+pub struct Throttled {
+    /// The HTTP status we received.
+    pub status: hyper::StatusCode,
+
+    /// How long the service asked us to wait, if it told us.
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for Throttled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "throttled by remote service: {}", self.status)?;
+        if let Some(retry_after) = self.retry_after {
+            write!(f, " (retry after {} secs)", retry_after.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Throttled {}
+
+/// A remote service returned an HTTP error.
+#[derive(Debug)]
+pub struct RemoteStatusError {
+    /// The HTTP status we received.
+    pub status: hyper::StatusCode,
+
+    /// The body of the response, which may explain what went wrong.
+    pub body: String,
+}
+
+impl fmt::Display for RemoteStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "geocoding error: {}\n{}", self.status, self.body)
+    }
+}
+
+impl error::Error for RemoteStatusError {}
@@ -0,0 +1,385 @@
+//! Parquet and Arrow IPC input and output. These formats have typed columns,
+//! which we try to preserve.
+
+use std::{cmp::min, fmt::Write as _, sync::Arc};
+
+use anyhow::{format_err, Context};
+use arrow::{
+    array::{new_empty_array, ArrayRef, BooleanArray, Float64Array, StringArray},
+    compute::cast,
+    datatypes::{DataType, Field, Schema, SchemaRef},
+    error::ArrowError,
+    ipc::{reader::FileReader, writer::FileWriter},
+    record_batch::RecordBatch,
+    util::display::{ArrayFormatter, FormatOptions},
+};
+use csv::StringRecord;
+use parquet::{
+    arrow::{arrow_reader::ParquetRecordBatchReaderBuilder, ArrowWriter},
+    basic::Compression as ParquetCompression,
+    file::properties::WriterProperties,
+};
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::addresses::AddressColumnSpec;
+use crate::geocoders::{ColumnType, Geocoder};
+use crate::io_util::{Location, OutputStream};
+use crate::pipeline::{
+    prepare_input, ChunkSender, ColumnOptions, Message, PreparedInput, Shared,
+    SourceRows,
+};
+use crate::progress::PROGRESS;
+use crate::Result;
+
+use super::{bad_rows::BadRows, for_each_chunk, parse_bool, rejects::Rejects, Format};
+
+/// Read a Parquet or Arrow IPC file from `input` and write it as messages to
+/// `tx`.
+#[allow(clippy::too_many_arguments)]
+pub fn read_arrow(
+    format: Format,
+    spec: AddressColumnSpec<String>,
+    geocoder: &dyn Geocoder,
+    input: &Location,
+    column_options: ColumnOptions,
+    batch_size: usize,
+    bad_rows: BadRows,
+    skip_rows: u64,
+    tx: Sender<Message>,
+) -> Result<()> {
+    let file = input.open_file()?;
+    match format {
+        Format::Parquet => {
+            let builder = ParquetRecordBatchReaderBuilder::try_new(file)
+                .context("cannot read Parquet metadata")?;
+            let schema = builder.schema().clone();
+            PROGRESS
+                .set_input_rows(builder.metadata().file_metadata().num_rows() as u64);
+            let prepared = prepare_input(
+                spec,
+                geocoder,
+                column_options,
+                batch_size,
+                headers_from_schema(&schema),
+                Some(schema),
+            )?;
+            // Ask Parquet for batches of exactly the size we want.
+            let reader = builder.with_batch_size(prepared.chunk_size).build()?;
+            send_batches(&prepared, reader, bad_rows, skip_rows, tx)
+        }
+        Format::Arrow => {
+            let reader = FileReader::try_new(file, None)
+                .context("cannot read Arrow IPC metadata")?;
+            let schema = reader.schema();
+            let prepared = prepare_input(
+                spec,
+                geocoder,
+                column_options,
+                batch_size,
+                headers_from_schema(&schema),
+                Some(schema),
+            )?;
+            send_batches(&prepared, reader, bad_rows, skip_rows, tx)
+        }
+        Format::Auto | Format::Csv | Format::JsonLines => {
+            unreachable!("should only be called for typed formats")
+        }
+    }
+}
+
+/// Get our column names from an Arrow schema.
+fn headers_from_schema(schema: &Schema) -> StringRecord {
+    schema.fields().iter().map(|field| field.name()).collect()
+}
+
+/// Split `batches` into chunks and send them to our geocoder. Typed formats
+/// can't contain malformed rows, so we never report anything to `bad_rows`.
+fn send_batches<I>(
+    prepared: &PreparedInput,
+    batches: I,
+    bad_rows: BadRows,
+    skip_rows: u64,
+    tx: Sender<Message>,
+) -> Result<()>
+where
+    I: Iterator<Item = Result<RecordBatch, ArrowError>>,
+{
+    let mut sender =
+        ChunkSender::new(prepared.shared.clone(), tx, skip_rows, bad_rows);
+    'batches: for batch in batches {
+        let batch = prepared.strip_batch(batch?)?;
+
+        // Some formats give us larger batches than we want, so split them up.
+        // Slicing a batch is cheap, because it doesn't copy the data.
+        let mut offset = 0;
+        while offset < batch.num_rows() {
+            let len = min(prepared.chunk_size, batch.num_rows() - offset);
+            let skip = sender.read_rows(len);
+            if skip < len {
+                let slice = batch.slice(offset + skip, len - skip);
+                let rows = rows_from_batch(&slice)?;
+                sender.send(rows, SourceRows::Arrow(slice))?;
+                if sender.is_stopping() {
+                    break 'batches;
+                }
+            }
+            offset += len;
+        }
+    }
+    sender.finish(vec![], SourceRows::Text)
+}
+
+/// Convert every value in `batch` to a string, so that we can extract
+/// addresses and append our geocoded columns.
+fn rows_from_batch(batch: &RecordBatch) -> Result<Vec<StringRecord>> {
+    // Null values will be formatted as empty strings.
+    let options = FormatOptions::default();
+    let formatters = batch
+        .columns()
+        .iter()
+        .map(|column| ArrayFormatter::try_new(column.as_ref(), &options))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut rows = Vec::with_capacity(batch.num_rows());
+    let mut buf = String::with_capacity(64);
+    for i in 0..batch.num_rows() {
+        let mut row = StringRecord::with_capacity(64, formatters.len());
+        for formatter in &formatters {
+            buf.clear();
+            write!(buf, "{}", formatter.value(i))?;
+            row.push_field(&buf);
+        }
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+/// Receive chunks from `rx` and write them to `output` as a Parquet or Arrow
+/// IPC file.
+pub fn write_arrow(
+    format: Format,
+    output: &Location,
+    rejects: Option<Rejects>,
+    rx: Receiver<Message>,
+) -> Result<()> {
+    let mut writer: Option<(SchemaRef, TypedWriter)> = None;
+    for_each_chunk(rx, rejects, |chunk| {
+        // Wait until we see our first chunk to create our output, because we
+        // need to know the schema.
+        if writer.is_none() {
+            let schema = output_schema(&chunk.shared);
+            let typed_writer = TypedWriter::new(format, output, schema.clone())?;
+            writer = Some((schema, typed_writer));
+        }
+        let (schema, typed_writer) = writer.as_mut().expect("writer should exist");
+        let batch =
+            batch_from_rows(schema, &chunk.shared, &chunk.rows, &chunk.source)?;
+        typed_writer.write(&batch)
+    })?;
+    let (_, typed_writer) =
+        writer.ok_or_else(|| format_err!("did not receive any output chunks"))?;
+    typed_writer.finish()
+}
+
+/// Build the schema for our output file. Input columns keep their original
+/// types (or become strings, if the input was untyped), and geocoded columns
+/// get the types supplied by our geocoder.
+fn output_schema(shared: &Shared) -> SchemaRef {
+    let mut fields = match &shared.in_schema {
+        Some(schema) => schema
+            .fields()
+            .iter()
+            .map(|field| field.as_ref().clone())
+            .collect::<Vec<_>>(),
+        None => shared
+            .out_headers
+            .iter()
+            .take(shared.in_column_count)
+            .map(|name| Field::new(name, DataType::Utf8, true))
+            .collect(),
+    };
+    for (name, column_type) in shared
+        .out_headers
+        .iter()
+        .skip(shared.in_column_count)
+        .zip(&shared.geocoded_column_types)
+    {
+        let data_type = match column_type {
+            ColumnType::Text => DataType::Utf8,
+            ColumnType::Float => DataType::Float64,
+            ColumnType::Boolean => DataType::Boolean,
+        };
+        fields.push(Field::new(name, data_type, true));
+    }
+    Arc::new(Schema::new(fields))
+}
+
+/// Convert the geocoded `rows` of a chunk into a `RecordBatch` matching
+/// `schema`.
+fn batch_from_rows(
+    schema: &SchemaRef,
+    shared: &Shared,
+    rows: &[StringRecord],
+    source: &SourceRows,
+) -> Result<RecordBatch> {
+    let in_column_count = shared.in_column_count;
+
+    // Get our input columns, preferably from the original typed data.
+    let mut columns = match source {
+        SourceRows::Arrow(batch) => batch.columns().to_vec(),
+        SourceRows::Text | SourceRows::Json(_) => (0..in_column_count)
+            .map(|i| {
+                let data_type = schema.field(i).data_type();
+                if rows.is_empty() {
+                    return Ok(new_empty_array(data_type));
+                }
+                let values =
+                    StringArray::from_iter_values(rows.iter().map(|row| &row[i]));
+                if data_type == &DataType::Utf8 {
+                    Ok(Arc::new(values) as ArrayRef)
+                } else {
+                    Ok(cast(&values, data_type)?)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?,
+    };
+
+    // Add our geocoded columns. Empty strings become nulls.
+    for (i, column_type) in shared.geocoded_column_types.iter().enumerate() {
+        let values = rows.iter().map(|row| &row[in_column_count + i]);
+        let column: ArrayRef = match column_type {
+            ColumnType::Text => Arc::new(
+                values
+                    .map(|v| if v.is_empty() { None } else { Some(v) })
+                    .collect::<StringArray>(),
+            ),
+            ColumnType::Float => Arc::new(
+                values
+                    .map(|v| v.parse::<f64>().ok())
+                    .collect::<Float64Array>(),
+            ),
+            ColumnType::Boolean => {
+                Arc::new(values.map(parse_bool).collect::<BooleanArray>())
+            }
+        };
+        columns.push(column);
+    }
+
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+#[test]
+fn typed_round_trip() {
+    use arrow::array::{Array, Int64Array};
+
+    let in_schema = Arc::new(Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("address", DataType::Utf8, true),
+    ]));
+    let shared = Shared {
+        spec: serde_json::from_str(r#"{"gc": {"house_number_and_street": 1}}"#)
+            .unwrap(),
+        out_headers: StringRecord::from(vec![
+            "id",
+            "address",
+            "gc_city",
+            "gc_latitude",
+            "gc_dst",
+        ]),
+        in_column_count: 2,
+        in_schema: Some(in_schema),
+        geocoded_column_types: vec![
+            ColumnType::Text,
+            ColumnType::Float,
+            ColumnType::Boolean,
+        ],
+        geocoder_column_names: vec![
+            "city".to_owned(),
+            "latitude".to_owned(),
+            "dst".to_owned(),
+        ],
+        on_error: crate::pipeline::OnError::Fail,
+    };
+    let rows = vec![
+        StringRecord::from(vec!["1", "20 W 34th St", "New York", "40.7", "T"]),
+        StringRecord::from(vec!["2", "", "", "", ""]),
+    ];
+
+    let schema = output_schema(&shared);
+    let batch = batch_from_rows(&schema, &shared, &rows, &SourceRows::Text).unwrap();
+    assert_eq!(batch.schema().field(0).data_type(), &DataType::Int64);
+    assert_eq!(batch.schema().field(3).data_type(), &DataType::Float64);
+    assert_eq!(batch.schema().field(4).data_type(), &DataType::Boolean);
+    let ids = batch
+        .column(0)
+        .as_any()
+        .downcast_ref::<Int64Array>()
+        .unwrap();
+    assert_eq!(ids.value(1), 2);
+    let latitudes = batch
+        .column(3)
+        .as_any()
+        .downcast_ref::<Float64Array>()
+        .unwrap();
+    assert_eq!(latitudes.value(0), 40.7);
+    assert!(latitudes.is_null(1));
+    assert!(batch.column(2).is_null(1));
+
+    // Converting back to strings should give us our original rows, because
+    // nulls become empty strings. But Arrow formats booleans differently.
+    let round_tripped = rows_from_batch(&batch).unwrap();
+    assert_eq!(round_tripped[0].get(4), Some("true"));
+    assert_eq!(round_tripped[1], rows[1]);
+}
+
+/// A writer for one of our typed output formats.
+enum TypedWriter {
+    Parquet(ArrowWriter<OutputStream>),
+    Arrow(FileWriter<OutputStream>),
+}
+
+impl TypedWriter {
+    /// Create a new writer for `format`.
+    fn new(format: Format, output: &Location, schema: SchemaRef) -> Result<Self> {
+        let stream = output.create_writer()?;
+        match format {
+            Format::Parquet => {
+                let props = WriterProperties::builder()
+                    .set_compression(ParquetCompression::SNAPPY)
+                    .build();
+                Ok(TypedWriter::Parquet(ArrowWriter::try_new(
+                    stream,
+                    schema,
+                    Some(props),
+                )?))
+            }
+            Format::Arrow => {
+                Ok(TypedWriter::Arrow(FileWriter::try_new(stream, &schema)?))
+            }
+            Format::Auto | Format::Csv | Format::JsonLines => {
+                unreachable!("should only be called for typed formats")
+            }
+        }
+    }
+
+    /// Write a batch of records.
+    fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        match self {
+            TypedWriter::Parquet(wtr) => wtr.write(batch)?,
+            TypedWriter::Arrow(wtr) => wtr.write(batch)?,
+        }
+        Ok(())
+    }
+
+    /// Write any file footers and finish our output.
+    fn finish(self) -> Result<()> {
+        let stream = match self {
+            TypedWriter::Parquet(wtr) => wtr.into_inner()?,
+            TypedWriter::Arrow(mut wtr) => {
+                wtr.finish()?;
+                wtr.into_inner()?
+            }
+        };
+        stream.finish()
+    }
+}
@@ -0,0 +1,211 @@
+//! Handling malformed input rows.
+
+use std::{
+    cell::RefCell,
+    fs::File,
+    io::{self, Read, Seek},
+    path::Path,
+    rc::Rc,
+};
+
+use anyhow::{format_err, Context, Error};
+use metrics::{counter, describe_counter};
+use strum_macros::EnumString;
+use tracing::{info, warn};
+
+use crate::io_util::reopen_at;
+use crate::Result;
+
+/// What should we do with input rows that we can't parse?
+#[derive(Clone, Copy, Debug, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum OnBadRow {
+    /// Fail with an error.
+    Fail,
+    /// Log a warning and skip the row.
+    Skip,
+    /// Skip the row, and write it to a quarantine file.
+    Quarantine,
+}
+
+/// Keeps track of malformed input rows, and decides what to do with them.
+pub struct BadRows {
+    /// What to do with bad rows.
+    on_bad_row: OnBadRow,
+
+    /// Where to write bad rows, if we're quarantining them.
+    quarantine: Option<csv::Writer<File>>,
+
+    /// How many bad rows have we seen?
+    count: u64,
+}
+
+impl BadRows {
+    /// Create a new `BadRows` handler. If `on_bad_row` is
+    /// [`OnBadRow::Quarantine`], then `quarantine_path` must be specified, and
+    /// we'll create a CSV file there with the columns `line`, `error` and
+    /// `raw`. If `resume_at` is specified, we're resuming from a checkpoint,
+    /// so we keep the first `resume_at` bytes of the existing quarantine file,
+    /// and append to it.
+    pub fn new(
+        on_bad_row: OnBadRow,
+        quarantine_path: Option<&Path>,
+        resume_at: Option<u64>,
+    ) -> Result<BadRows> {
+        describe_counter!(
+            "geocodecsv.bad_rows.total",
+            "Malformed input rows which were skipped or quarantined"
+        );
+
+        let quarantine = match (on_bad_row, quarantine_path) {
+            (OnBadRow::Quarantine, Some(path)) if resume_at.is_some() => {
+                let file = reopen_at(path, resume_at.unwrap_or_default())?;
+                Some(csv::Writer::from_writer(file))
+            }
+            (OnBadRow::Quarantine, Some(path)) => {
+                let mut wtr = csv::Writer::from_path(path).with_context(|| {
+                    format_err!("cannot create {}", path.display())
+                })?;
+                wtr.write_record(["line", "error", "raw"])?;
+                Some(wtr)
+            }
+            (OnBadRow::Quarantine, None) => {
+                return Err(format_err!(
+                    "--on-bad-row=quarantine requires --quarantine-path"
+                ));
+            }
+            (_, Some(_)) => {
+                return Err(format_err!(
+                    "--quarantine-path requires --on-bad-row=quarantine"
+                ));
+            }
+            (_, None) => None,
+        };
+        Ok(BadRows {
+            on_bad_row,
+            quarantine,
+            count: 0,
+        })
+    }
+
+    /// Do we need the raw bytes of bad rows?
+    pub fn wants_raw_bytes(&self) -> bool {
+        self.quarantine.is_some()
+    }
+
+    /// Flush our quarantine file, and return its length, so that we can
+    /// resume from this point. Returns `None` if we're not quarantining bad
+    /// rows.
+    pub fn checkpoint(&mut self) -> Result<Option<u64>> {
+        match &mut self.quarantine {
+            Some(wtr) => {
+                wtr.flush().context("could not flush quarantine file")?;
+                let mut file = wtr.get_ref();
+                Ok(Some(file.stream_position()?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Handle a bad row starting on `line`, which failed with `err`. This
+    /// returns an error if we're supposed to fail.
+    pub fn handle(&mut self, line: u64, raw: &[u8], err: Error) -> Result<()> {
+        if self.on_bad_row == OnBadRow::Fail {
+            return Err(err.context(format!("bad input row on line {}", line)));
+        }
+
+        self.count += 1;
+        counter!("geocodecsv.bad_rows.total", 1);
+        warn!("skipping bad input row on line {}: {:#}", line, err);
+        if let Some(wtr) = &mut self.quarantine {
+            let line = line.to_string();
+            let err = format!("{:#}", err);
+            wtr.write_record([line.as_bytes(), err.as_bytes(), raw])?;
+        }
+        Ok(())
+    }
+
+    /// Flush our quarantine file and report how many rows we skipped.
+    pub fn finish(self) -> Result<()> {
+        if let Some(mut wtr) = self.quarantine {
+            wtr.flush().context("could not flush quarantine file")?;
+        }
+        if self.count > 0 {
+            info!("skipped {} bad input rows", self.count);
+        }
+        Ok(())
+    }
+}
+
+/// A reader which keeps a copy of recently read bytes, so that we can recover
+/// the raw text of bad rows. Our CSV parser reads ahead, so we need to keep
+/// everything after the start of the current row.
+pub struct RecordingReader<R> {
+    /// The reader we wrap.
+    inner: R,
+
+    /// Our recorded bytes, which we share with a [`Recording`].
+    recording: Rc<RefCell<Recording>>,
+}
+
+impl<R: Read> RecordingReader<R> {
+    /// Wrap `inner`, returning the reader and a handle to the recorded bytes.
+    pub fn new(inner: R) -> (Self, Rc<RefCell<Recording>>) {
+        let recording = Rc::new(RefCell::new(Recording::default()));
+        let rdr = RecordingReader {
+            inner,
+            recording: recording.clone(),
+        };
+        (rdr, recording)
+    }
+}
+
+impl<R: Read> Read for RecordingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        self.recording
+            .borrow_mut()
+            .bytes
+            .extend_from_slice(&buf[..count]);
+        Ok(count)
+    }
+}
+
+/// Bytes recorded by a [`RecordingReader`].
+#[derive(Default)]
+pub struct Recording {
+    /// The stream offset of `bytes[0]`.
+    offset: u64,
+
+    /// Bytes we haven't discarded yet.
+    bytes: Vec<u8>,
+}
+
+impl Recording {
+    /// Get the bytes between stream offsets `start` and `end`.
+    pub fn get(&self, start: u64, end: u64) -> &[u8] {
+        let start = (start.saturating_sub(self.offset) as usize).min(self.bytes.len());
+        let end =
+            (end.saturating_sub(self.offset) as usize).clamp(start, self.bytes.len());
+        &self.bytes[start..end]
+    }
+
+    /// Discard all bytes before stream offset `pos`.
+    pub fn discard_before(&mut self, pos: u64) {
+        let count = (pos.saturating_sub(self.offset) as usize).min(self.bytes.len());
+        self.bytes.drain(..count);
+        self.offset += count as u64;
+    }
+}
+
+#[test]
+fn recording_reader_keeps_bytes_until_discarded() {
+    let (mut rdr, recording) = RecordingReader::new(&b"a,b\n1,2\n"[..]);
+    let mut buf = [0; 6];
+    rdr.read_exact(&mut buf).unwrap();
+    assert_eq!(recording.borrow().get(4, 8), b"1,");
+    recording.borrow_mut().discard_before(4);
+    rdr.read_to_end(&mut vec![]).unwrap();
+    assert_eq!(recording.borrow().get(4, 8), b"1,2\n");
+    assert_eq!(recording.borrow().get(0, 2), b"");
+}
@@ -0,0 +1,320 @@
+//! CSV input and output.
+
+use std::{
+    io::{Read, Write},
+    mem,
+    str::FromStr,
+};
+
+use anyhow::{format_err, Context, Error};
+use csv::StringRecord;
+use tokio::sync::mpsc::{Receiver, Sender};
+
+use crate::addresses::AddressColumnSpec;
+use crate::checkpoint::Checkpointer;
+use crate::geocoders::Geocoder;
+use crate::io_util::{Location, OutputStream};
+use crate::pipeline::{
+    prepare_input, ChunkSender, ColumnOptions, Message, SourceRows,
+};
+use crate::Result;
+
+use super::{
+    bad_rows::{BadRows, RecordingReader},
+    for_each_chunk,
+    rejects::Rejects,
+};
+
+/// How to read or write a CSV file.
+#[derive(Clone, Debug)]
+pub struct CsvDialect {
+    /// The field delimiter.
+    pub delimiter: u8,
+
+    /// The quote character.
+    pub quote: u8,
+
+    /// The character used to escape quotes inside quoted fields. If this is
+    /// `None`, quotes are escaped by doubling them.
+    pub escape: Option<u8>,
+
+    /// Does this file have a header row? If not, input columns are named using
+    /// [`headerless_column_name`].
+    pub has_headers: bool,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        CsvDialect {
+            delimiter: b',',
+            quote: b'"',
+            escape: None,
+            has_headers: true,
+        }
+    }
+}
+
+impl CsvDialect {
+    /// Create a CSV reader using this dialect. We always treat the first row
+    /// as data, and handle headers ourselves.
+    fn reader<R: Read>(&self, rdr: R) -> csv::Reader<R> {
+        csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .escape(self.escape)
+            .has_headers(false)
+            .from_reader(rdr)
+    }
+
+    /// Create a CSV writer using this dialect.
+    fn writer<W: Write>(&self, wtr: W) -> csv::Writer<W> {
+        let mut builder = csv::WriterBuilder::new();
+        builder.delimiter(self.delimiter).quote(self.quote);
+        if let Some(escape) = self.escape {
+            builder.escape(escape).double_quote(false);
+        }
+        builder.from_writer(wtr)
+    }
+}
+
+/// The name we give to column `idx` of a file without headers. Address specs
+/// for these files refer to columns by index, which we convert to names using
+/// this function.
+pub fn headerless_column_name(idx: usize) -> String {
+    format!("column_{}", idx)
+}
+
+/// A single-byte character used in a CSV dialect. (Helper struct for argument
+/// parsing.)
+#[derive(Clone, Copy, Debug)]
+pub struct CsvChar(pub u8);
+
+impl FromStr for CsvChar {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tab" | "\\t" => Ok(CsvChar(b'\t')),
+            _ if s.len() == 1 && s.is_ascii() => Ok(CsvChar(s.as_bytes()[0])),
+            _ => Err(format_err!(
+                "expected a single ASCII character or \"tab\", found {:?}",
+                s
+            )),
+        }
+    }
+}
+
+#[test]
+fn parse_csv_char() {
+    assert_eq!(CsvChar::from_str(",").unwrap().0, b',');
+    assert_eq!(CsvChar::from_str("|").unwrap().0, b'|');
+    assert_eq!(CsvChar::from_str("tab").unwrap().0, b'\t');
+    assert_eq!(CsvChar::from_str("\\t").unwrap().0, b'\t');
+    assert!(CsvChar::from_str("").is_err());
+    assert!(CsvChar::from_str("ab").is_err());
+    assert!(CsvChar::from_str("é").is_err());
+}
+
+/// The rows of a CSV file, read using its [`CsvDialect`] and encoding, but
+/// without the rest of our pipeline. Used by tools like `cache invalidate`,
+/// which need to see addresses exactly as we do when geocoding.
+pub struct CsvRows {
+    /// Our CSV reader.
+    rdr: csv::Reader<Box<dyn Read>>,
+
+    /// Our column names.
+    headers: StringRecord,
+
+    /// The first row, if we had to read it to count our columns.
+    first_row: Option<StringRecord>,
+}
+
+impl CsvRows {
+    /// Open `input` and read its headers.
+    pub fn open(input: &Location) -> Result<CsvRows> {
+        let dialect = &input.csv_dialect;
+        let mut rdr = dialect.reader(input.open_reader()?);
+        let mut first_row = StringRecord::new();
+        let has_first_row = rdr.read_record(&mut first_row).with_context(|| {
+            format!("could not read headers from {}", input.description())
+        })?;
+        let (headers, first_row) = if dialect.has_headers {
+            (first_row, None)
+        } else {
+            let headers = (0..first_row.len()).map(headerless_column_name).collect();
+            (headers, Some(first_row).filter(|_| has_first_row))
+        };
+        Ok(CsvRows {
+            rdr,
+            headers,
+            first_row,
+        })
+    }
+
+    /// Our column names, using [`headerless_column_name`] if our file has no
+    /// headers.
+    pub fn headers(&self) -> &StringRecord {
+        &self.headers
+    }
+}
+
+impl Iterator for CsvRows {
+    type Item = Result<StringRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(row) = self.first_row.take() {
+            return Some(Ok(row));
+        }
+        let mut row = StringRecord::new();
+        match self.rdr.read_record(&mut row) {
+            Ok(true) => Some(Ok(row)),
+            Ok(false) => None,
+            Err(err) => Some(Err(err.into())),
+        }
+    }
+}
+
+/// Read a CSV file from `input` and write it as messages to `tx`.
+#[allow(clippy::too_many_arguments)]
+pub fn read_csv(
+    spec: AddressColumnSpec<String>,
+    geocoder: &dyn Geocoder,
+    input: &Location,
+    column_options: ColumnOptions,
+    batch_size: usize,
+    bad_rows: BadRows,
+    skip_rows: u64,
+    tx: Sender<Message>,
+) -> Result<()> {
+    // If we're quarantining bad rows, keep a copy of the raw input.
+    let (rdr, recording): (Box<dyn Read>, _) = if bad_rows.wants_raw_bytes() {
+        let (rdr, recording) = RecordingReader::new(input.open_reader()?);
+        (Box::new(rdr), Some(recording))
+    } else {
+        (input.open_reader()?, None)
+    };
+
+    // Open up our CSV file and get the headers. If we don't have headers,
+    // count the columns in the first row and make some up. We can't recover
+    // from errors here.
+    let dialect = &input.csv_dialect;
+    let mut rdr = dialect.reader(rdr);
+    let mut first_row = StringRecord::new();
+    let has_first_row = rdr.read_record(&mut first_row)?;
+    let in_headers = if dialect.has_headers {
+        first_row.clone()
+    } else {
+        (0..first_row.len()).map(headerless_column_name).collect()
+    };
+    let prepared =
+        prepare_input(spec, geocoder, column_options, batch_size, in_headers, None)?;
+    let chunk_size = prepared.chunk_size;
+
+    // Group up the rows into chunks and send them to `tx`.
+    let mut sender =
+        ChunkSender::new(prepared.shared.clone(), tx, skip_rows, bad_rows);
+    let mut rows = Vec::with_capacity(chunk_size);
+    if has_first_row && !dialect.has_headers && sender.read_rows(1) == 0 {
+        rows.push(prepared.strip_row(first_row));
+    }
+    let mut row = StringRecord::new();
+    loop {
+        let start = rdr.position().clone();
+        let result = rdr.read_record(&mut row);
+
+        // If we're resuming, skip any rows we've already processed.
+        let skip = !matches!(result, Ok(false)) && sender.read_rows(1) > 0;
+
+        match result {
+            _ if skip => {}
+            Ok(true) => {
+                // Strip out any duplicate columns.
+                rows.push(prepared.strip_row(mem::take(&mut row)));
+            }
+            Ok(false) => break,
+            Err(err) if is_bad_row_error(&err) => {
+                let raw = recording
+                    .as_ref()
+                    .map(|recording| {
+                        let end = rdr.position().byte();
+                        recording.borrow().get(start.byte(), end).to_owned()
+                    })
+                    .unwrap_or_default();
+                sender.bad_row(start.line(), &raw, err.into())?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+        if let Some(recording) = &recording {
+            recording.borrow_mut().discard_before(rdr.position().byte());
+        }
+
+        if rows.len() >= chunk_size {
+            sender.send(rows, SourceRows::Text)?;
+            rows = Vec::with_capacity(chunk_size);
+            if sender.is_stopping() {
+                break;
+            }
+        }
+    }
+    sender.finish(rows, SourceRows::Text)
+}
+
+/// Is `err` caused by a single malformed row, so that we can keep reading?
+fn is_bad_row_error(err: &csv::Error) -> bool {
+    matches!(
+        err.kind(),
+        csv::ErrorKind::Utf8 { .. } | csv::ErrorKind::UnequalLengths { .. }
+    )
+}
+
+/// Receive chunks of a CSV file from `rx` and write them to `output`.
+pub fn write_csv(
+    output: &Location,
+    mut checkpointer: Checkpointer,
+    rejects: Option<Rejects>,
+    rx: Receiver<Message>,
+) -> Result<()> {
+    let dialect = &output.csv_dialect;
+
+    // We need to take our `OutputStream` back from our CSV writer whenever we
+    // record a checkpoint, so keep the writer in an `Option`.
+    let mut wtr = Some(dialect.writer(checkpointer.create_writer(output)?));
+
+    // If we're resuming, we've already written our headers.
+    let mut headers_written = checkpointer.is_resuming();
+    for_each_chunk(rx, rejects, |chunk| {
+        let csv_wtr = wtr.as_mut().expect("should always have a writer");
+        if !headers_written {
+            if dialect.has_headers {
+                csv_wtr.write_record(&chunk.shared.out_headers)?;
+            }
+            headers_written = true;
+        }
+        for row in &chunk.rows {
+            csv_wtr.write_record(row)?;
+        }
+        checkpointer.wrote_chunk(&chunk);
+        if checkpointer.is_due() {
+            let csv_wtr = wtr.take().expect("should always have a writer");
+            let mut output_stream = into_output_stream(csv_wtr)?;
+            checkpointer.save(&mut output_stream)?;
+            wtr = Some(dialect.writer(output_stream));
+        }
+        Ok(())
+    })?;
+    assert!(headers_written);
+
+    // Flush our CSV writer and finish any compressed output. If we skip this,
+    // we may not notice errors until it's too late to report them.
+    let mut output_stream =
+        into_output_stream(wtr.expect("should always have a writer"))?;
+    checkpointer.save(&mut output_stream)?;
+    output_stream.finish()
+}
+
+/// Flush `wtr` and return the underlying `OutputStream`.
+fn into_output_stream(wtr: csv::Writer<OutputStream>) -> Result<OutputStream> {
+    wtr.into_inner()
+        .map_err(|err| err.into_error())
+        .context("could not flush CSV output")
+}
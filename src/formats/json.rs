@@ -0,0 +1,309 @@
+//! JSON Lines input and output.
+//!
+//! Each input line is a JSON object. We geocode the fields named by our
+//! `AddressColumnSpec`, and add the results to the object as a nested object
+//! under each prefix.
+
+use std::{
+    borrow::Cow,
+    io::{BufRead, BufReader, Write},
+};
+
+use anyhow::{format_err, Context};
+use csv::StringRecord;
+use serde_json::{Map, Number, Value};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tracing::warn;
+
+use crate::addresses::AddressColumnSpec;
+use crate::checkpoint::Checkpointer;
+use crate::geocoders::{ColumnType, Geocoder};
+use crate::io_util::Location;
+use crate::pipeline::{
+    prepare_input, ChunkSender, ColumnOptions, Message, OnDuplicateColumns, Shared,
+    SourceRows,
+};
+use crate::Result;
+
+use super::{bad_rows::BadRows, for_each_chunk, parse_bool, rejects::Rejects};
+
+/// Read a JSON Lines file from `input` and write it as messages to `tx`.
+#[allow(clippy::too_many_arguments)]
+pub fn read_json(
+    spec: AddressColumnSpec<String>,
+    geocoder: &dyn Geocoder,
+    input: &Location,
+    column_options: ColumnOptions,
+    batch_size: usize,
+    bad_rows: BadRows,
+    skip_rows: u64,
+    tx: Sender<Message>,
+) -> Result<()> {
+    let mut rdr = BufReader::new(input.open_reader()?);
+
+    // JSON objects don't have headers, so pretend that we have one column for
+    // each field mentioned in our spec.
+    let in_headers = spec.column_names().into_iter().collect::<StringRecord>();
+    let prefixes = spec
+        .prefixes()
+        .into_iter()
+        .map(|prefix| prefix.to_owned())
+        .collect::<Vec<_>>();
+    let prepared = prepare_input(
+        spec,
+        geocoder,
+        column_options,
+        batch_size,
+        in_headers.clone(),
+        None,
+    )?;
+    let chunk_size = prepared.chunk_size;
+
+    // Group up the objects into chunks and send them to `tx`.
+    let mut sender =
+        ChunkSender::new(prepared.shared.clone(), tx, skip_rows, bad_rows);
+    let mut rows = Vec::with_capacity(chunk_size);
+    let mut objects = Vec::with_capacity(chunk_size);
+    let mut warned_about_duplicates = false;
+    let mut line = vec![];
+    let mut line_number = 0;
+    loop {
+        line.clear();
+        if rdr.read_until(b'\n', &mut line)? == 0 {
+            break;
+        }
+        line_number += 1;
+        if line.iter().all(u8::is_ascii_whitespace) {
+            continue;
+        }
+
+        // If we're resuming, skip any rows we've already processed.
+        if sender.read_rows(1) > 0 {
+            continue;
+        }
+        let (row, object) = match parse_line(&line, &in_headers) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                sender.bad_row(line_number, &line, err)?;
+                continue;
+            }
+        };
+
+        // Our output will be nested under each prefix, so check for
+        // conflicts. JSON objects can't contain duplicate keys, so we treat
+        // `Append` like `Replace`.
+        for prefix in &prefixes {
+            if object.contains_key(prefix) {
+                match column_options.on_duplicate_columns {
+                    OnDuplicateColumns::Error => {
+                        return Err(format_err!(
+                            "input field {:?} on line {} would conflict with geocoding output",
+                            prefix,
+                            line_number,
+                        ));
+                    }
+                    OnDuplicateColumns::Replace | OnDuplicateColumns::Append => {
+                        if !warned_about_duplicates {
+                            warn!("replacing input field: {}", prefix);
+                            warned_about_duplicates = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        rows.push(row);
+        objects.push(object);
+        if rows.len() >= chunk_size {
+            sender.send(rows, SourceRows::Json(objects))?;
+            rows = Vec::with_capacity(chunk_size);
+            objects = Vec::with_capacity(chunk_size);
+            if sender.is_stopping() {
+                break;
+            }
+        }
+    }
+    sender.finish(rows, SourceRows::Json(objects))
+}
+
+/// Parse a line of JSON Lines input, returning the fields in `in_headers` as
+/// a row, and the original object.
+fn parse_line(
+    line: &[u8],
+    in_headers: &StringRecord,
+) -> Result<(StringRecord, Map<String, Value>)> {
+    let object = serde_json::from_slice::<Map<String, Value>>(line)
+        .context("expected JSON object")?;
+    let row = in_headers
+        .iter()
+        .map(|name| field_to_str(&object, name))
+        .collect::<Result<Vec<_>>>()?;
+    Ok((StringRecord::from(row), object))
+}
+
+/// Get the field `name` from `object` as a string, so that we can geocode it.
+/// Missing fields and `null` are treated as empty strings.
+fn field_to_str<'a>(
+    object: &'a Map<String, Value>,
+    name: &str,
+) -> Result<Cow<'a, str>> {
+    match object.get(name) {
+        None | Some(Value::Null) => Ok(Cow::Borrowed("")),
+        Some(Value::String(s)) => Ok(Cow::Borrowed(s)),
+        Some(value @ (Value::Bool(_) | Value::Number(_))) => {
+            Ok(Cow::Owned(value.to_string()))
+        }
+        Some(value @ (Value::Array(_) | Value::Object(_))) => Err(format_err!(
+            "expected field {:?} to contain a string, found {}",
+            name,
+            value,
+        )),
+    }
+}
+
+#[test]
+fn json_field_to_str() {
+    let object = serde_json::from_str::<Map<String, Value>>(
+        r#"{"street": "20 W 34th St", "zip": 10118, "city": null, "tags": []}"#,
+    )
+    .unwrap();
+    assert_eq!(field_to_str(&object, "street").unwrap(), "20 W 34th St");
+    assert_eq!(field_to_str(&object, "zip").unwrap(), "10118");
+    assert_eq!(field_to_str(&object, "city").unwrap(), "");
+    assert_eq!(field_to_str(&object, "state").unwrap(), "");
+    assert!(field_to_str(&object, "tags").is_err());
+}
+
+/// Receive chunks from `rx` and write them to `output` as JSON Lines.
+pub fn write_json(
+    output: &Location,
+    mut checkpointer: Checkpointer,
+    rejects: Option<Rejects>,
+    rx: Receiver<Message>,
+) -> Result<()> {
+    let mut wtr = checkpointer.create_writer(output)?;
+    for_each_chunk(rx, rejects, |chunk| {
+        let objects = match &chunk.source {
+            SourceRows::Json(objects) => objects.clone(),
+            SourceRows::Text | SourceRows::Arrow(_) => chunk
+                .rows
+                .iter()
+                .map(|row| input_object(&chunk.shared, row))
+                .collect(),
+        };
+        for (mut object, row) in objects.into_iter().zip(&chunk.rows) {
+            add_geocoded_fields(&chunk.shared, row, &mut object);
+            serde_json::to_writer(&mut wtr, &object)?;
+            wtr.write_all(b"\n")?;
+        }
+        checkpointer.wrote_chunk(&chunk);
+        if checkpointer.is_due() {
+            checkpointer.save(&mut wtr)?;
+        }
+        Ok(())
+    })?;
+    checkpointer.save(&mut wtr)?;
+    wtr.finish()
+}
+
+/// Build a JSON object from the input columns of `row`.
+fn input_object(shared: &Shared, row: &StringRecord) -> Map<String, Value> {
+    shared
+        .out_headers
+        .iter()
+        .zip(row.iter())
+        .take(shared.in_column_count)
+        .map(|(name, value)| (name.to_owned(), Value::String(value.to_owned())))
+        .collect()
+}
+
+/// Add the geocoded columns of `row` to `object`, nested under each prefix.
+/// If we have no geocoding output for a prefix, we set it to `null`.
+fn add_geocoded_fields(
+    shared: &Shared,
+    row: &StringRecord,
+    object: &mut Map<String, Value>,
+) {
+    let names = &shared.geocoder_column_names;
+    let mut offset = shared.in_column_count;
+    for prefix in shared.spec.prefixes() {
+        let values = row.iter().skip(offset).take(names.len());
+        let types = &shared.geocoded_column_types[offset - shared.in_column_count..];
+        let geocoded = if values.clone().all(|value| value.is_empty()) {
+            Value::Null
+        } else {
+            Value::Object(
+                names
+                    .iter()
+                    .zip(values.zip(types))
+                    .map(|(name, (value, column_type))| {
+                        (name.to_owned(), typed_value(value, *column_type))
+                    })
+                    .collect(),
+            )
+        };
+        object.insert(prefix.to_owned(), geocoded);
+        offset += names.len();
+    }
+}
+
+/// Convert a geocoded value to JSON. Empty values become `null`.
+fn typed_value(value: &str, column_type: ColumnType) -> Value {
+    match column_type {
+        _ if value.is_empty() => Value::Null,
+        ColumnType::Text => Value::String(value.to_owned()),
+        ColumnType::Float => value
+            .parse::<f64>()
+            .ok()
+            .and_then(Number::from_f64)
+            .map_or(Value::Null, Value::Number),
+        ColumnType::Boolean => parse_bool(value).map_or(Value::Null, Value::Bool),
+    }
+}
+
+#[test]
+fn nested_geocoded_fields() {
+    let shared = Shared {
+        spec: serde_json::from_str(
+            r#"{"home": {"address": 0}, "work": {"address": 1}}"#,
+        )
+        .unwrap(),
+        out_headers: StringRecord::from(vec![
+            "home_address",
+            "work_address",
+            "home_city",
+            "home_latitude",
+            "work_city",
+            "work_latitude",
+        ]),
+        in_column_count: 2,
+        in_schema: None,
+        geocoded_column_types: vec![
+            ColumnType::Text,
+            ColumnType::Float,
+            ColumnType::Text,
+            ColumnType::Float,
+        ],
+        geocoder_column_names: vec!["city".to_owned(), "latitude".to_owned()],
+        on_error: crate::pipeline::OnError::Fail,
+    };
+    let row = StringRecord::from(vec![
+        "20 W 34th St",
+        "nowhere",
+        "New York",
+        "40.7",
+        "",
+        "",
+    ]);
+    let mut object = input_object(&shared, &row);
+    add_geocoded_fields(&shared, &row, &mut object);
+    assert_eq!(
+        Value::Object(object),
+        serde_json::json!({
+            "home_address": "20 W 34th St",
+            "work_address": "nowhere",
+            "home": { "city": "New York", "latitude": 40.7 },
+            "work": null,
+        }),
+    );
+}
@@ -0,0 +1,217 @@
+//! Input and output file formats.
+
+use std::{ffi::OsStr, path::Path};
+
+use anyhow::format_err;
+use futures::{executor::block_on, StreamExt};
+use strum_macros::EnumString;
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{error, trace};
+
+use crate::addresses::AddressColumnSpec;
+use crate::checkpoint::Checkpointer;
+use crate::geocoders::Geocoder;
+use crate::io_util::Location;
+use crate::pipeline::{Chunk, ColumnOptions, Message};
+use crate::progress::PROGRESS;
+
+use self::{bad_rows::BadRows, rejects::Rejects};
+use crate::Result;
+
+mod arrow;
+pub mod bad_rows;
+pub mod csv;
+mod json;
+pub mod rejects;
+
+/// File formats we can read and write.
+#[derive(Clone, Copy, Debug, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum Format {
+    /// Guess the format from the file extension, defaulting to CSV.
+    Auto,
+    /// CSV files.
+    Csv,
+    /// Parquet files.
+    Parquet,
+    /// Arrow IPC files (sometimes known as Feather files).
+    Arrow,
+    /// JSON Lines (or NDJSON), with one JSON object per line.
+    #[strum(serialize = "jsonl")]
+    JsonLines,
+}
+
+impl Format {
+    /// Replace `Auto` with a concrete format, based on the extension of
+    /// `path`. We ignore any compression extensions like `.gz`.
+    pub fn resolve(self, path: Option<&Path>) -> Format {
+        match (self, path) {
+            (Format::Auto, Some(path)) => {
+                let mut ext = path.extension().and_then(OsStr::to_str);
+                if matches!(ext, Some("gz") | Some("zst")) {
+                    ext = path
+                        .file_stem()
+                        .map(Path::new)
+                        .and_then(Path::extension)
+                        .and_then(OsStr::to_str);
+                }
+                match ext {
+                    Some("parquet") => Format::Parquet,
+                    Some("arrow") | Some("ipc") | Some("feather") => Format::Arrow,
+                    Some("jsonl") | Some("ndjson") => Format::JsonLines,
+                    _ => Format::Csv,
+                }
+            }
+            (Format::Auto, None) => Format::Csv,
+            (format, _) => format,
+        }
+    }
+}
+
+#[test]
+fn resolve_format_from_extension() {
+    let examples = &[
+        (Format::Auto, Some("in.csv"), Format::Csv),
+        (Format::Auto, Some("in.csv.gz"), Format::Csv),
+        (Format::Auto, Some("in.parquet"), Format::Parquet),
+        (Format::Auto, Some("in.arrow"), Format::Arrow),
+        (Format::Auto, Some("in.arrow.zst"), Format::Arrow),
+        (Format::Auto, Some("in.jsonl"), Format::JsonLines),
+        (Format::Auto, Some("in.ndjson.gz"), Format::JsonLines),
+        (Format::Auto, Some("in"), Format::Csv),
+        (Format::Auto, None, Format::Csv),
+        (Format::Parquet, None, Format::Parquet),
+    ];
+    for &(format, path, expected) in examples {
+        assert_eq!(format.resolve(path.map(Path::new)), expected);
+    }
+}
+
+/// Read `input` and send it to `tx` as chunks, skipping the first
+/// `skip_rows` rows (including bad rows) if we're resuming. Each chunk
+/// contains at most `batch_size` addresses, where possible.
+#[allow(clippy::too_many_arguments)]
+pub fn read_input(
+    spec: AddressColumnSpec<String>,
+    geocoder: &dyn Geocoder,
+    input: &Location,
+    column_options: ColumnOptions,
+    batch_size: usize,
+    bad_rows: BadRows,
+    skip_rows: u64,
+    tx: Sender<Message>,
+) -> Result<()> {
+    match input.format() {
+        Format::Auto | Format::Csv => csv::read_csv(
+            spec,
+            geocoder,
+            input,
+            column_options,
+            batch_size,
+            bad_rows,
+            skip_rows,
+            tx,
+        ),
+        Format::JsonLines => json::read_json(
+            spec,
+            geocoder,
+            input,
+            column_options,
+            batch_size,
+            bad_rows,
+            skip_rows,
+            tx,
+        ),
+        format @ (Format::Parquet | Format::Arrow) => arrow::read_arrow(
+            format,
+            spec,
+            geocoder,
+            input,
+            column_options,
+            batch_size,
+            bad_rows,
+            skip_rows,
+            tx,
+        ),
+    }
+}
+
+/// Receive chunks from `rx` and write them to `output`, recording checkpoints
+/// as we go. If we have `rejects`, we write rows we couldn't geocode there.
+pub fn write_output(
+    output: &Location,
+    checkpointer: Checkpointer,
+    rejects: Option<Rejects>,
+    rx: Receiver<Message>,
+) -> Result<()> {
+    match output.format() {
+        Format::Auto | Format::Csv => {
+            csv::write_csv(output, checkpointer, rejects, rx)
+        }
+        Format::JsonLines => json::write_json(output, checkpointer, rejects, rx),
+        // `geocode_stdio` makes sure we're not checkpointing these.
+        format @ (Format::Parquet | Format::Arrow) => {
+            arrow::write_arrow(format, output, rejects, rx)
+        }
+    }
+}
+
+/// Receive chunks from `rx` and pass them to `f`, returning an error if we
+/// never see the end of the stream. Before passing each chunk to `f`, we write
+/// any rows we couldn't geocode to `rejects`, so that `f` can checkpoint both
+/// files together.
+fn for_each_chunk<F>(
+    rx: Receiver<Message>,
+    mut rejects: Option<Rejects>,
+    mut f: F,
+) -> Result<()>
+where
+    F: FnMut(Chunk) -> Result<()>,
+{
+    let mut rx = ReceiverStream::new(rx);
+    while let Some(message) = block_on(rx.next()) {
+        match message {
+            Message::Chunk(mut chunk) => {
+                trace!("received {} output rows", chunk.rows.len());
+                if let Some(rejects) = &mut rejects {
+                    chunk.rejects_end = Some(rejects.write_chunk(&chunk)?);
+                }
+                let rows = chunk.rows.len();
+                f(chunk)?;
+                PROGRESS.wrote_rows(rows);
+            }
+            Message::EndOfStream => {
+                trace!("received end-of-stream for output");
+                if let Some(rejects) = rejects {
+                    rejects.finish()?;
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    // The background thread exitted without sending anything. This shouldn't
+    // happen.
+    error!("did not receive end-of-stream");
+    Err(format_err!(
+        "did not receive end-of-stream from geocoder (perhaps it failed)"
+    ))
+}
+
+/// Parse a boolean value from a geocoder.
+fn parse_bool(value: &str) -> Option<bool> {
+    match value {
+        "T" | "Y" | "true" => Some(true),
+        "F" | "N" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+#[test]
+fn parse_geocoder_bools() {
+    assert_eq!(parse_bool("T"), Some(true));
+    assert_eq!(parse_bool("F"), Some(false));
+    assert_eq!(parse_bool(""), None);
+    assert_eq!(parse_bool("maybe"), None);
+}
@@ -0,0 +1,83 @@
+//! Writing rows that we couldn't geocode to a separate file.
+
+use std::{fs::File, io::Seek, path::Path};
+
+use anyhow::{format_err, Context};
+use metrics::{counter, describe_counter};
+use tracing::info;
+
+use crate::io_util::reopen_at;
+use crate::pipeline::Chunk;
+use crate::Result;
+
+/// Writes input rows with addresses that we couldn't geocode to a CSV file.
+/// We write one row for each address that failed, with the input columns
+/// followed by `geocode_prefix` and `geocode_failure` columns.
+pub struct Rejects {
+    /// Where we write our rejected rows.
+    wtr: csv::Writer<File>,
+
+    /// Have we written our headers yet?
+    headers_written: bool,
+
+    /// How many rows have we rejected?
+    count: u64,
+}
+
+impl Rejects {
+    /// Create a rejects file at `path`. If `resume_at` is specified, we're
+    /// resuming from a checkpoint, so we keep the first `resume_at` bytes of
+    /// the existing file, and append to it.
+    pub fn new(path: &Path, resume_at: Option<u64>) -> Result<Rejects> {
+        describe_counter!(
+            "geocodecsv.rejected_addresses.total",
+            "Addresses which could not be geocoded, by reason"
+        );
+
+        let file = match resume_at {
+            Some(offset) => reopen_at(path, offset)?,
+            None => File::create(path)
+                .with_context(|| format_err!("cannot create {}", path.display()))?,
+        };
+        Ok(Rejects {
+            wtr: csv::Writer::from_writer(file),
+            // We write our headers along with our first rejected row.
+            headers_written: resume_at.unwrap_or_default() > 0,
+            count: 0,
+        })
+    }
+
+    /// Write out any rows in `chunk` with addresses we couldn't geocode. We
+    /// flush our file afterwards, and return its length, so that we can resume
+    /// after this chunk. This does blocking I/O, so we call it from our output
+    /// thread.
+    pub fn write_chunk(&mut self, chunk: &Chunk) -> Result<u64> {
+        let in_column_count = chunk.shared.in_column_count;
+        if !self.headers_written {
+            let headers = chunk.shared.out_headers.iter().take(in_column_count);
+            self.wtr
+                .write_record(headers.chain(["geocode_prefix", "geocode_failure"]))?;
+            self.headers_written = true;
+        }
+        for unmatched in &chunk.unmatched {
+            let reason = unmatched.reason.to_string();
+            let row = chunk.rows[unmatched.row].iter().take(in_column_count);
+            self.wtr
+                .write_record(row.chain([&unmatched.prefix[..], &reason[..]]))?;
+            counter!("geocodecsv.rejected_addresses.total", 1, "reason" => reason);
+            self.count += 1;
+        }
+        self.wtr.flush().context("could not flush rejects file")?;
+        let mut file = self.wtr.get_ref();
+        Ok(file.stream_position()?)
+    }
+
+    /// Flush our rejects file and report how many addresses we rejected.
+    pub fn finish(mut self) -> Result<()> {
+        self.wtr.flush().context("could not flush rejects file")?;
+        if self.count > 0 {
+            info!("wrote {} addresses which could not be geocoded", self.count);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+//! Tools for inspecting and managing a cache, used by the `geocode-csv cache`
+//! subcommands.
+//!
+//! Cache keys look like `gcsv:sm:1a2b:state:city:zipcode:street`, where
+//! `sm:1a2b` is the "geocoder prefix" returned by [`Geocoder::cache_prefix`].
+//! Each geocoder configuration has its own geocoder prefix, so we use them to
+//! group and select entries.
+//!
+//! [`Geocoder::cache_prefix`]: crate::geocoders::Geocoder::cache_prefix
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{format_err, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    addresses::{Address, AddressColumnSpec},
+    formats::csv::CsvRows,
+    geocoders::normalizer::AddressNormalizer,
+    io_util::Location,
+    key_value_stores::KeyValueStore,
+    Result,
+};
+
+use super::{
+    cache_key, compression::CacheCompressor, decode_entry, encode_entry,
+    CacheCompression, CacheTtls,
+};
+
+/// How many entries should we scan, write or delete at once?
+const PAGE_SIZE: usize = 1000;
+
+/// A single cache entry, as stored in an exported NDJSON file.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ExportedEntry {
+    /// The cache key, without any `--cache-key-prefix`.
+    key: String,
+
+    /// The cached output columns, or `None` if the address couldn't be
+    /// geocoded.
+    column_values: Option<Vec<String>>,
+}
+
+/// Statistics about the cache entries for one geocoder prefix.
+#[derive(Debug, Default, Serialize)]
+struct PrefixStats {
+    /// The geocoder prefix, or `other` for keys we don't recognize.
+    prefix: String,
+
+    /// The number of entries we scanned.
+    keys: u64,
+
+    /// Entries for addresses which could be geocoded.
+    found: u64,
+
+    /// Entries for addresses which couldn't be geocoded.
+    unknown: u64,
+
+    /// Entries which we couldn't decode.
+    invalid: u64,
+
+    /// The total size of our scanned keys and values.
+    bytes: u64,
+}
+
+/// Pages through all the entries in a [`KeyValueStore`] with a given prefix.
+struct Scan<'store> {
+    /// The store we're scanning.
+    store: &'store dyn KeyValueStore,
+
+    /// The prefix of the keys we want.
+    prefix: String,
+
+    /// Where to start our next page, if we've already started.
+    cursor: Option<String>,
+
+    /// Have we seen the last page?
+    done: bool,
+}
+
+impl<'store> Scan<'store> {
+    /// Scan for all keys in `store` starting with `prefix`.
+    fn new(store: &'store dyn KeyValueStore, prefix: String) -> Scan<'store> {
+        Scan {
+            store,
+            prefix,
+            cursor: None,
+            done: false,
+        }
+    }
+
+    /// Fetch our next page of entries, or `None` if we're done.
+    async fn next_page(&mut self) -> Result<Option<Vec<(String, Vec<u8>)>>> {
+        if self.done {
+            return Ok(None);
+        }
+        let page = self
+            .store
+            .scan(&self.prefix, self.cursor.take(), PAGE_SIZE)
+            .await?;
+        self.done = page.cursor.is_none();
+        self.cursor = page.cursor;
+        Ok(Some(page.entries))
+    }
+}
+
+/// The key prefix for all entries from `geocoder_prefix`, or all our entries
+/// if no geocoder prefix is specified.
+fn key_prefix(geocoder_prefix: Option<&str>) -> String {
+    match geocoder_prefix {
+        Some(geocoder_prefix) => {
+            format!("gcsv:{}:", geocoder_prefix.to_ascii_lowercase())
+        }
+        None => "gcsv:".to_owned(),
+    }
+}
+
+/// Extract the geocoder prefix from `key`, if it has one.
+fn geocoder_prefix(key: &str) -> Option<&str> {
+    let rest = key.strip_prefix("gcsv:")?;
+    let mut parts = rest.splitn(3, ':');
+    let tag = parts.next()?;
+    let hash = parts.next()?;
+    parts.next()?;
+    Some(&rest[..tag.len() + 1 + hash.len()])
+}
+
+#[test]
+fn geocoder_prefix_extracts_tag_and_hash() {
+    assert_eq!(
+        geocoder_prefix("gcsv:sm:1a2b:ny:new york::20 w 34th st"),
+        Some("sm:1a2b"),
+    );
+    assert_eq!(geocoder_prefix("gcsv:sm:1a2b"), None);
+    assert_eq!(geocoder_prefix("other:sm:1a2b:ny"), None);
+}
+
+/// Open `path` for writing, or standard output if `path` is `None`.
+fn create_output(path: Option<&Path>) -> Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => {
+            Box::new(BufWriter::new(File::create(path).with_context(|| {
+                format_err!("cannot create {}", path.display())
+            })?))
+        }
+        None => Box::new(BufWriter::new(io::stdout())),
+    })
+}
+
+/// Open `path` for reading, or standard input if `path` is `None`.
+fn open_input(path: Option<&Path>) -> Result<Box<dyn BufRead>> {
+    Ok(match path {
+        Some(path) => {
+            Box::new(BufReader::new(File::open(path).with_context(|| {
+                format_err!("cannot open {}", path.display())
+            })?))
+        }
+        None => Box::new(BufReader::new(io::stdin())),
+    })
+}
+
+/// Scan up to `sample` cache entries, and write CSV statistics for each
+/// geocoder prefix to standard output.
+///
+/// We scan entries in key order, so if the cache has more than `sample`
+/// entries, we only see the first few geocoder prefixes. This is cheap, but
+/// it isn't a random sample.
+pub async fn stats(store: &dyn KeyValueStore, sample: usize) -> Result<()> {
+    let compressor = CacheCompressor::new(CacheCompression::None);
+    let mut stats_by_prefix = BTreeMap::<String, PrefixStats>::new();
+    let mut sampled = 0;
+    let mut decompressed = Vec::with_capacity(256);
+    let mut scan = Scan::new(store, key_prefix(None));
+    'scan: while let Some(entries) = scan.next_page().await? {
+        for (key, value) in entries {
+            if sampled >= sample {
+                break 'scan;
+            }
+            sampled += 1;
+
+            let prefix = geocoder_prefix(&key).unwrap_or("other");
+            let stats =
+                stats_by_prefix.entry(prefix.to_owned()).or_insert_with(|| {
+                    PrefixStats {
+                        prefix: prefix.to_owned(),
+                        ..PrefixStats::default()
+                    }
+                });
+            stats.keys += 1;
+            stats.bytes += (key.len() + value.len()) as u64;
+            match decode_entry(&compressor, &value, &mut decompressed) {
+                Ok(Some(_)) => stats.found += 1,
+                Ok(None) => stats.unknown += 1,
+                Err(_) => stats.invalid += 1,
+            }
+        }
+    }
+
+    // Write our headers ourselves, so that we have them even if the cache is
+    // empty.
+    let mut wtr = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(io::stdout());
+    wtr.write_record(["prefix", "keys", "found", "unknown", "invalid", "bytes"])?;
+    for stats in stats_by_prefix.values() {
+        wtr.serialize(stats)?;
+    }
+    wtr.flush()?;
+    if sampled >= sample && !scan.done {
+        eprintln!(
+            "geocode-csv: only scanned the first {} cache entries in key order, so these statistics may not be representative",
+            sampled,
+        );
+    }
+    Ok(())
+}
+
+/// Export all the cache entries for `geocoder_prefix` (or all entries, if not
+/// specified) to `path` as NDJSON, decoding their values.
+pub async fn export(
+    store: &dyn KeyValueStore,
+    geocoder_prefix: Option<&str>,
+    path: Option<&Path>,
+) -> Result<()> {
+    let compressor = CacheCompressor::new(CacheCompression::None);
+    let mut wtr = create_output(path)?;
+    let mut count = 0;
+    let mut decompressed = Vec::with_capacity(256);
+    let mut scan = Scan::new(store, key_prefix(geocoder_prefix));
+    while let Some(entries) = scan.next_page().await? {
+        for (key, value) in entries {
+            let column_values = decode_entry(&compressor, &value, &mut decompressed)
+                .with_context(|| {
+                format!("could not decode cache entry {:?}", key)
+            })?;
+            serde_json::to_writer(&mut wtr, &ExportedEntry { key, column_values })?;
+            wtr.write_all(b"\n")?;
+            count += 1;
+        }
+    }
+    wtr.flush()?;
+    eprintln!("geocode-csv: exported {} cache entries", count);
+    Ok(())
+}
+
+/// Import cache entries from an NDJSON file at `path`, in the format written by
+/// [`export`]. New entries are compressed using `compression`, and expire
+/// according to `ttls`.
+pub async fn import(
+    store: &dyn KeyValueStore,
+    path: Option<&Path>,
+    compression: CacheCompression,
+    ttls: CacheTtls,
+) -> Result<()> {
+    let compressor = CacheCompressor::new(compression);
+    let rdr = open_input(path)?;
+    let mut count = 0;
+    let mut encoded = Vec::with_capacity(256);
+    let mut pipelined_set = store.new_pipelined_set();
+    let mut set_count = 0;
+    for (idx, line) in rdr.lines().enumerate() {
+        let line = line.context("could not read cache entries")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str::<ExportedEntry>(&line)
+            .with_context(|| format!("invalid cache entry on line {}", idx + 1))?;
+        if geocoder_prefix(&entry.key).is_none() {
+            return Err(format_err!(
+                "invalid cache key {:?} on line {}",
+                entry.key,
+                idx + 1,
+            ));
+        }
+
+        // Apply the same rules as `Cache` does for new entries.
+        let ttl = match &entry.column_values {
+            Some(_) => ttls.found,
+            None if ttls.cache_unknown => ttls.unknown,
+            None => continue,
+        };
+        let value =
+            encode_entry(&compressor, entry.column_values.as_ref(), &mut encoded)?;
+        pipelined_set.add_set(entry.key, value, ttl);
+        set_count += 1;
+        count += 1;
+
+        if set_count == PAGE_SIZE {
+            pipelined_set.execute().await?;
+            pipelined_set = store.new_pipelined_set();
+            set_count = 0;
+        }
+    }
+    if set_count > 0 {
+        pipelined_set.execute().await?;
+    }
+    eprintln!("geocode-csv: imported {} cache entries", count);
+    Ok(())
+}
+
+/// A CSV file of addresses whose cache entries should be deleted.
+pub struct AddressesToInvalidate<'a> {
+    /// Where to read our addresses, and how to parse them.
+    pub input: &'a Location,
+
+    /// The address columns in `input`.
+    pub spec: &'a AddressColumnSpec<String>,
+
+    /// If present, normalize our addresses the same way `--normalize` does
+    /// before looking them up.
+    pub normalizer: Option<&'a AddressNormalizer>,
+}
+
+/// Delete cache entries for `geocoder_prefix`. If `addresses` is specified,
+/// we only delete entries for those addresses. Otherwise, we delete all
+/// entries for `geocoder_prefix`.
+pub async fn invalidate(
+    store: &dyn KeyValueStore,
+    geocoder_prefix: &str,
+    addresses: Option<AddressesToInvalidate<'_>>,
+) -> Result<()> {
+    let mut count = 0;
+    if let Some(addresses) = addresses {
+        let cache_prefix = geocoder_prefix.to_ascii_lowercase();
+        let rows = CsvRows::open(addresses.input)?;
+        let spec = addresses
+            .spec
+            .convert_to_indices_using_headers(rows.headers())?;
+        let mut page = Vec::with_capacity(PAGE_SIZE);
+        for record in rows {
+            let record = record.context("could not read address")?;
+            for prefix in spec.prefixes() {
+                page.push(
+                    spec.get(prefix)
+                        .expect("prefix should be in spec")
+                        .extract_address_from_record(&record)?,
+                );
+            }
+            if page.len() >= PAGE_SIZE {
+                count += delete_addresses(
+                    store,
+                    &cache_prefix,
+                    addresses.normalizer,
+                    &page,
+                )
+                .await?;
+                page.clear();
+            }
+        }
+        count += delete_addresses(store, &cache_prefix, addresses.normalizer, &page)
+            .await?;
+    } else {
+        let mut scan = Scan::new(store, key_prefix(Some(geocoder_prefix)));
+        while let Some(entries) = scan.next_page().await? {
+            let keys = entries.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+            count += keys.len();
+            store.delete(keys).await?;
+        }
+    }
+    eprintln!("geocode-csv: invalidated {} cache keys", count);
+    Ok(())
+}
+
+/// Delete the cache entries for `addresses`, normalizing them first if we
+/// have a `normalizer`. Returns the number of keys deleted.
+async fn delete_addresses(
+    store: &dyn KeyValueStore,
+    cache_prefix: &str,
+    normalizer: Option<&AddressNormalizer>,
+    addresses: &[Address],
+) -> Result<usize> {
+    let normalized;
+    let addresses = match normalizer {
+        Some(normalizer) => {
+            normalized = normalizer.normalize(addresses).await?;
+            &normalized[..]
+        }
+        None => addresses,
+    };
+    let keys = addresses
+        .iter()
+        .map(|address| cache_key(cache_prefix, address))
+        .collect::<Vec<_>>();
+    let count = keys.len();
+    store.delete(keys).await?;
+    Ok(count)
+}
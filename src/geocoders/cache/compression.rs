@@ -1,18 +1,96 @@
-//! Basic compression and decompression for cached data, using a custom language
-//! model to build a dictionary for `zstd`.
+//! Compression and decompression for cached data, using `zstd`.
+//!
+//! Each cache entry starts with a single byte identifying how it was
+//! compressed: `N` for uncompressed data, or the ID of one of our
+//! [`DICTIONARIES`]. We can always read entries written using any format we
+//! know about, so switching formats never invalidates the cache.
+//!
+//! To retrain our dictionary, see `examples/train_cache_dictionary.rs`.
 
-use metrics::{counter, describe_counter, Unit};
+use std::io::{Read, Write};
+
+use anyhow::{format_err, Context};
+use metrics::{counter, describe_counter, describe_histogram, histogram, Unit};
+use strum_macros::EnumString;
+use zstd::{
+    dict::{DecoderDictionary, EncoderDictionary},
+    stream::{read::Decoder, write::Encoder},
+};
 
 use crate::Result;
 
-/// Interface for compressing and decompressing cache entries.
+/// The ID we use for uncompressed entries.
+const NONE_ID: u8 = b'N';
+
+/// A `zstd` dictionary, and the ID we use for entries compressed with it. An
+/// empty dictionary means plain `zstd`.
+struct Dictionary {
+    /// The ID we store at the start of each cache entry. Must be unique.
+    id: u8,
+
+    /// The name of this dictionary, for use in metrics.
+    name: &'static str,
+
+    /// The dictionary itself.
+    data: &'static [u8],
+}
+
+/// All our dictionaries, oldest first. We compress new entries using the last
+/// one. Never remove or change a dictionary here, or we'll be unable to read
+/// existing cache entries.
+///
+/// IDs are single ASCII bytes. `N` means uncompressed and `Z` means plain
+/// `zstd`. The digits `b'1'` to `b'9'` are reserved for trained dictionaries,
+/// numbered in the order we add them. `1` belonged to an unreleased
+/// dictionary, and must not be reused.
 ///
-/// We want to allow alternate implementations in the future.
-pub struct CacheCompressor {}
+/// `v2.dict` was trained on 50,000 synthetic rows in Smarty's `complete`
+/// output format, about 15% of them unmatched, covering 51 US cities.
+const DICTIONARIES: &[Dictionary] = &[
+    Dictionary {
+        id: b'Z',
+        name: "zstd",
+        data: &[],
+    },
+    Dictionary {
+        id: b'2',
+        name: "zstd_v2",
+        data: include_bytes!("dictionaries/v2.dict"),
+    },
+];
+
+/// The `zstd` compression level to use. Cache entries are small, so higher
+/// levels gain very little.
+const ZSTD_LEVEL: i32 = 3;
+
+/// How should we compress new cache entries?
+#[derive(Debug, Clone, Copy, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum CacheCompression {
+    /// Store entries uncompressed. Older versions of `geocode-csv` can only
+    /// read these.
+    None,
+    /// Compress entries using `zstd` and our latest dictionary. geocode-csv
+    /// 1.4.0 and earlier can't read these.
+    Zstd,
+}
+
+/// Interface for compressing and decompressing cache entries.
+pub struct CacheCompressor {
+    /// How we compress new entries.
+    compression: CacheCompression,
+
+    /// Our latest dictionary, prepared for compression.
+    encoder_dictionary: EncoderDictionary<'static>,
+
+    /// All our dictionaries, prepared for decompression, in the same order as
+    /// [`DICTIONARIES`].
+    decoder_dictionaries: Vec<DecoderDictionary<'static>>,
+}
 
 impl CacheCompressor {
     /// Create a new cache compressor and initialize our dictionaries.
-    pub fn new() -> CacheCompressor {
+    pub fn new(compression: CacheCompression) -> CacheCompressor {
         describe_counter!(
             "geocodecsv.compressor_input.bytes_total",
             Unit::Bytes,
@@ -33,28 +111,87 @@ impl CacheCompressor {
             Unit::Bytes,
             "Bytes output by decompressor"
         );
+        describe_histogram!(
+            "geocodecsv.compressor.ratio",
+            "Size of each compressed cache entry divided by its original size"
+        );
 
-        CacheCompressor {}
-    }
-
-    /// The ID of this type of compression. Must be unique.
-    pub fn id(&self) -> u8 {
-        b'N' // "N" means "none".
+        let latest = DICTIONARIES.last().expect("no cache dictionaries");
+        CacheCompressor {
+            compression,
+            encoder_dictionary: EncoderDictionary::copy(latest.data, ZSTD_LEVEL),
+            decoder_dictionaries: DICTIONARIES
+                .iter()
+                .map(|dict| DecoderDictionary::copy(dict.data))
+                .collect(),
+        }
     }
 
-    /// Compress `input` and append to `output`.
+    /// Compress `input` and append it to `output`, starting with a byte which
+    /// identifies the compression format.
     pub fn compress(&self, input: &[u8], output: &mut Vec<u8>) -> Result<()> {
-        counter!("geocodecsv.compressor_input.bytes_total", input.len() as u64, "compressor" => "none");
-        output.extend_from_slice(input);
-        counter!("geocodecsv.compressor_output.bytes_total", output.len() as u64, "compressor" => "none");
+        let start = output.len();
+        let name = match self.compression {
+            CacheCompression::None => {
+                output.push(NONE_ID);
+                output.extend_from_slice(input);
+                "none"
+            }
+            CacheCompression::Zstd => {
+                let latest = DICTIONARIES.last().expect("no cache dictionaries");
+                output.push(latest.id);
+                let mut encoder = Encoder::with_prepared_dictionary(
+                    &mut *output,
+                    &self.encoder_dictionary,
+                )
+                .context("could not create zstd encoder")?;
+                // Our ID byte already tells us which dictionary we used.
+                encoder.include_dictid(false)?;
+                encoder.write_all(input)?;
+                encoder.finish().context("could not compress cache entry")?;
+                latest.name
+            }
+        };
+        let compressed_len = output.len() - start;
+        counter!("geocodecsv.compressor_input.bytes_total", input.len() as u64, "compressor" => name);
+        counter!("geocodecsv.compressor_output.bytes_total", compressed_len as u64, "compressor" => name);
+        if !input.is_empty() {
+            histogram!(
+                "geocodecsv.compressor.ratio",
+                compressed_len as f64 / input.len() as f64,
+                "compressor" => name
+            );
+        }
         Ok(())
     }
 
-    /// Decompress `input` and store in `output`.
+    /// Decompress `input` and append it to `output`, using whichever format
+    /// `input` was compressed with.
     pub fn decompress(&self, input: &[u8], output: &mut Vec<u8>) -> Result<()> {
-        counter!("geocodecsv.decompressor_input.bytes_total", input.len() as u64, "compressor" => "none");
-        output.extend_from_slice(input);
-        counter!("geocodecsv.decompressor_output.bytes_total", output.len() as u64, "compressor" => "none");
+        let start = output.len();
+        let (&id, data) = input
+            .split_first()
+            .ok_or_else(|| format_err!("empty cache entry"))?;
+        let name = if id == NONE_ID {
+            output.extend_from_slice(data);
+            "none"
+        } else {
+            let idx = DICTIONARIES
+                .iter()
+                .position(|dict| dict.id == id)
+                .ok_or_else(|| format_err!("unknown compression format {:?}", id))?;
+            let mut decoder = Decoder::with_prepared_dictionary(
+                data,
+                &self.decoder_dictionaries[idx],
+            )
+            .context("could not create zstd decoder")?;
+            decoder
+                .read_to_end(output)
+                .context("could not decompress cache entry")?;
+            DICTIONARIES[idx].name
+        };
+        counter!("geocodecsv.decompressor_input.bytes_total", input.len() as u64, "compressor" => name);
+        counter!("geocodecsv.decompressor_output.bytes_total", (output.len() - start) as u64, "compressor" => name);
         Ok(())
     }
 }
@@ -63,24 +200,104 @@ impl CacheCompressor {
 mod tests {
     use super::*;
 
+    /// A typical Smarty result, encoded the way `Cache` stores it.
+    fn typical_entry() -> Vec<u8> {
+        let columns =
+            ",20 W 34th St,,New York NY 10118-0114,101180114999,,20,34th,W,,\
+            St,,,,,,,New York,New York,NY,10118,0114,99,9,H,Standard,36061,\
+            New York,C024,12,,Commercial,0087,A,40.74842,-73.98573,Zip9,Eastern,\
+            -5,T,Y,AABB,N,N,Y,,N#,,,";
+        let value = Some(columns.split(',').map(str::to_owned).collect::<Vec<_>>());
+        let bincode_config = bincode::config::standard()
+            .with_little_endian()
+            .with_variable_int_encoding();
+        bincode::encode_to_vec(value, bincode_config).unwrap()
+    }
+
     #[test]
     fn round_trip_compression() {
+        let typical = typical_entry();
         let examples: &[&[u8]] = &[
             b"781 Franklin Ave Crown Heights Brooklyn NYC NY 11216 USA",
             b"20 W 34th St, New York, NY 10001",
             b"",
             b"abc123",
+            &typical,
         ];
 
-        let cache_compressor = CacheCompressor::new();
-        for &example in examples {
+        for compression in [CacheCompression::None, CacheCompression::Zstd] {
+            let cache_compressor = CacheCompressor::new(compression);
+            for &example in examples {
+                let mut compressed = vec![];
+                cache_compressor.compress(example, &mut compressed).unwrap();
+                let mut decompressed = vec![];
+                cache_compressor
+                    .decompress(&compressed, &mut decompressed)
+                    .unwrap();
+                assert_eq!(example, decompressed);
+            }
+        }
+    }
+
+    #[test]
+    fn zstd_uses_latest_dictionary() {
+        let typical = typical_entry();
+        let mut compressed = vec![];
+        CacheCompressor::new(CacheCompression::Zstd)
+            .compress(&typical, &mut compressed)
+            .unwrap();
+        assert_eq!(compressed[0], b'2');
+        // Plain `zstd` can barely compress a single small entry, but our
+        // dictionary should at least halve it.
+        assert!(
+            compressed.len() * 2 < typical.len(),
+            "compressed {} bytes to {}",
+            typical.len(),
+            compressed.len(),
+        );
+    }
+
+    #[test]
+    fn read_entries_written_in_any_format() {
+        let typical = typical_entry();
+        let none = CacheCompressor::new(CacheCompression::None);
+        let zstd = CacheCompressor::new(CacheCompression::Zstd);
+        for writer in [&none, &zstd] {
             let mut compressed = vec![];
-            cache_compressor.compress(example, &mut compressed).unwrap();
-            let mut decompressed = vec![];
-            cache_compressor
-                .decompress(&compressed, &mut decompressed)
-                .unwrap();
-            assert_eq!(example, decompressed);
+            writer.compress(&typical, &mut compressed).unwrap();
+            for reader in [&none, &zstd] {
+                let mut decompressed = vec![];
+                reader.decompress(&compressed, &mut decompressed).unwrap();
+                assert_eq!(typical, decompressed);
+            }
+        }
+    }
+
+    #[test]
+    fn read_plain_zstd_entries() {
+        let typical = typical_entry();
+        let mut compressed = vec![b'Z'];
+        compressed.extend(zstd::encode_all(&typical[..], ZSTD_LEVEL).unwrap());
+        let mut decompressed = vec![];
+        CacheCompressor::new(CacheCompression::Zstd)
+            .decompress(&compressed, &mut decompressed)
+            .unwrap();
+        assert_eq!(typical, decompressed);
+    }
+
+    #[test]
+    fn reject_unknown_formats() {
+        let compressor = CacheCompressor::new(CacheCompression::Zstd);
+        let mut output = vec![];
+        assert!(compressor.decompress(b"?abc", &mut output).is_err());
+        assert!(compressor.decompress(b"", &mut output).is_err());
+    }
+
+    #[test]
+    fn dictionary_ids_are_unique() {
+        for (i, dict) in DICTIONARIES.iter().enumerate() {
+            assert_ne!(dict.id, NONE_ID);
+            assert!(DICTIONARIES[i + 1..].iter().all(|d| d.id != dict.id));
         }
     }
 }
@@ -1,20 +1,40 @@
 //! Redis-based caching layer (because Redis is one of the few things fast
 //! enough to handle a cluster of geocode-csv clients running at full speed).
 
-use std::fmt::{self, Write};
+use std::{
+    fmt::{self, Write},
+    time::Duration,
+};
 
 use anyhow::{format_err, Context};
 use async_trait::async_trait;
 use metrics::{counter, describe_counter};
 
-use crate::{addresses::Address, key_value_stores::KeyValueStore, Result};
+use crate::{
+    addresses::Address, key_value_stores::KeyValueStore, progress::PROGRESS, Result,
+};
 
+pub use self::compression::CacheCompression;
 use self::compression::CacheCompressor;
 
-use super::{Geocoded, Geocoder};
+use super::{ColumnType, GeocodeResult, Geocoded, Geocoder, Unmatched};
 
+pub mod admin;
 mod compression;
 
+/// How long we should keep cached results.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheTtls {
+    /// How long to keep addresses we could geocode. `None` means forever.
+    pub found: Option<Duration>,
+
+    /// How long to keep addresses we couldn't geocode. `None` means forever.
+    pub unknown: Option<Duration>,
+
+    /// Should we cache addresses we couldn't geocode at all?
+    pub cache_unknown: bool,
+}
+
 /// A Redis-based caching layer.
 ///
 /// This wraps another geocoder, and caches calls in Redis.
@@ -37,18 +57,27 @@ pub struct Cache {
     /// Should we geocode cache misses?
     cache_hits_only: bool,
 
+    /// Should we count our lookups as cache hits and misses?
+    count_lookups: bool,
+
+    /// How long should we keep the results we cache?
+    ttls: CacheTtls,
+
     /// The column names we output.
     column_names: Vec<String>,
 }
 
 impl Cache {
     /// Create a new cache wrapping `inner`, and storing values in
-    /// `key_value_store`.
+    /// `key_value_store`, compressed using `compression`, and expiring
+    /// according to `ttls`.
     pub async fn new(
         key_value_store: Box<dyn KeyValueStore>,
         inner: Box<dyn Geocoder>,
         output_keys: bool,
         cache_hits_only: bool,
+        compression: CacheCompression,
+        ttls: CacheTtls,
     ) -> Result<Cache> {
         describe_counter!("geocodecsv.cache_hits.total", "Addresses found in cache");
         describe_counter!(
@@ -63,15 +92,25 @@ impl Cache {
         }
 
         Ok(Cache {
-            compressor: CacheCompressor::new(),
+            compressor: CacheCompressor::new(compression),
             key_value_store,
             inner,
             inner_cache_prefix,
             output_keys,
             column_names,
             cache_hits_only,
+            count_lookups: true,
+            ttls,
         })
     }
+
+    /// Don't count our lookups as cache hits and misses. Use this when an
+    /// earlier tier of a cascade has already looked up the same keys in the
+    /// same store, so that each address is only counted once.
+    pub fn without_counting_lookups(mut self) -> Cache {
+        self.count_lookups = false;
+        self
+    }
 }
 
 #[async_trait]
@@ -89,17 +128,29 @@ impl Geocoder for Cache {
         &self.column_names
     }
 
+    fn column_types(&self) -> Vec<ColumnType> {
+        let mut column_types = self.inner.column_types();
+        if self.output_keys {
+            column_types.push(ColumnType::Text);
+        }
+        column_types
+    }
+
+    fn precision_column(&self) -> Option<usize> {
+        self.inner.precision_column()
+    }
+
     async fn geocode_addresses(
         &self,
         addresses: &[Address],
-    ) -> Result<Vec<Option<Geocoded>>> {
+    ) -> Result<Vec<GeocodeResult>> {
         // Build our list of keys.
         let keys = addresses
             .iter()
             .map(|addr| cache_key(&self.inner_cache_prefix, addr))
             .collect::<Vec<_>>();
-        // Start with each geocoded address set to `None`.
-        let mut geocoded = vec![None; addresses.len()];
+        // Start with each geocoded address marked as not cached.
+        let mut geocoded = vec![Err(Unmatched::NotCached); addresses.len()];
 
         // If we have no records, don't call into the cache, because this may
         // cause weird problems, including hanging or running out of memory.
@@ -109,7 +160,8 @@ impl Geocoder for Cache {
             return Ok(geocoded);
         }
 
-        // TODO: De-duplicate duplicate addresses _within_ `addresses`.
+        // Duplicate addresses _within_ `addresses` are only removed if we're
+        // wrapped in a `MemoryCache`.
 
         // Check to see what keys are stored in Redis.
         let mut pipelined_get = self.key_value_store.new_pipelined_get();
@@ -118,33 +170,19 @@ impl Geocoder for Cache {
         }
         let cache_results: Vec<Option<Vec<u8>>> = pipelined_get.execute().await?;
 
-        // Our standard bincode configuration.
-        let bincode_config = bincode::config::standard()
-            .with_little_endian()
-            .with_variable_int_encoding();
-
         // Unpack our results, recording any cache hits, and building a list of
         // the misses to forward to our inner geocoder.
         let mut cache_misses = Vec::with_capacity(addresses.len());
         let mut cache_miss_offsets = Vec::with_capacity(addresses.len());
         let mut decompressed = Vec::with_capacity(256);
+        let mut found_hits = 0;
+        let mut unknown_hits = 0;
+        let mut invalid_hits = 0;
         for (i, cached_value) in cache_results.iter().enumerate() {
             if let Some(cache_hit) = cached_value {
                 // We found this result in the cache.
-                decompressed.clear();
-                if cache_hit[0] != self.compressor.id() {
-                    return Err(format_err!(
-                        "unknown compression format {:?}",
-                        cache_hit[0]
-                    ));
-                }
-                self.compressor
-                    .decompress(&cache_hit[1..], &mut decompressed)?;
-                let (cache_hit, _) = bincode::serde::decode_from_slice::<
-                    Option<Vec<String>>,
-                    _,
-                >(&decompressed, bincode_config)
-                .context("could not deserialize cached data")?;
+                let cache_hit =
+                    decode_entry(&self.compressor, cache_hit, &mut decompressed)?;
 
                 // Here, a `None` value represents a cached geocoding _failure_.
                 // If a previous attempt failed, we expect that more recent ones
@@ -167,25 +205,14 @@ impl Geocoder for Cache {
                         // miss.
                         cache_misses.push(addresses[i].clone());
                         cache_miss_offsets.push(i);
-                        counter!(
-                            "geocodecsv.cache_hits.total",
-                            1,
-                            "geocoding_result" => "invalid_data"
-                        );
+                        invalid_hits += 1;
                     } else {
-                        geocoded[i] = Some(candidate);
-                        counter!(
-                            "geocodecsv.cache_hits.total",
-                            1,
-                            "geocoding_result" => "found"
-                        );
+                        geocoded[i] = Ok(candidate);
+                        found_hits += 1;
                     }
                 } else {
-                    counter!(
-                        "geocodecsv.cache_hits.total",
-                        1,
-                        "geocoding_result" => "unknown_address"
-                    );
+                    geocoded[i] = Err(Unmatched::CachedUnknown);
+                    unknown_hits += 1;
                 }
             } else {
                 // We need to forward this result.
@@ -193,7 +220,13 @@ impl Geocoder for Cache {
                 cache_miss_offsets.push(i);
             }
         }
-        counter!("geocodecsv.cache_misses.total", cache_misses.len() as u64);
+        if self.count_lookups {
+            counter!("geocodecsv.cache_hits.total", found_hits, "geocoding_result" => "found");
+            counter!("geocodecsv.cache_hits.total", unknown_hits, "geocoding_result" => "unknown_address");
+            counter!("geocodecsv.cache_hits.total", invalid_hits, "geocoding_result" => "invalid_data");
+            counter!("geocodecsv.cache_misses.total", cache_misses.len() as u64);
+            PROGRESS.cache_lookups(keys.len(), (found_hits + unknown_hits) as usize);
+        }
         drop(cache_results);
 
         // If we have any cache misses, deal with them.
@@ -207,35 +240,43 @@ impl Geocoder for Cache {
             // Record our successes (and build a Redis command to store them).
             let mut pipelined_set = self.key_value_store.new_pipelined_set();
             let mut encoded = Vec::with_capacity(256);
+            let mut set_count = 0;
             for (i, retry) in cache_miss_offsets
                 .into_iter()
                 .zip(cache_miss_retries.into_iter())
             {
-                // Encode our value for caching.
-                let value = retry.as_ref().map(|retry| &retry.column_values);
-                encoded.clear();
-                bincode::encode_into_std_write(value, &mut encoded, bincode_config)
-                    .context("could not encode value for caching")?;
-
-                // Compress our encoded value and add it to our pipeline set.
-                let mut compressed = Vec::with_capacity(256);
-                compressed.push(self.compressor.id());
-                self.compressor.compress(&encoded, &mut compressed)?;
-                pipelined_set.add_set(keys[i].clone(), compressed);
+                // Decide whether and for how long to cache this result.
+                let ttl = match &retry {
+                    Ok(_) => self.ttls.found,
+                    Err(_) if self.ttls.cache_unknown => self.ttls.unknown,
+                    Err(_) => {
+                        geocoded[i] = retry;
+                        continue;
+                    }
+                };
+
+                // Encode our value and add it to our pipeline set.
+                let value = retry.as_ref().ok().map(|retry| &retry.column_values);
+                let compressed = encode_entry(&self.compressor, value, &mut encoded)?;
+                pipelined_set.add_set(keys[i].clone(), compressed, ttl);
+                set_count += 1;
 
                 // Add out geocoding result to our output.
                 geocoded[i] = retry;
             }
 
-            // Write our new results back to our cache.
-            pipelined_set.execute().await?;
+            // Write our new results back to our cache. As with lookups, don't
+            // send empty requests.
+            if set_count > 0 {
+                pipelined_set.execute().await?;
+            }
         }
 
         // Output our cache key, too, if we were asked to do so.
         if self.output_keys {
             debug_assert_eq!(geocoded.len(), keys.len());
             for (result, key) in geocoded.iter_mut().zip(keys.iter()) {
-                if let Some(result) = result {
+                if let Ok(result) = result {
                     result.column_values.push(key.to_owned());
                 }
             }
@@ -245,12 +286,50 @@ impl Geocoder for Cache {
     }
 }
 
+/// Our standard bincode configuration.
+fn bincode_config() -> impl bincode::config::Config {
+    bincode::config::standard()
+        .with_little_endian()
+        .with_variable_int_encoding()
+}
+
+/// Encode and compress `value` for storage in the cache, using `encoded` as
+/// scratch space. `None` represents an address we couldn't geocode.
+fn encode_entry(
+    compressor: &CacheCompressor,
+    value: Option<&Vec<String>>,
+    encoded: &mut Vec<u8>,
+) -> Result<Vec<u8>> {
+    encoded.clear();
+    bincode::encode_into_std_write(value, encoded, bincode_config())
+        .context("could not encode value for caching")?;
+    let mut compressed = Vec::with_capacity(256);
+    compressor.compress(encoded, &mut compressed)?;
+    Ok(compressed)
+}
+
+/// Decompress and decode a cache entry, using `decompressed` as scratch space.
+fn decode_entry(
+    compressor: &CacheCompressor,
+    entry: &[u8],
+    decompressed: &mut Vec<u8>,
+) -> Result<Option<Vec<String>>> {
+    decompressed.clear();
+    compressor.decompress(entry, decompressed)?;
+    let (value, _) = bincode::serde::decode_from_slice::<Option<Vec<String>>, _>(
+        decompressed,
+        bincode_config(),
+    )
+    .context("could not deserialize cached data")?;
+    Ok(value)
+}
+
 /// Given an address, build our cache key.
 ///
 /// We convert this to lowercase to provide a _tiny_ level of normalization,
 /// which may also help normalized mode (which always uses lowercase) and
 /// unnormalized mode (which uses mixed case) to share more cache hits.
-fn cache_key(cache_prefix: &str, addr: &Address) -> String {
+pub(crate) fn cache_key(cache_prefix: &str, addr: &Address) -> String {
     format!(
         "gcsv:{}:{}:{}:{}:{}",
         cache_prefix,
@@ -0,0 +1,266 @@
+//! Cascading geocoder. Try each geocoder in turn, passing along only the
+//! addresses that earlier geocoders couldn't match.
+
+use async_trait::async_trait;
+use metrics::{counter, describe_counter};
+
+use crate::format_err;
+use crate::geocoders::{ColumnType, GeocodeResult, Geocoded, Geocoder, Unmatched};
+use crate::{addresses::Address, Result};
+
+/// The name of the column that records which geocoder matched an address.
+const SOURCE_COLUMN: &str = "source";
+
+/// A geocoder that tries several geocoders in order, and returns the first
+/// match.
+pub struct Cascade {
+    /// Our geocoders, with the names we use for them in our `source` column.
+    geocoders: Vec<(String, Box<dyn Geocoder>)>,
+
+    /// For each geocoder, the position of each of its columns in our output.
+    column_indices: Vec<Vec<usize>>,
+
+    /// The column names output by this geocoder. This includes every column
+    /// output by any of our geocoders, followed by `source`.
+    column_names: Vec<String>,
+
+    /// The types of our output columns.
+    column_types: Vec<ColumnType>,
+
+    /// The configuration key for this geocoder.
+    config_key: String,
+}
+
+impl Cascade {
+    /// Create a new geocoder which tries each of `geocoders` in order. Columns
+    /// with the same name are merged, so that all our geocoders can share a
+    /// single set of columns.
+    pub fn new(geocoders: Vec<(String, Box<dyn Geocoder>)>) -> Result<Cascade> {
+        describe_counter!(
+            "geocodecsv.cascade_matches.total",
+            "Addresses matched by each geocoder in a cascade"
+        );
+
+        let mut column_names: Vec<String> = vec![];
+        let mut column_types = vec![];
+        let mut column_indices = vec![];
+        for (_, geocoder) in &geocoders {
+            let mut indices = vec![];
+            for (name, ty) in
+                geocoder.column_names().iter().zip(geocoder.column_types())
+            {
+                if name == SOURCE_COLUMN {
+                    return Err(format_err!(
+                        "cannot cascade geocoders with a {:?} column",
+                        SOURCE_COLUMN,
+                    ));
+                }
+                match column_names.iter().position(|n| n == name) {
+                    Some(idx) => {
+                        // If two geocoders disagree about a type, fall back to
+                        // text.
+                        if column_types[idx] != ty {
+                            column_types[idx] = ColumnType::Text;
+                        }
+                        indices.push(idx);
+                    }
+                    None => {
+                        indices.push(column_names.len());
+                        column_names.push(name.to_owned());
+                        column_types.push(ty);
+                    }
+                }
+            }
+            column_indices.push(indices);
+        }
+        column_names.push(SOURCE_COLUMN.to_owned());
+        column_types.push(ColumnType::Text);
+
+        let config_key = geocoders
+            .iter()
+            .map(|(name, geocoder)| {
+                format!("{}={}", name, geocoder.configuration_key())
+            })
+            .collect::<Vec<_>>()
+            .join(">");
+        Ok(Cascade {
+            geocoders,
+            column_indices,
+            column_names,
+            column_types,
+            config_key,
+        })
+    }
+
+    /// Convert `geocoded` from geocoder `idx` to use our columns.
+    fn unify(&self, idx: usize, geocoded: Geocoded) -> Geocoded {
+        let mut column_values = vec![String::new(); self.column_names.len()];
+        for (value, &i) in geocoded
+            .column_values
+            .into_iter()
+            .zip(&self.column_indices[idx])
+        {
+            column_values[i] = value;
+        }
+        *column_values.last_mut().expect("should have source column") =
+            self.geocoders[idx].0.clone();
+        Geocoded { column_values }
+    }
+}
+
+#[async_trait]
+impl Geocoder for Cascade {
+    fn tag(&self) -> &str {
+        "cascade"
+    }
+
+    fn configuration_key(&self) -> &str {
+        &self.config_key
+    }
+
+    fn column_names(&self) -> &[String] {
+        &self.column_names
+    }
+
+    fn column_types(&self) -> Vec<ColumnType> {
+        self.column_types.clone()
+    }
+
+    fn precision_column(&self) -> Option<usize> {
+        // Columns with the same name are merged, so use the first one we find.
+        self.geocoders.iter().zip(&self.column_indices).find_map(
+            |((_, geocoder), indices)| {
+                geocoder.precision_column().map(|idx| indices[idx])
+            },
+        )
+    }
+
+    async fn geocode_addresses(
+        &self,
+        addresses: &[Address],
+    ) -> Result<Vec<GeocodeResult>> {
+        // Keep track of the original positions of the addresses that we still
+        // need to match.
+        let mut result = vec![Err(Unmatched::NoMatch); addresses.len()];
+        let mut remaining = (0..addresses.len()).collect::<Vec<_>>();
+        for (idx, (name, geocoder)) in self.geocoders.iter().enumerate() {
+            if remaining.is_empty() {
+                break;
+            }
+
+            let remaining_addresses = remaining
+                .iter()
+                .map(|&i| addresses[i].clone())
+                .collect::<Vec<_>>();
+            let geocoded = geocoder.geocode_addresses(&remaining_addresses).await?;
+            if geocoded.len() != remaining_addresses.len() {
+                return Err(format_err!(
+                    "geocoder {} returned {} results for {} addresses",
+                    name,
+                    geocoded.len(),
+                    remaining_addresses.len(),
+                ));
+            }
+
+            // Record our matches, and keep any failures for the next geocoder.
+            // If nobody matches an address, we report the last failure.
+            let mut still_remaining = vec![];
+            for (i, geocoded) in remaining.into_iter().zip(geocoded) {
+                match geocoded {
+                    Ok(geocoded) => result[i] = Ok(self.unify(idx, geocoded)),
+                    Err(unmatched) => {
+                        result[i] = Err(unmatched);
+                        still_remaining.push(i);
+                    }
+                }
+            }
+            let matched = remaining_addresses.len() - still_remaining.len();
+            counter!("geocodecsv.cascade_matches.total", matched as u64, "geocoder" => name.clone());
+            remaining = still_remaining;
+        }
+        Ok(result)
+    }
+}
+
+#[test]
+fn cascade_tries_geocoders_in_order() {
+    use futures::executor::block_on;
+
+    /// A test geocoder which only matches streets starting with `prefix`.
+    struct Matches {
+        prefix: &'static str,
+        column_names: Vec<String>,
+    }
+
+    #[async_trait]
+    impl Geocoder for Matches {
+        fn tag(&self) -> &str {
+            "test"
+        }
+
+        fn configuration_key(&self) -> &str {
+            self.prefix
+        }
+
+        fn column_names(&self) -> &[String] {
+            &self.column_names
+        }
+
+        async fn geocode_addresses(
+            &self,
+            addresses: &[Address],
+        ) -> Result<Vec<GeocodeResult>> {
+            Ok(addresses
+                .iter()
+                .map(|addr| {
+                    if addr.street.starts_with(self.prefix) {
+                        Ok(Geocoded {
+                            column_values: vec![addr.street.clone(); 2],
+                        })
+                    } else {
+                        Err(Unmatched::NoMatch)
+                    }
+                })
+                .collect())
+        }
+    }
+
+    let cascade = Cascade::new(vec![
+        (
+            "fst".to_owned(),
+            Box::new(Matches {
+                prefix: "1",
+                column_names: vec!["a".to_owned(), "b".to_owned()],
+            }),
+        ),
+        (
+            "snd".to_owned(),
+            Box::new(Matches {
+                prefix: "2",
+                column_names: vec!["b".to_owned(), "c".to_owned()],
+            }),
+        ),
+    ])
+    .unwrap();
+    assert_eq!(cascade.column_names(), &["a", "b", "c", "source"]);
+
+    let addresses = ["1 Main St", "2 Main St", "3 Main St"]
+        .iter()
+        .map(|&street| Address {
+            street: street.to_owned(),
+            city: None,
+            state: None,
+            zipcode: None,
+        })
+        .collect::<Vec<_>>();
+    let geocoded = block_on(cascade.geocode_addresses(&addresses)).unwrap();
+    assert_eq!(
+        geocoded[0].as_ref().unwrap().column_values,
+        &["1 Main St", "1 Main St", "", "fst"],
+    );
+    assert_eq!(
+        geocoded[1].as_ref().unwrap().column_values,
+        &["", "2 Main St", "2 Main St", "snd"],
+    );
+    assert_eq!(geocoded[2].as_ref().unwrap_err(), &Unmatched::NoMatch);
+}
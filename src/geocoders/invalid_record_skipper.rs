@@ -5,7 +5,7 @@ use metrics::{counter, describe_counter};
 
 use crate::addresses::Address;
 
-use super::{Geocoded, Geocoder, Result};
+use super::{ColumnType, GeocodeResult, Geocoder, Result, Unmatched};
 
 /// Skip invalid addresses and don't pass them through to the next layer.
 pub struct InvalidRecordSkipper {
@@ -41,10 +41,18 @@ impl Geocoder for InvalidRecordSkipper {
         self.inner.column_names()
     }
 
+    fn column_types(&self) -> Vec<ColumnType> {
+        self.inner.column_types()
+    }
+
+    fn precision_column(&self) -> Option<usize> {
+        self.inner.precision_column()
+    }
+
     async fn geocode_addresses(
         &self,
         addresses: &[Address],
-    ) -> Result<Vec<Option<Geocoded>>> {
+    ) -> Result<Vec<GeocodeResult>> {
         // Extract our valid addresses, keeping track of their original
         // positions.
         let mut original_indices = vec![];
@@ -59,11 +67,11 @@ impl Geocoder for InvalidRecordSkipper {
         }
 
         // Geocode our valid addresses, if we have any.
-        let mut result = vec![None; addresses.len()];
+        let mut result = vec![Err(Unmatched::InvalidRecord); addresses.len()];
         if !valid_addresses.is_empty() {
             let geocodeded = self.inner.geocode_addresses(&valid_addresses).await?;
 
-            // Rebuild our geocoded addresses, inserting `None` for invalid.
+            // Rebuild our geocoded addresses, leaving invalid ones unmatched.
             for (i, geocoded) in geocodeded.into_iter().enumerate() {
                 let original_index = original_indices[i];
                 result[original_index] = geocoded;
@@ -1,13 +1,15 @@
 //! "Geocoding" interface based on `libpostal`. This really just does address
 //! normalization, and doesn't geocode.
 
+use std::collections::HashMap;
+
 use async_trait::async_trait;
 use libpostal_rust::{parse_address, ParseAddressOptions};
 use metrics::{counter, describe_counter};
 
 use crate::{addresses::Address, Result};
 
-use super::{Geocoded, Geocoder};
+use super::{GeocodeResult, Geocoded, Geocoder, Unmatched};
 
 pub(crate) static COLUMN_NAMES: &[&str] = &[
     // From
@@ -48,6 +50,10 @@ pub(crate) static COLUMN_NAMES: &[&str] = &[
 pub struct LibPostal {
     /// Our column names.
     column_names: Vec<String>,
+
+    /// Should we only report a match for addresses which pass
+    /// [`is_complete`]? Otherwise, we report every parsed address as a match.
+    complete_only: bool,
 }
 
 impl LibPostal {
@@ -62,7 +68,21 @@ impl LibPostal {
             .iter()
             .map(|&name| name.to_owned())
             .collect::<Vec<_>>();
-        LibPostal { column_names }
+        LibPostal {
+            column_names,
+            complete_only: false,
+        }
+    }
+
+    /// Create a new LibPostal geocoder which returns [`Unmatched::NoMatch`]
+    /// for addresses that don't parse into a complete street address. This
+    /// is used when libpostal is one tier in a cascade, because otherwise it
+    /// would claim to match everything.
+    pub fn complete_only() -> LibPostal {
+        LibPostal {
+            complete_only: true,
+            ..LibPostal::new()
+        }
     }
 
     pub async fn prime() {
@@ -87,7 +107,11 @@ impl Geocoder for LibPostal {
     }
 
     fn configuration_key(&self) -> &str {
-        "default"
+        if self.complete_only {
+            "complete_only"
+        } else {
+            "default"
+        }
     }
 
     fn column_names(&self) -> &[String] {
@@ -97,7 +121,7 @@ impl Geocoder for LibPostal {
     async fn geocode_addresses(
         &self,
         addresses: &[Address],
-    ) -> Result<Vec<Option<Geocoded>>> {
+    ) -> Result<Vec<GeocodeResult>> {
         let parse_opt = ParseAddressOptions::default();
 
         let mut result = Vec::with_capacity(addresses.len());
@@ -113,6 +137,10 @@ impl Geocoder for LibPostal {
 
             // Parse it.
             let parsed = parse_address(&addr_str, &parse_opt)?;
+            if self.complete_only && !is_complete(&parsed) {
+                result.push(Err(Unmatched::NoMatch));
+                continue;
+            }
             let mut geocoded = Geocoded {
                 column_values: Vec::with_capacity(self.column_names.len()),
             };
@@ -125,9 +153,50 @@ impl Geocoder for LibPostal {
             }
 
             debug_assert_eq!(geocoded.column_values.len(), self.column_names().len());
-            result.push(Some(geocoded));
+            result.push(Ok(geocoded));
         }
         counter!("geocodecsv.addresses_parsed.total", result.len() as u64, "parser" => "libpostal");
         Ok(result)
     }
 }
+
+/// Does `parsed` look like a complete street address? We require a house
+/// number and road, plus either a postcode or a city and state.
+fn is_complete(parsed: &HashMap<String, String>) -> bool {
+    let has = |field: &str| parsed.get(field).is_some_and(|v| !v.is_empty());
+    has("house_number")
+        && has("road")
+        && (has("postcode") || (has("city") && has("state")))
+}
+
+#[test]
+fn is_complete_requires_street_and_locality() {
+    let parsed = |fields: &[(&str, &str)]| {
+        fields
+            .iter()
+            .map(|&(k, v)| (k.to_owned(), v.to_owned()))
+            .collect::<HashMap<_, _>>()
+    };
+    let street = [("house_number", "20"), ("road", "w 34th st")];
+    assert!(!is_complete(&parsed(&street)));
+    assert!(is_complete(&parsed(&[
+        street[0],
+        street[1],
+        ("postcode", "10118")
+    ])));
+    assert!(is_complete(&parsed(&[
+        street[0],
+        street[1],
+        ("city", "new york"),
+        ("state", "ny"),
+    ])));
+    assert!(!is_complete(&parsed(&[
+        street[0],
+        street[1],
+        ("city", "springfield"),
+    ])));
+    assert!(!is_complete(&parsed(&[
+        ("road", "main st"),
+        ("postcode", "10118")
+    ])));
+}
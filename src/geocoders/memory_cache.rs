@@ -0,0 +1,445 @@
+//! An in-memory LRU cache, which also merges duplicate lookups.
+//!
+//! Real-world inputs often contain the same address many times, both within a
+//! single chunk and in chunks which are being geocoded at the same time. We
+//! geocode each distinct address in a chunk only once, and if another chunk
+//! is already geocoding an address, we wait for its result instead of sending
+//! a second request.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    mem,
+    sync::Mutex,
+};
+
+use async_trait::async_trait;
+use hashlink::LruCache;
+use metrics::{counter, describe_counter, describe_gauge, gauge, Unit};
+use tokio::sync::watch;
+
+use crate::{addresses::Address, Result};
+
+use super::{cache::cache_key, ColumnType, GeocodeResult, Geocoder, Unmatched};
+
+/// Approximate memory used by each cache entry, not counting the key and
+/// column values themselves.
+const ENTRY_OVERHEAD: usize = 128;
+
+/// Approximate memory used by each column value, not counting its contents.
+const VALUE_OVERHEAD: usize = mem::size_of::<String>();
+
+/// An in-memory cache in front of another geocoder.
+pub struct MemoryCache {
+    /// The geocoder we're wrapping.
+    inner: Box<dyn Geocoder>,
+
+    /// The cache key for `inner`.
+    inner_cache_prefix: String,
+
+    /// The approximate maximum size of our cache, in bytes.
+    max_bytes: usize,
+
+    /// Our cache and in-flight requests.
+    state: Mutex<State>,
+}
+
+/// The mutable state of a `MemoryCache`.
+struct State {
+    /// Recently used results.
+    lru: LruCache<String, GeocodeResult>,
+
+    /// The approximate size of `lru`, in bytes.
+    bytes: usize,
+
+    /// Addresses which are currently being geocoded. Each receiver will see
+    /// `Some(result)` when the address has been geocoded, or will be closed if
+    /// geocoding fails.
+    in_flight: HashMap<String, watch::Receiver<Option<GeocodeResult>>>,
+}
+
+impl State {
+    /// Add `result` to our cache, and evict old entries until we fit in
+    /// `max_bytes`.
+    fn insert(&mut self, key: String, result: &GeocodeResult, max_bytes: usize) {
+        // Errors which depend on how we were run shouldn't be remembered.
+        if matches!(
+            result,
+            Err(Unmatched::NotCached) | Err(Unmatched::GeocoderError)
+        ) {
+            return;
+        }
+        self.bytes += entry_size(&key, result);
+        if let Some(old) = self.lru.insert(key.clone(), result.clone()) {
+            self.bytes -= entry_size(&key, &old);
+        }
+        while self.bytes > max_bytes {
+            match self.lru.remove_lru() {
+                Some((key, old)) => self.bytes -= entry_size(&key, &old),
+                None => break,
+            }
+        }
+        gauge!("geocodecsv.memory_cache.bytes", self.bytes as f64);
+    }
+}
+
+/// The approximate memory used by caching `result` under `key`.
+fn entry_size(key: &str, result: &GeocodeResult) -> usize {
+    let values = match result {
+        Ok(geocoded) => geocoded
+            .column_values
+            .iter()
+            .map(|value| VALUE_OVERHEAD + value.len())
+            .sum(),
+        Err(_) => 0,
+    };
+    ENTRY_OVERHEAD + key.len() + values
+}
+
+/// Removes our in-flight requests if we fail or are cancelled, so that other
+/// callers don't wait forever.
+struct InFlightGuard<'a> {
+    /// The state containing our in-flight requests.
+    state: &'a Mutex<State>,
+
+    /// The keys we're responsible for.
+    keys: Vec<String>,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        if self.keys.is_empty() {
+            return;
+        }
+        let mut state = self.state.lock().expect("lock poisoned");
+        for key in &self.keys {
+            state.in_flight.remove(key);
+        }
+    }
+}
+
+impl MemoryCache {
+    /// Create a new cache wrapping `inner`, using approximately `max_bytes`
+    /// of memory.
+    pub fn new(inner: Box<dyn Geocoder>, max_bytes: usize) -> MemoryCache {
+        describe_counter!(
+            "geocodecsv.memory_cache_hits.total",
+            "Addresses found in the in-memory cache"
+        );
+        describe_counter!(
+            "geocodecsv.memory_cache_misses.total",
+            "Addresses not found in the in-memory cache"
+        );
+        describe_counter!(
+            "geocodecsv.memory_cache_coalesced.total",
+            "Addresses which were already being geocoded, in the same chunk or another one"
+        );
+        describe_gauge!(
+            "geocodecsv.memory_cache.bytes",
+            Unit::Bytes,
+            "Approximate size of the in-memory cache"
+        );
+
+        let inner_cache_prefix = inner.cache_prefix();
+        MemoryCache {
+            inner,
+            inner_cache_prefix,
+            max_bytes,
+            state: Mutex::new(State {
+                lru: LruCache::new_unbounded(),
+                bytes: 0,
+                in_flight: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Geocode `indices` of `addresses` using our inner geocoder, and cache
+    /// the results. Returns the results in the same order as `indices`.
+    async fn geocode_uncached(
+        &self,
+        addresses: &[Address],
+        keys: &[String],
+        indices: &[usize],
+    ) -> Result<Vec<GeocodeResult>> {
+        let uncached = indices
+            .iter()
+            .map(|&i| addresses[i].clone())
+            .collect::<Vec<_>>();
+        let geocoded = self.inner.geocode_addresses(&uncached).await?;
+        let mut state = self.state.lock().expect("lock poisoned");
+        for (&i, result) in indices.iter().zip(&geocoded) {
+            state.insert(keys[i].clone(), result, self.max_bytes);
+        }
+        Ok(geocoded)
+    }
+}
+
+#[async_trait]
+impl Geocoder for MemoryCache {
+    fn tag(&self) -> &str {
+        self.inner.tag()
+    }
+
+    fn configuration_key(&self) -> &str {
+        self.inner.configuration_key()
+    }
+
+    fn column_names(&self) -> &[String] {
+        self.inner.column_names()
+    }
+
+    fn column_types(&self) -> Vec<ColumnType> {
+        self.inner.column_types()
+    }
+
+    fn precision_column(&self) -> Option<usize> {
+        self.inner.precision_column()
+    }
+
+    async fn geocode_addresses(
+        &self,
+        addresses: &[Address],
+    ) -> Result<Vec<GeocodeResult>> {
+        let keys = addresses
+            .iter()
+            .map(|addr| cache_key(&self.inner_cache_prefix, addr))
+            .collect::<Vec<_>>();
+        let mut results: Vec<Option<GeocodeResult>> = vec![None; addresses.len()];
+
+        // Sort our addresses into cache hits, duplicates of earlier addresses
+        // in this chunk, addresses being geocoded by somebody else, and
+        // addresses we need to geocode ourselves.
+        let mut first_seen = HashMap::<&str, usize>::new();
+        let mut duplicates = vec![];
+        let mut waiting = vec![];
+        let mut misses = vec![];
+        let mut senders = vec![];
+        let mut guard = InFlightGuard {
+            state: &self.state,
+            keys: vec![],
+        };
+        {
+            let mut state = self.state.lock().expect("lock poisoned");
+            for (i, key) in keys.iter().enumerate() {
+                match first_seen.entry(key) {
+                    Entry::Occupied(entry) => {
+                        duplicates.push((i, *entry.get()));
+                        continue;
+                    }
+                    Entry::Vacant(entry) => {
+                        entry.insert(i);
+                    }
+                }
+                if let Some(result) = state.lru.get(key) {
+                    results[i] = Some(result.clone());
+                } else if let Some(receiver) = state.in_flight.get(key) {
+                    waiting.push((i, receiver.clone()));
+                } else {
+                    let (sender, receiver) = watch::channel(None);
+                    state.in_flight.insert(key.clone(), receiver);
+                    guard.keys.push(key.clone());
+                    misses.push(i);
+                    senders.push(sender);
+                }
+            }
+        }
+        let hits = addresses.len() - duplicates.len() - waiting.len() - misses.len();
+        counter!("geocodecsv.memory_cache_hits.total", hits as u64);
+        counter!(
+            "geocodecsv.memory_cache_coalesced.total",
+            (duplicates.len() + waiting.len()) as u64
+        );
+
+        // Geocode our misses, and share the results with anyone waiting. If
+        // this fails, `guard` will tell anyone waiting to geocode these
+        // addresses themselves.
+        if !misses.is_empty() {
+            let geocoded = self.geocode_uncached(addresses, &keys, &misses).await?;
+            let mut state = self.state.lock().expect("lock poisoned");
+            for ((&i, result), sender) in misses.iter().zip(geocoded).zip(senders) {
+                state.in_flight.remove(&keys[i]);
+                // This fails if nobody is waiting, which is fine.
+                let _ = sender.send(Some(result.clone()));
+                results[i] = Some(result);
+            }
+            guard.keys.clear();
+        }
+
+        // Wait for addresses that somebody else was geocoding. If they failed,
+        // try again ourselves.
+        let mut orphans = vec![];
+        for (i, mut receiver) in waiting {
+            match receiver.wait_for(Option::is_some).await {
+                Ok(result) => results[i] = result.clone(),
+                Err(_) => orphans.push(i),
+            }
+        }
+        if !orphans.is_empty() {
+            let geocoded = self.geocode_uncached(addresses, &keys, &orphans).await?;
+            for (i, result) in orphans.iter().zip(geocoded) {
+                results[*i] = Some(result);
+            }
+        }
+        counter!(
+            "geocodecsv.memory_cache_misses.total",
+            (misses.len() + orphans.len()) as u64
+        );
+
+        // Fill in our duplicates.
+        for (i, first) in duplicates {
+            results[i] = results[first].clone();
+        }
+        Ok(results
+            .into_iter()
+            .map(|result| result.expect("every address should have a result"))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::geocoders::Geocoded;
+
+    /// A slow test geocoder which counts the addresses it sees.
+    struct Counting {
+        column_names: Vec<String>,
+        addresses: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl Geocoder for Counting {
+        fn tag(&self) -> &str {
+            "test"
+        }
+
+        fn configuration_key(&self) -> &str {
+            ""
+        }
+
+        fn column_names(&self) -> &[String] {
+            &self.column_names
+        }
+
+        async fn geocode_addresses(
+            &self,
+            addresses: &[Address],
+        ) -> Result<Vec<GeocodeResult>> {
+            self.addresses.fetch_add(addresses.len(), Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(addresses
+                .iter()
+                .map(|addr| {
+                    Ok(Geocoded {
+                        column_values: vec![addr.street.clone()],
+                    })
+                })
+                .collect())
+        }
+    }
+
+    /// Create a cache, and a counter of the addresses it passes through.
+    fn counting_cache(max_bytes: usize) -> (MemoryCache, Arc<AtomicUsize>) {
+        let addresses = Arc::new(AtomicUsize::new(0));
+        let cache = MemoryCache::new(
+            Box::new(Counting {
+                column_names: vec!["street".to_owned()],
+                addresses: addresses.clone(),
+            }),
+            max_bytes,
+        );
+        (cache, addresses)
+    }
+
+    fn addresses(streets: &[&str]) -> Vec<Address> {
+        streets
+            .iter()
+            .map(|&street| Address {
+                street: street.to_owned(),
+                city: None,
+                state: None,
+                zipcode: None,
+            })
+            .collect()
+    }
+
+    fn streets(results: &[GeocodeResult]) -> Vec<&str> {
+        results
+            .iter()
+            .map(|result| &result.as_ref().unwrap().column_values[0][..])
+            .collect()
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deduplicate_and_cache() {
+        let (cache, count) = counting_cache(1_000_000);
+
+        // Duplicates within a chunk are only geocoded once. Cache keys are
+        // lowercase, so case doesn't matter.
+        let chunk = addresses(&["1 Main St", "2 Main St", "1 MAIN ST", "1 Main St"]);
+        let geocoded = cache.geocode_addresses(&chunk).await.unwrap();
+        assert_eq!(
+            streets(&geocoded),
+            &["1 Main St", "2 Main St", "1 Main St", "1 Main St"],
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // Later chunks hit our cache.
+        let chunk = addresses(&["2 Main St", "3 Main St"]);
+        let geocoded = cache.geocode_addresses(&chunk).await.unwrap();
+        assert_eq!(streets(&geocoded), &["2 Main St", "3 Main St"]);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+        assert!(cache.state.lock().unwrap().in_flight.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesce_concurrent_lookups() {
+        let (cache, count) = counting_cache(1_000_000);
+
+        let first = addresses(&["1 Main St", "2 Main St"]);
+        let second = addresses(&["2 Main St", "3 Main St"]);
+        let (first, second) = tokio::join!(
+            cache.geocode_addresses(&first),
+            cache.geocode_addresses(&second),
+        );
+        assert_eq!(streets(&first.unwrap()), &["1 Main St", "2 Main St"]);
+        assert_eq!(streets(&second.unwrap()), &["2 Main St", "3 Main St"]);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn evict_least_recently_used() {
+        // Leave room for about two entries.
+        let entry = entry_size(
+            &cache_key("test:0000", &addresses(&["1 Main St"])[0]),
+            &Ok(Geocoded {
+                column_values: vec!["1 Main St".to_owned()],
+            }),
+        );
+        let (cache, count) = counting_cache(2 * entry + entry / 2);
+
+        for streets in [
+            &["1 Main St"],
+            &["2 Main St"],
+            &["1 Main St"],
+            &["3 Main St"],
+        ] {
+            cache.geocode_addresses(&addresses(streets)).await.unwrap();
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+
+        // "2 Main St" was used least recently, so it should be gone.
+        cache
+            .geocode_addresses(&addresses(&["1 Main St", "2 Main St"]))
+            .await
+            .unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 4);
+    }
+}
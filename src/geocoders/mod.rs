@@ -1,6 +1,6 @@
 //! Geocoding backends.
 
-use std::{fmt, iter::repeat, str::FromStr, sync::Arc};
+use std::{fmt, str::FromStr, sync::Arc};
 
 use anyhow::format_err;
 use async_trait::async_trait;
@@ -9,6 +9,7 @@ use hyper::{client::HttpConnector, Client};
 use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use strum_macros::Display;
 
 use crate::{
     addresses::{prefix_column_name, Address},
@@ -16,8 +17,10 @@ use crate::{
 };
 
 pub mod cache;
+pub mod cascade;
 pub mod invalid_record_skipper;
 pub mod libpostal;
+pub mod memory_cache;
 pub mod normalizer;
 pub mod paired;
 pub mod smarty;
@@ -27,12 +30,14 @@ pub type SharedHttpClient = Arc<Client<HttpsConnector<HttpConnector>>>;
 
 pub fn shared_http_client(concurrency: usize) -> SharedHttpClient {
     // Create a shared `hyper::Client` with a connection pool, so that we can
-    // use keep-alive.
+    // use keep-alive. We allow plain HTTP so that we can talk to local mock
+    // servers, but individual geocoders should insist on HTTPS for anything
+    // else.
     Arc::new(
         Client::builder().pool_max_idle_per_host(concurrency).build(
             HttpsConnectorBuilder::new()
                 .with_native_roots()
-                .https_only()
+                .https_or_http()
                 .enable_http2()
                 .build(),
         ),
@@ -116,6 +121,40 @@ impl Geocoded {
     }
 }
 
+/// Why we couldn't geocode an address.
+#[derive(Clone, Copy, Debug, Display, Eq, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum Unmatched {
+    /// The address was missing required fields, so we didn't try to geocode it.
+    InvalidRecord,
+    /// Our cache remembers that this address couldn't be geocoded.
+    CachedUnknown,
+    /// The address wasn't in our cache, and we were only using cached results.
+    NotCached,
+    /// Our geocoder couldn't find a match.
+    NoMatch,
+    /// Our geocoder rejected the address as invalid input.
+    RejectedByGeocoder,
+    /// We couldn't geocode the address because of an error, and we were
+    /// asked to continue anyway.
+    GeocoderError,
+}
+
+/// The result of geocoding a single address.
+pub type GeocodeResult = std::result::Result<Geocoded, Unmatched>;
+
+/// The type of a geocoder output column. We always produce strings, but output
+/// formats with typed columns can use this to pick a better type.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ColumnType {
+    /// Ordinary text.
+    Text,
+    /// A floating point number.
+    Float,
+    /// A boolean value, written as `T` or `F`.
+    Boolean,
+}
+
 /// Abstract geocoding interface.
 #[async_trait]
 pub trait Geocoder: Send + Sync + 'static {
@@ -133,6 +172,19 @@ pub trait Geocoder: Send + Sync + 'static {
     /// The column names output by this geocoder.
     fn column_names(&self) -> &[String];
 
+    /// The types of the columns output by this geocoder, in the same order as
+    /// [`Geocoder::column_names`]. By default, everything is text.
+    fn column_types(&self) -> Vec<ColumnType> {
+        vec![ColumnType::Text; self.column_names().len()]
+    }
+
+    /// The position of the column containing the match precision (like
+    /// Smarty's `precision`) for the best candidate, if we have one. This is
+    /// used to summarize our results.
+    fn precision_column(&self) -> Option<usize> {
+        None
+    }
+
     /// The full cache prefix to use for this geocoder.
     ///
     /// This is moderately expensive to compute, so please save it instead of
@@ -150,11 +202,12 @@ pub trait Geocoder: Send + Sync + 'static {
 
     /// Geocode a slice of addresses.
     ///
-    /// We return `None` for addresses that we couldn't geocode.
+    /// For addresses that we couldn't geocode, we return an [`Unmatched`]
+    /// value explaining why.
     async fn geocode_addresses(
         &self,
         addresses: &[Address],
-    ) -> Result<Vec<Option<Geocoded>>>;
+    ) -> Result<Vec<GeocodeResult>>;
 
     /// Update a CSV record with our headers, prefixed with `prefix`.
     fn add_header_columns(&self, prefix: &str, out_headers: &mut StringRecord) {
@@ -177,6 +230,8 @@ pub trait Geocoder: Send + Sync + 'static {
     /// Copy empty values into `geocoded`, one for each column that this
     /// geocoder would produce.
     fn add_empty_columns_to_row(&self, out_row: &mut StringRecord) {
-        out_row.extend(repeat("").take(self.column_names().len()));
+        for _ in 0..self.column_names().len() {
+            out_row.push_field("");
+        }
     }
 }
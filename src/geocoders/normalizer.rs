@@ -9,13 +9,14 @@ use tracing::trace;
 
 use crate::addresses::Address;
 
-use super::{libpostal::LibPostal, Geocoded, Geocoder, Result};
-
-/// Normalize geocodes and pass them through to another geocoder.
-pub struct Normalizer {
-    // Our inner geocoder that we're normalizing for.
-    inner: Box<dyn Geocoder>,
-
+use super::{
+    libpostal::LibPostal, ColumnType, GeocodeResult, Geocoded, Geocoder, Result,
+};
+
+/// Normalizes addresses using libpostal. This is the part of [`Normalizer`]
+/// which doesn't depend on the inner geocoder, so that tools like `cache
+/// invalidate` can build the same cache keys that we do.
+pub struct AddressNormalizer {
     // Our normalizer.
     libpostal: LibPostal,
 
@@ -24,9 +25,9 @@ pub struct Normalizer {
     libpostal_component_indices: HashMap<String, usize>,
 }
 
-impl Normalizer {
-    /// Create a new `Normalizer` wrapping the specified geocoder.
-    pub fn new(inner: Box<dyn Geocoder>) -> Normalizer {
+impl AddressNormalizer {
+    /// Create a new `AddressNormalizer`.
+    pub fn new() -> AddressNormalizer {
         describe_counter!(
             "geocodecsv.addresses_normalized.total",
             "Addresses changed by normalization"
@@ -38,34 +39,14 @@ impl Normalizer {
             libpostal_component_indices.insert(column_name.to_owned(), i);
         }
 
-        Normalizer {
-            inner,
+        AddressNormalizer {
             libpostal,
             libpostal_component_indices,
         }
     }
-}
-
-#[async_trait]
-impl Geocoder for Normalizer {
-    fn tag(&self) -> &str {
-        // TODO: We should probably incorporate our inner tag as well.
-        "norm"
-    }
 
-    fn configuration_key(&self) -> &str {
-        // TODO: Include libpostal configuration, too?
-        self.inner.configuration_key()
-    }
-
-    fn column_names(&self) -> &[String] {
-        self.inner.column_names()
-    }
-
-    async fn geocode_addresses(
-        &self,
-        addresses: &[Address],
-    ) -> Result<Vec<Option<Geocoded>>> {
+    /// Normalize `addresses`, returning one normalized address for each.
+    pub async fn normalize(&self, addresses: &[Address]) -> Result<Vec<Address>> {
         // Geocode using libpostal first.
         let normalized = self.libpostal.geocode_addresses(addresses).await?;
 
@@ -73,12 +54,12 @@ impl Geocoder for Normalizer {
         let mut normalized_addresses = vec![];
         for (i, raw) in normalized.iter().enumerate() {
             match raw {
-                None => {
+                Err(_) => {
                     // If our normalizer gave up completely (which I don't think
                     // ever happens?), pass through the original address.
                     normalized_addresses.push(addresses[i].clone());
                 }
-                Some(raw) => {
+                Ok(raw) => {
                     let normalized_address =
                         normalized_to_address(&self.libpostal_component_indices, raw);
                     if !normalized_address.eq_ignore_ascii_case(&addresses[i]) {
@@ -99,8 +80,59 @@ impl Geocoder for Normalizer {
                 }
             }
         }
+        Ok(normalized_addresses)
+    }
+}
 
+/// Normalize geocodes and pass them through to another geocoder.
+pub struct Normalizer {
+    // Our inner geocoder that we're normalizing for.
+    inner: Box<dyn Geocoder>,
+
+    // Our normalizer.
+    normalizer: AddressNormalizer,
+}
+
+impl Normalizer {
+    /// Create a new `Normalizer` wrapping the specified geocoder.
+    pub fn new(inner: Box<dyn Geocoder>) -> Normalizer {
+        Normalizer {
+            inner,
+            normalizer: AddressNormalizer::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl Geocoder for Normalizer {
+    fn tag(&self) -> &str {
+        // TODO: We should probably incorporate our inner tag as well.
+        "norm"
+    }
+
+    fn configuration_key(&self) -> &str {
+        // TODO: Include libpostal configuration, too?
+        self.inner.configuration_key()
+    }
+
+    fn column_names(&self) -> &[String] {
+        self.inner.column_names()
+    }
+
+    fn column_types(&self) -> Vec<ColumnType> {
+        self.inner.column_types()
+    }
+
+    fn precision_column(&self) -> Option<usize> {
+        self.inner.precision_column()
+    }
+
+    async fn geocode_addresses(
+        &self,
+        addresses: &[Address],
+    ) -> Result<Vec<GeocodeResult>> {
         // Pass normalized addresses to our inner geocoder.
+        let normalized_addresses = self.normalizer.normalize(addresses).await?;
         self.inner.geocode_addresses(&normalized_addresses).await
     }
 }
@@ -5,7 +5,7 @@
 use async_trait::async_trait;
 
 use crate::format_err;
-use crate::geocoders::{Geocoded, Geocoder};
+use crate::geocoders::{ColumnType, GeocodeResult, Geocoded, Geocoder};
 use crate::{addresses::Address, Result};
 
 /// A geocoder that runs two geocoders and returns both results.
@@ -66,14 +66,14 @@ impl Paired {
 
     fn combine_geocoder_results(
         &self,
-        fst: Option<Geocoded>,
-        snd: Option<Geocoded>,
-    ) -> Option<Geocoded> {
+        fst: GeocodeResult,
+        snd: GeocodeResult,
+    ) -> GeocodeResult {
         match (fst, snd) {
-            (None, None) => None,
-            (Some(f), None) => Some(f.concat(&self.snd_empty_output)),
-            (None, Some(s)) => Some(self.fst_empty_output.concat(&s)),
-            (Some(f), Some(s)) => Some(f.concat(&s)),
+            (Err(f), Err(_)) => Err(f),
+            (Ok(f), Err(_)) => Ok(f.concat(&self.snd_empty_output)),
+            (Err(_), Ok(s)) => Ok(self.fst_empty_output.concat(&s)),
+            (Ok(f), Ok(s)) => Ok(f.concat(&s)),
         }
     }
 }
@@ -92,10 +92,21 @@ impl Geocoder for Paired {
         &self.column_names
     }
 
+    fn column_types(&self) -> Vec<ColumnType> {
+        let mut column_types = self.fst.column_types();
+        column_types.extend(self.snd.column_types());
+        column_types
+    }
+
+    fn precision_column(&self) -> Option<usize> {
+        // Our first geocoder's columns come first.
+        self.fst.precision_column()
+    }
+
     async fn geocode_addresses(
         &self,
         addresses: &[Address],
-    ) -> Result<Vec<Option<Geocoded>>> {
+    ) -> Result<Vec<GeocodeResult>> {
         let fst = self.fst.geocode_addresses(addresses).await?;
         let snd = self.snd.geocode_addresses(addresses).await?;
         if fst.len() != snd.len() {
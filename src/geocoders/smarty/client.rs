@@ -1,18 +1,20 @@
 //! Interface to Smarty REST API.
 
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 use std::{env, str};
 
 use anyhow::{format_err, Context};
 use futures::stream::StreamExt;
-use hyper::{Body, Request};
+use hyper::{header::RETRY_AFTER, Body, Request, StatusCode};
 use metrics::{counter, describe_histogram, histogram, Unit};
 use serde::{Deserialize, Serialize};
 use tracing::{error, instrument};
-use url::Url;
+use url::{Host, Url};
 
 use crate::addresses::Address;
-use crate::errors::hyper_error_description_for_metrics;
+use crate::errors::{
+    hyper_error_description_for_metrics, RemoteStatusError, Throttled,
+};
 use crate::geocoders::{MatchStrategy, SharedHttpClient};
 use crate::unpack_vec::unpack_vec;
 use crate::Result;
@@ -50,6 +52,9 @@ pub struct AddressRequest {
     /// What match strategy should we use?
     #[serde(rename = "match")]
     pub match_strategy: MatchStrategy,
+
+    /// The maximum number of candidates to return for this address.
+    pub candidates: usize,
 }
 
 /// A Smarty address response.
@@ -65,22 +70,29 @@ pub struct AddressResponse {
     pub fields: serde_json::Value,
 }
 
+/// The default Smarty street address endpoint.
+pub const DEFAULT_URL: &str = "https://api.smartystreets.com/street-address";
+
 /// The real implementation of `S.
 pub struct SmartyClient {
+    url: Url,
     credentials: Credentials,
     client: SharedHttpClient,
 }
 
 impl SmartyClient {
-    /// Create a new Smarty client.
-    pub fn new(client: SharedHttpClient) -> Result<SmartyClient> {
+    /// Create a new Smarty client which talks to the street address endpoint
+    /// at `url`.
+    pub fn new(url: Url, client: SharedHttpClient) -> Result<SmartyClient> {
+        check_url(&url)?;
         describe_histogram!(
-            "geocodecsv.smart.geocode_request.duration_seconds",
+            "geocodecsv.smarty.geocode_request.duration_seconds",
             Unit::Seconds,
             "Time required for Smarty to geocode a batch of rows"
         );
 
         Ok(SmartyClient {
+            url,
             credentials: Credentials::from_env()?,
             client,
         })
@@ -97,8 +109,9 @@ impl SmartyClient {
         &self,
         requests: Vec<AddressRequest>,
         license: String,
-    ) -> Result<Vec<Option<AddressResponse>>> {
+    ) -> Result<Vec<Vec<AddressResponse>>> {
         street_addresses_impl(
+            self.url.clone(),
             self.credentials.clone(),
             self.client.clone(),
             requests,
@@ -108,17 +121,57 @@ impl SmartyClient {
     }
 }
 
-/// The real implementation of `street_addresses`.
+/// Make sure that `url` is safe to send our credentials to. We require HTTPS,
+/// except for loopback addresses, which are useful for testing against a local
+/// mock server.
+fn check_url(url: &Url) -> Result<()> {
+    let is_loopback = match url.host() {
+        Some(Host::Domain(domain)) => domain == "localhost",
+        Some(Host::Ipv4(addr)) => addr.is_loopback(),
+        Some(Host::Ipv6(addr)) => addr.is_loopback(),
+        None => false,
+    };
+    match url.scheme() {
+        "https" => Ok(()),
+        "http" if is_loopback => Ok(()),
+        _ => Err(format_err!(
+            "Smarty URL must use https: (or http: for localhost), found {}",
+            url,
+        )),
+    }
+}
+
+#[test]
+fn only_allow_http_for_loopback() {
+    for ok in [
+        DEFAULT_URL,
+        "http://localhost:8080/street-address",
+        "http://127.0.0.1:8080/street-address",
+        "http://[::1]:8080/street-address",
+    ] {
+        assert!(check_url(&Url::parse(ok).unwrap()).is_ok(), "{}", ok);
+    }
+    for bad in [
+        "http://api.smartystreets.com/street-address",
+        "http://10.0.0.1/street-address",
+        "ftp://localhost/street-address",
+    ] {
+        assert!(check_url(&Url::parse(bad).unwrap()).is_err(), "{}", bad);
+    }
+}
+
+/// The real implementation of `street_addresses`. Returns the candidates for
+/// each request, in the order Smarty ranked them.
 async fn street_addresses_impl(
+    mut url: Url,
     credentials: Credentials,
     client: SharedHttpClient,
     requests: Vec<AddressRequest>,
     license: String,
-) -> Result<Vec<Option<AddressResponse>>> {
+) -> Result<Vec<Vec<AddressResponse>>> {
     let start = Instant::now();
 
     // Build our URL.
-    let mut url = Url::parse("https://api.smartystreets.com/street-address")?;
     url.query_pairs_mut()
         .append_pair("auth-id", &credentials.auth_id)
         .append_pair("auth-token", &credentials.auth_token)
@@ -142,6 +195,10 @@ async fn street_addresses_impl(
         }
     };
     let status = res.status();
+    let retry_after_header = res.headers().get(RETRY_AFTER);
+    let has_retry_after = retry_after_header.is_some();
+    let retry_after = retry_after_header
+        .and_then(|value| parse_retry_after(value.to_str().ok()?, SystemTime::now()));
     let mut body = res.into_body();
     let mut body_data = vec![];
     while let Some(chunk_result) = body.next().await {
@@ -164,6 +221,19 @@ async fn street_addresses_impl(
         // Add error to metrics.
         counter!("geocodecsv.selected_errors.count", 1, "component" => "smarty", "cause" => status.to_string());
 
+        // If Smarty wants us to slow down, report that separately, so that we
+        // can back off instead of failing. A 503 only counts if it tells us
+        // when to come back. Otherwise, it's an ordinary server error.
+        if status == StatusCode::TOO_MANY_REQUESTS
+            || (status == StatusCode::SERVICE_UNAVAILABLE && has_retry_after)
+        {
+            return Err(Throttled {
+                status,
+                retry_after,
+            }
+            .into());
+        }
+
         // Log information about bad street fields, if we can.
         if status == 422 {
             if let Ok(error_response) =
@@ -186,13 +256,53 @@ async fn street_addresses_impl(
             }
         }
 
-        // Convert to a Rust error.
-        Err(format_err!(
-            "geocoding error: {}\n{}",
+        // Convert to a Rust error, explaining the errors that we see most
+        // often.
+        let err = anyhow::Error::from(RemoteStatusError {
             status,
-            String::from_utf8_lossy(&body_data),
-        ))
+            body: String::from_utf8_lossy(&body_data).into_owned(),
+        });
+        Err(match status {
+            StatusCode::UNAUTHORIZED => err.context(
+                "Smarty rejected our credentials (check SMARTY_AUTH_ID and SMARTY_AUTH_TOKEN)",
+            ),
+            StatusCode::PAYMENT_REQUIRED => err.context(
+                "Smarty requires payment (check your subscription and --smarty-license)",
+            ),
+            _ => err,
+        })
+    }
+}
+
+/// Parse a `Retry-After` header, which may be either a number of seconds or
+/// an HTTP date. Dates are converted to a delay from `now`, and dates in the
+/// past mean that we can retry immediately.
+fn parse_retry_after(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
     }
+    let date = httpdate::parse_http_date(value).ok()?;
+    Some(date.duration_since(now).unwrap_or_default())
+}
+
+#[test]
+fn parse_retry_after_seconds_and_dates() {
+    let now = httpdate::parse_http_date("Wed, 21 Oct 2015 07:28:00 GMT").unwrap();
+    assert_eq!(parse_retry_after("5", now), Some(Duration::from_secs(5)));
+    assert_eq!(
+        parse_retry_after(" 120 ", now),
+        Some(Duration::from_secs(120))
+    );
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+        Some(Duration::from_secs(30)),
+    );
+    assert_eq!(
+        parse_retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+        Some(Duration::ZERO),
+    );
+    assert_eq!(parse_retry_after("soon", now), None);
 }
 
 /// Smarty error response body.
@@ -2,21 +2,30 @@ use std::sync::Arc;
 
 use anyhow::format_err;
 use async_trait::async_trait;
-use leaky_bucket::RateLimiter;
 use metrics::{counter, describe_counter};
-use tracing::{trace_span, Instrument};
+use tokio::time::Instant;
+use url::Url;
 
-use crate::{addresses::Address, Result};
+use crate::{
+    addresses::Address, concurrency::record_upstream_latency, errors::Throttled,
+    rate_limiter::AdaptiveRateLimiter, Result,
+};
 
 use self::{
     client::{AddressRequest, SmartyClient},
     structure::Structure,
 };
 
-use super::{Geocoded, Geocoder, MatchStrategy, SharedHttpClient};
+use super::{
+    ColumnType, GeocodeResult, Geocoded, Geocoder, MatchStrategy, SharedHttpClient,
+    Unmatched,
+};
 
 pub mod client;
-mod structure;
+pub mod structure;
+
+/// The largest number of addresses that Smarty accepts in a single request.
+pub const MAX_BATCH_SIZE: usize = 100;
 
 /// Geocoding interface for Smarty.
 pub struct Smarty {
@@ -26,39 +35,97 @@ pub struct Smarty {
     /// The names of the geocoding output columns we produce.
     column_names: Vec<String>,
 
+    /// The types of the geocoding output columns we produce.
+    column_types: Vec<ColumnType>,
+
     /// How should we match addresses?
     match_strategy: MatchStrategy,
 
+    /// How many candidates should we ask for? If this is more than 1, we
+    /// output a numbered group of columns for each candidate.
+    max_candidates: usize,
+
+    /// The number of columns in each candidate group.
+    candidate_column_count: usize,
+
+    /// The position of the `precision` column for our first candidate, if we
+    /// output it.
+    precision_column: Option<usize>,
+
     /// What Smarty license are we using?
     license: String,
 
     /// The structure of a Smarty response.
     structure: Structure,
 
-    /// Optionally controls rate at which we access Smarty.
-    rate_limiter: Option<Arc<RateLimiter>>,
+    /// Controls the rate at which we access Smarty, and slows down when
+    /// Smarty throttles us.
+    rate_limiter: Arc<AdaptiveRateLimiter>,
 
-    /// Our Smarty API client.
-    client: SmartyClient,
+    /// Our Smarty API client, if we can make requests.
+    client: Option<SmartyClient>,
 }
 
 impl Smarty {
+    /// Create a new Smarty geocoder, which will output the fields in
+    /// `structure` for up to `max_candidates` candidates. Requests are sent to
+    /// the street address endpoint at `url`.
+    ///
+    /// If `http_client` is `None`, we don't need credentials, but we can't
+    /// geocode anything. This is still enough to describe our output columns
+    /// and cache entries, for cascade tiers which only read our cache.
     pub fn new(
         match_strategy: MatchStrategy,
         license: String,
-        rate_limiter: Option<Arc<RateLimiter>>,
-        http_client: SharedHttpClient,
+        structure: Structure,
+        max_candidates: usize,
+        url: Url,
+        rate_limiter: Arc<AdaptiveRateLimiter>,
+        http_client: Option<SharedHttpClient>,
     ) -> Result<Smarty> {
         describe_counter!("geocodecsv.addresses_geocoded.total", "Addresses geocoded");
 
-        let configuration_key = format!("{}:{}", match_strategy, license);
-        let structure = Structure::complete()?;
-        let column_names = structure.output_column_names()?;
-        let client = SmartyClient::new(http_client)?;
+        // Custom structures need to be part of our cache key. We leave the key
+        // alone for the default structure, so that existing caches still work.
+        let mut configuration_key = format!("{}:{}", match_strategy, license);
+        let structure_key = structure.configuration_key();
+        if structure_key != Structure::complete()?.configuration_key() {
+            configuration_key.push(':');
+            configuration_key.push_str(&structure_key);
+        }
+
+        // Smarty allows up to 10 candidates. If we have more than one, we
+        // number our columns, starting from 1.
+        if !(1..=10).contains(&max_candidates) {
+            return Err(format_err!(
+                "number of candidates must be between 1 and 10, found {}",
+                max_candidates,
+            ));
+        }
+        let candidate_column_names = structure.output_column_names()?;
+        let candidate_column_types = structure.output_column_types()?;
+        let candidate_column_count = candidate_column_names.len();
+        let precision_column = candidate_column_names
+            .iter()
+            .position(|name| name == "precision");
+        if max_candidates > 1 {
+            configuration_key.push_str(&format!(":candidates={}", max_candidates));
+        }
+        let column_names =
+            numbered_candidate_columns(&candidate_column_names, max_candidates);
+        let column_types = candidate_column_types.repeat(max_candidates);
+
+        let client = http_client
+            .map(|http_client| SmartyClient::new(url, http_client))
+            .transpose()?;
         Ok(Smarty {
             configuration_key,
             column_names,
+            column_types,
             match_strategy,
+            max_candidates,
+            candidate_column_count,
+            precision_column,
             license,
             structure,
             rate_limiter,
@@ -67,6 +134,35 @@ impl Smarty {
     }
 }
 
+/// Build column names for `max_candidates` candidates. If we only have one
+/// candidate, we use `column_names` as is. Otherwise, we output one group of
+/// columns for each candidate, numbered from 1.
+fn numbered_candidate_columns(
+    column_names: &[String],
+    max_candidates: usize,
+) -> Vec<String> {
+    if max_candidates == 1 {
+        return column_names.to_owned();
+    }
+    (1..=max_candidates)
+        .flat_map(|candidate| {
+            column_names
+                .iter()
+                .map(move |name| format!("{}_{}", candidate, name))
+        })
+        .collect()
+}
+
+#[test]
+fn numbered_candidate_column_names() {
+    let column_names = vec!["latitude".to_owned(), "longitude".to_owned()];
+    assert_eq!(numbered_candidate_columns(&column_names, 1), column_names);
+    assert_eq!(
+        numbered_candidate_columns(&column_names, 2),
+        &["1_latitude", "1_longitude", "2_latitude", "2_longitude"],
+    );
+}
+
 #[async_trait]
 impl Geocoder for Smarty {
     fn tag(&self) -> &str {
@@ -81,57 +177,90 @@ impl Geocoder for Smarty {
         &self.column_names
     }
 
+    fn column_types(&self) -> Vec<ColumnType> {
+        self.column_types.clone()
+    }
+
+    fn precision_column(&self) -> Option<usize> {
+        // Our first candidate's columns come first.
+        self.precision_column
+    }
+
     async fn geocode_addresses(
         &self,
         addresses: &[Address],
-    ) -> Result<Vec<Option<Geocoded>>> {
+    ) -> Result<Vec<GeocodeResult>> {
         // An array used to store our geocoding results.
-        let mut geocoded = vec![None; addresses.len()];
+        let mut geocoded = vec![Err(Unmatched::NoMatch); addresses.len()];
 
         // If we have nothing to do, exit immediately.
         if addresses.is_empty() {
             return Ok(geocoded);
         }
+        let client = self.client.as_ref().ok_or_else(|| {
+            format_err!("cannot geocode using Smarty without an HTTP client")
+        })?;
 
-        // If we have a rate limiter, ask for permission to geocode the
-        // specified number of addresses. We only check if we have one, in order
-        // minimize thread synchronization costs.
-        if let Some(rate_limiter) = &self.rate_limiter {
-            let permits_needed = addresses.len();
-            let span =
-                trace_span!("rate_limiter::acquire", permits_needed = permits_needed);
-            rate_limiter.acquire(permits_needed).instrument(span).await;
-        }
+        // Ask for permission to geocode the specified number of addresses.
+        self.rate_limiter.acquire(addresses.len()).await;
 
         let requests = addresses
             .iter()
             .map(|addr| AddressRequest {
                 match_strategy: self.match_strategy,
+                candidates: self.max_candidates,
                 address: addr.to_owned(), // This could be more efficient.
             })
             .collect::<Vec<_>>();
 
-        let response = self
-            .client
+        // Time only the request itself, and not our wait for the rate limiter,
+        // so that throttling doesn't also count as slow requests.
+        let start = Instant::now();
+        let result = client
             .street_addresses(requests, self.license.to_owned())
-            .await?;
+            .await;
+        record_upstream_latency(start.elapsed());
 
-        let hits = response.iter().filter(|g| g.is_some()).count();
+        // If Smarty throttles us, slow down all our workers before reporting
+        // the error.
+        let response = match result {
+            Ok(response) => {
+                self.rate_limiter.succeeded();
+                response
+            }
+            Err(err) => {
+                if let Some(throttled) = err.downcast_ref::<Throttled>() {
+                    self.rate_limiter.throttled(throttled.retry_after);
+                }
+                return Err(err);
+            }
+        };
+
+        let hits = response.iter().filter(|g| !g.is_empty()).count();
         counter!("geocodecsv.addresses_geocoded.total", hits as u64, "geocoder" => "smarty", "geocode_result" => "found");
         counter!("geocodecsv.addresses_geocoded.total", (addresses.len() - hits) as u64, "geocoder" => "smarty", "geocode_result" => "unknown_address");
 
-        // Response with only the addresses that actually geocoded successfully.
-        // We need make sure we map outputs back to their original positions.
-        for address_output in response.into_iter().flatten() {
-            let column_values =
-                self.structure.value_columns_for(&address_output.fields)?;
+        // Fill in the addresses that actually geocoded successfully, with one
+        // group of columns per candidate. If we have fewer candidates than
+        // `max_candidates`, we leave the remaining groups empty.
+        for (i, candidates) in response.into_iter().enumerate() {
+            if candidates.is_empty() {
+                continue;
+            }
+            let mut column_values =
+                Vec::with_capacity(self.max_candidates * self.candidate_column_count);
+            for address_output in candidates.iter().take(self.max_candidates) {
+                column_values
+                    .extend(self.structure.value_columns_for(&address_output.fields)?);
+            }
+            column_values.resize(self.column_names.len(), String::new());
             let candidate = Geocoded { column_values };
             if candidate.contains_null_bytes() {
                 return Err(format_err!(
                     "Smarty returned a geocoded address with a null byte"
                 ));
             }
-            geocoded[address_output.input_index] = Some(candidate);
+            geocoded[i] = Ok(candidate);
         }
 
         Ok(geocoded)
@@ -1,13 +1,15 @@
 //! Various subsets of data potenitally returned by Smarty.
 //!
-//! There used to be an idea that we'd allow users to customize this, but it
-//! never happened, and it probably never will, now that we're moving to
-//! multiple backends. So this is now an implementation detail.
+//! By default, we use `structures/complete.json`, but users can supply their
+//! own structure using `--smarty-structure`. A structure is a JSON object
+//! mapping Smarty field names to `true` or `false`, with nested objects for
+//! fields like `components` and `metadata`.
 
-use anyhow::format_err;
+use anyhow::{format_err, Context};
 use serde_json::{self, Map, Value};
-use std::borrow::Cow;
+use std::{borrow::Cow, collections::HashSet, fs, path::Path};
 
+use crate::geocoders::ColumnType;
 use crate::Result;
 
 /// A subset of the fields returned by Smarty.
@@ -33,6 +35,14 @@ impl Structure {
         Self::from_str(COMPLETE)
     }
 
+    /// Load a `Structure` from a JSON file.
+    pub fn from_path(path: &Path) -> Result<Structure> {
+        let s = fs::read_to_string(path)
+            .with_context(|| format_err!("cannot read {}", path.display()))?;
+        Self::from_str(&s)
+            .with_context(|| format_err!("cannot parse {}", path.display()))
+    }
+
     /// Parse a `Structure` from a string containing JSON.
     fn from_str(s: &str) -> Result<Structure> {
         // Parse our JSON and build our structure.
@@ -42,16 +52,29 @@ impl Structure {
             fields,
         };
 
-        // Update our column count.
-        let mut count = 0;
-        structure.traverse(|_path| {
-            count += 1;
+        // Update our column count, and make sure that we won't output two
+        // columns with the same name.
+        let mut names = HashSet::new();
+        structure.traverse(|path| {
+            let name = structure.column_name(path);
+            if !names.insert(name) {
+                return Err(format_err!(
+                    "more than one field would produce the column {:?}",
+                    structure.column_name(path),
+                ));
+            }
             Ok(())
         })?;
-        structure.column_count = count;
+        structure.column_count = names.len();
         Ok(structure)
     }
 
+    /// A string representing this structure, which can be used as part of a
+    /// cache key.
+    pub fn configuration_key(&self) -> String {
+        Value::Object(self.fields.clone()).to_string()
+    }
+
     /// Given the path to a colum in our [`structure::Structure`], return the
     /// column name we should use. This will panic if `path` is empty, because
     /// that should be impossible.
@@ -73,6 +96,24 @@ impl Structure {
         Ok(columns)
     }
 
+    /// Return the types of the columns returned by
+    /// [`Structure::output_column_names`]. Smarty returns most fields as
+    /// strings, so we only need to list the exceptions.
+    pub fn output_column_types(&self) -> Result<Vec<ColumnType>> {
+        let mut types = vec![];
+        self.traverse(|path| {
+            types.push(match path {
+                ["metadata", "latitude"]
+                | ["metadata", "longitude"]
+                | ["metadata", "utc_offset"] => ColumnType::Float,
+                ["metadata", "dst"] | ["analysis", "ews_match"] => ColumnType::Boolean,
+                _ => ColumnType::Text,
+            });
+            Ok(())
+        })?;
+        Ok(types)
+    }
+
     /// Extract fields from `data` and merge them into `row`.
     ///
     /// PERFORMANCE: This is probably slower than it should be in a hot loop.
@@ -213,6 +254,68 @@ fn output_column_names() {
     assert_eq!(column_names, expected);
 }
 
+#[test]
+fn custom_structure() {
+    let structure = Structure::from_str(
+        r#"{
+    "delivery_line_1": true,
+    "delivery_point_barcode": false,
+    "metadata": {
+        "latitude": true,
+        "longitude": true,
+        "coordinate_license": true
+    },
+    "analysis": {
+        "enhanced_match": true
+    }
+}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        structure.output_column_names().unwrap(),
+        &[
+            "delivery_line_1",
+            "latitude",
+            "longitude",
+            "coordinate_license",
+            "enhanced_match",
+        ]
+    );
+    assert_ne!(
+        structure.configuration_key(),
+        Structure::complete().unwrap().configuration_key(),
+    );
+}
+
+#[test]
+fn invalid_structures() {
+    // Non-boolean leaves.
+    assert!(Structure::from_str(r#"{"addressee": 1}"#).is_err());
+    // Nested too deeply.
+    assert!(Structure::from_str(r#"{"a": {"b": {"c": true}}}"#).is_err());
+    // Duplicate column names.
+    assert!(Structure::from_str(
+        r#"{"components": {"zipcode": true}, "metadata": {"zipcode": true}}"#
+    )
+    .is_err());
+}
+
+#[test]
+fn output_column_types() {
+    let structure = Structure::complete().unwrap();
+    let column_names = structure.output_column_names().unwrap();
+    let column_types = structure.output_column_types().unwrap();
+    assert_eq!(column_names.len(), column_types.len());
+    for (name, column_type) in column_names.iter().zip(&column_types) {
+        let expected = match &name[..] {
+            "latitude" | "longitude" | "utc_offset" => ColumnType::Float,
+            "dst" | "ews_match" => ColumnType::Boolean,
+            _ => ColumnType::Text,
+        };
+        assert_eq!(*column_type, expected, "wrong type for {}", name);
+    }
+}
+
 #[test]
 fn value_columns_for() {
     let structure = Structure::complete().unwrap();
@@ -0,0 +1,479 @@
+//! Opening input and output streams, with optional compression.
+
+use std::{
+    ffi::OsStr,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{format_err, Context};
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use flate2::{read::MultiGzDecoder, write::GzEncoder};
+use strum_macros::EnumString;
+
+use crate::formats::{csv::CsvDialect, Format};
+use crate::progress::PROGRESS;
+use crate::Result;
+
+/// Compression formats that we support for input and output.
+#[derive(Clone, Copy, Debug, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum Compression {
+    /// Guess the compression format from the file extension.
+    Auto,
+    /// No compression.
+    None,
+    /// `gzip` compression (`.gz`).
+    Gzip,
+    /// `zstd` compression (`.zst`).
+    Zstd,
+}
+
+impl Compression {
+    /// Replace `Auto` with a concrete compression format, based on the
+    /// extension of `path`. Standard input and output are never assumed to be
+    /// compressed.
+    fn resolve(self, path: Option<&Path>) -> Compression {
+        match (self, path) {
+            (Compression::Auto, Some(path)) => {
+                match path.extension().and_then(OsStr::to_str) {
+                    Some("gz") => Compression::Gzip,
+                    Some("zst") => Compression::Zstd,
+                    _ => Compression::None,
+                }
+            }
+            (Compression::Auto, None) => Compression::None,
+            (compression, _) => compression,
+        }
+    }
+}
+
+#[test]
+fn resolve_compression_from_extension() {
+    let examples = &[
+        (Compression::Auto, Some("in.csv"), Compression::None),
+        (Compression::Auto, Some("in.csv.gz"), Compression::Gzip),
+        (Compression::Auto, Some("in.csv.zst"), Compression::Zstd),
+        (Compression::Auto, None, Compression::None),
+        (Compression::Gzip, Some("in.csv"), Compression::Gzip),
+        (Compression::None, Some("in.csv.gz"), Compression::None),
+        (Compression::Zstd, None, Compression::Zstd),
+    ];
+    for &(compression, path, expected) in examples {
+        assert_eq!(compression.resolve(path.map(Path::new)), expected);
+    }
+}
+
+/// Text encodings that we can read. We always write UTF-8.
+#[derive(Clone, Copy, Debug, EnumString, Eq, PartialEq)]
+pub enum Encoding {
+    /// UTF-8.
+    #[strum(serialize = "utf-8", serialize = "utf8")]
+    Utf8,
+    /// ISO-8859-1. Like web browsers, we actually decode this as
+    /// Windows-1252, which differs only in some rarely-used control
+    /// characters.
+    #[strum(serialize = "latin1", serialize = "iso-8859-1")]
+    Latin1,
+    /// Windows-1252, used by many older Windows applications.
+    #[strum(serialize = "windows-1252", serialize = "cp1252")]
+    Windows1252,
+}
+
+/// Where to read input or write output.
+#[derive(Clone, Debug)]
+pub struct Location {
+    /// The file to use, or `None` for standard input or output.
+    pub path: Option<PathBuf>,
+
+    /// How the data is compressed.
+    pub compression: Compression,
+
+    /// The file format.
+    pub format: Format,
+
+    /// The text encoding of our input. Ignored for output.
+    pub encoding: Encoding,
+
+    /// Should we strip any byte-order mark from the start of our input?
+    /// Ignored for output.
+    pub strip_bom: bool,
+
+    /// How to read or write CSV data. Ignored by other formats.
+    pub csv_dialect: CsvDialect,
+}
+
+impl Location {
+    /// A human-readable description of this location, for error messages.
+    pub fn description(&self) -> String {
+        match &self.path {
+            Some(path) => path.display().to_string(),
+            None => "standard I/O".to_owned(),
+        }
+    }
+
+    /// The file format to use, guessing it from the file extension if needed.
+    pub fn format(&self) -> Format {
+        self.format.resolve(self.path.as_deref())
+    }
+
+    /// If this is standard output and no format was specified, use `format`
+    /// instead of defaulting to CSV.
+    pub fn with_default_format(mut self, format: Format) -> Location {
+        if self.path.is_none() && self.format == Format::Auto {
+            self.format = format;
+        }
+        self
+    }
+
+    /// Open this location as an uncompressed file. This is needed by formats
+    /// like Parquet, which read the end of the file before the beginning.
+    pub fn open_file(&self) -> Result<File> {
+        let path = self.path.as_ref().ok_or_else(|| {
+            format_err!("{:?} input must be read from a file", self.format())
+        })?;
+        if self.compression.resolve(Some(path)) != Compression::None {
+            return Err(format_err!(
+                "{:?} input cannot be compressed: {}",
+                self.format(),
+                path.display(),
+            ));
+        }
+        File::open(path).with_context(|| format_err!("cannot open {}", path.display()))
+    }
+
+    /// Open this location for reading, decompressing it if necessary.
+    ///
+    /// This must be called on the thread that will do the reading, because
+    /// standard input can't be sent between threads once locked.
+    pub fn open_reader(&self) -> Result<Box<dyn Read>> {
+        let raw: Box<dyn Read> = match &self.path {
+            Some(path) => {
+                let file = File::open(path)
+                    .with_context(|| format_err!("cannot open {}", path.display()))?;
+                // Count how much of the file we've read, so that we can
+                // estimate how long we have left.
+                if let Ok(metadata) = file.metadata() {
+                    PROGRESS.set_input_bytes(metadata.len());
+                }
+                Box::new(BufReader::new(CountingReader { inner: file }))
+            }
+            None => Box::new(io::stdin().lock()),
+        };
+        let decompressed: Box<dyn Read> = match self
+            .compression
+            .resolve(self.path.as_deref())
+        {
+            Compression::None | Compression::Auto => raw,
+            // Use `MultiGzDecoder`, because `gzip` files may be built by
+            // concatenating several compressed "members".
+            Compression::Gzip => Box::new(BufReader::new(MultiGzDecoder::new(raw))),
+            Compression::Zstd => Box::new(
+                zstd::Decoder::new(raw).context("cannot initialize zstd decoder")?,
+            ),
+        };
+        Ok(self.decode(decompressed))
+    }
+
+    /// Transcode `rdr` to UTF-8 and strip any byte-order mark, if requested.
+    fn decode(&self, rdr: Box<dyn Read>) -> Box<dyn Read> {
+        match (self.encoding, self.strip_bom) {
+            (Encoding::Utf8, false) => rdr,
+            // Pass through invalid UTF-8 unchanged, so that our CSV parser
+            // can report it with a line number.
+            (Encoding::Utf8, true) => Box::new(
+                DecodeReaderBytesBuilder::new()
+                    .utf8_passthru(true)
+                    .strip_bom(true)
+                    .build(rdr),
+            ),
+            (Encoding::Latin1 | Encoding::Windows1252, strip_bom) => Box::new(
+                DecodeReaderBytesBuilder::new()
+                    .encoding(Some(encoding_rs::WINDOWS_1252))
+                    .strip_bom(strip_bom)
+                    .build(rdr),
+            ),
+        }
+    }
+
+    /// Open this location for writing, compressing it if necessary.
+    ///
+    /// You must call [`OutputStream::finish`] when done, or compressed output
+    /// may be truncated.
+    pub fn create_writer(&self) -> Result<OutputStream> {
+        let raw: Box<dyn Write + Send> =
+            match &self.path {
+                Some(path) => Box::new(File::create(path).with_context(|| {
+                    format_err!("cannot create {}", path.display())
+                })?),
+                None => Box::new(io::stdout()),
+            };
+        self.output_stream(raw, 0)
+    }
+
+    /// Reopen this location to append more output, discarding anything after
+    /// `offset`. `offset` should have been returned by
+    /// [`OutputStream::checkpoint`].
+    pub fn append_writer(&self, offset: u64) -> Result<OutputStream> {
+        let path = self
+            .path
+            .as_ref()
+            .ok_or_else(|| format_err!("cannot append to standard output"))?;
+        let file = reopen_at(path, offset)?;
+        self.output_stream(Box::new(file), offset)
+    }
+
+    /// Wrap `raw` in an `OutputStream`, assuming that `offset` bytes have
+    /// already been written.
+    fn output_stream(
+        &self,
+        raw: Box<dyn Write + Send>,
+        offset: u64,
+    ) -> Result<OutputStream> {
+        let raw = BufWriter::new(CountingWriter {
+            inner: raw,
+            count: offset,
+        });
+        let compression = self.compression.resolve(self.path.as_deref());
+        Ok(OutputStream {
+            compression,
+            encoder: Encoder::new(compression, raw)?,
+        })
+    }
+}
+
+/// Reopen the file at `path` for writing, discarding anything after `offset`,
+/// and positioned so that we write after `offset`. Used when resuming from a
+/// checkpoint.
+pub fn reopen_at(path: &Path, offset: u64) -> Result<File> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .with_context(|| format_err!("cannot open {}", path.display()))?;
+    file.set_len(offset)
+        .with_context(|| format_err!("cannot truncate {}", path.display()))?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(file)
+}
+
+/// A reader which records how many bytes have been read from our input file.
+struct CountingReader {
+    inner: File,
+}
+
+impl Read for CountingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        PROGRESS.read_input_bytes(count);
+        Ok(count)
+    }
+}
+
+/// A writer which counts how many bytes have been written to it.
+struct CountingWriter {
+    inner: Box<dyn Write + Send>,
+    count: u64,
+}
+
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.count += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The uncompressed writer underneath our `Encoder`.
+type RawWriter = BufWriter<CountingWriter>;
+
+/// An output stream, possibly compressed.
+pub struct OutputStream {
+    compression: Compression,
+    encoder: Encoder,
+}
+
+/// The different kinds of encoders we support.
+enum Encoder {
+    Plain(RawWriter),
+    Gzip(GzEncoder<RawWriter>),
+    Zstd(zstd::Encoder<'static, RawWriter>),
+}
+
+impl Encoder {
+    /// Create a new encoder for `compression`, which must not be `Auto`.
+    fn new(compression: Compression, raw: RawWriter) -> Result<Encoder> {
+        Ok(match compression {
+            Compression::None | Compression::Auto => Encoder::Plain(raw),
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(raw, flate2::Compression::default()))
+            }
+            Compression::Zstd => Encoder::Zstd(
+                zstd::Encoder::new(raw, zstd::DEFAULT_COMPRESSION_LEVEL)
+                    .context("cannot initialize zstd encoder")?,
+            ),
+        })
+    }
+
+    /// Finish writing any compressed data and flush all buffers.
+    fn finish(self) -> Result<RawWriter> {
+        let mut raw = match self {
+            Encoder::Plain(raw) => raw,
+            Encoder::Gzip(encoder) => {
+                encoder.finish().context("could not finish gzip output")?
+            }
+            Encoder::Zstd(encoder) => {
+                encoder.finish().context("could not finish zstd output")?
+            }
+        };
+        raw.flush().context("could not flush output")?;
+        Ok(raw)
+    }
+}
+
+impl OutputStream {
+    /// Finish any compressed data, flush all buffers, and return the number of
+    /// bytes written so far. If we're interrupted after this, we can use
+    /// [`Location::append_writer`] to continue writing from this point.
+    ///
+    /// Both `gzip` and `zstd` allow concatenating compressed data, so we just
+    /// start over with a new encoder.
+    pub fn checkpoint(&mut self) -> Result<u64> {
+        let placeholder = Encoder::Plain(BufWriter::new(CountingWriter {
+            inner: Box::new(io::sink()),
+            count: 0,
+        }));
+        let raw = mem::replace(&mut self.encoder, placeholder).finish()?;
+        let offset = raw.get_ref().count;
+        self.encoder = Encoder::new(self.compression, raw)?;
+        Ok(offset)
+    }
+
+    /// Finish writing any compressed data and flush all buffers. We do this
+    /// explicitly instead of relying on `Drop`, so that we can report errors.
+    pub fn finish(self) -> Result<()> {
+        self.encoder.finish()?;
+        Ok(())
+    }
+}
+
+impl Write for OutputStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match &mut self.encoder {
+            Encoder::Plain(w) => w.write(buf),
+            Encoder::Gzip(w) => w.write(buf),
+            Encoder::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.encoder {
+            Encoder::Plain(w) => w.flush(),
+            Encoder::Gzip(w) => w.flush(),
+            Encoder::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+#[test]
+fn compressed_round_trip() {
+    let dir =
+        std::env::temp_dir().join(format!("geocode-csv-io-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in &["plain.csv", "data.csv.gz", "data.csv.zst"] {
+        let location = Location {
+            path: Some(dir.join(name)),
+            compression: Compression::Auto,
+            format: Format::Csv,
+            encoding: Encoding::Utf8,
+            strip_bom: false,
+            csv_dialect: CsvDialect::default(),
+        };
+        let mut wtr = location.create_writer().unwrap();
+        wtr.write_all(b"a,b\n1,2\n").unwrap();
+        wtr.finish().unwrap();
+
+        let mut data = String::new();
+        location
+            .open_reader()
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "a,b\n1,2\n");
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn checkpoint_and_append() {
+    let dir = std::env::temp_dir()
+        .join(format!("geocode-csv-checkpoint-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in &["plain.csv", "data.csv.gz", "data.csv.zst"] {
+        let location = Location {
+            path: Some(dir.join(name)),
+            compression: Compression::Auto,
+            format: Format::Csv,
+            encoding: Encoding::Utf8,
+            strip_bom: false,
+            csv_dialect: CsvDialect::default(),
+        };
+        let mut wtr = location.create_writer().unwrap();
+        wtr.write_all(b"a,b\n").unwrap();
+        let offset = wtr.checkpoint().unwrap();
+        wtr.write_all(b"lost,data\n").unwrap();
+        wtr.checkpoint().unwrap();
+        drop(wtr);
+
+        let mut wtr = location.append_writer(offset).unwrap();
+        wtr.write_all(b"1,2\n").unwrap();
+        wtr.finish().unwrap();
+
+        let mut data = String::new();
+        location
+            .open_reader()
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "a,b\n1,2\n");
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn decode_windows_1252_and_strip_bom() {
+    let dir = std::env::temp_dir()
+        .join(format!("geocode-csv-decode-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let examples: &[(&[u8], Encoding, bool, &str)] = &[
+        (b"caf\xe9", Encoding::Windows1252, false, "café"),
+        (b"\x93hi\x94", Encoding::Latin1, false, "\u{201c}hi\u{201d}"),
+        (b"\xef\xbb\xbfcaf\xc3\xa9", Encoding::Utf8, true, "café"),
+        (b"\xef\xbb\xbfa", Encoding::Utf8, false, "\u{feff}a"),
+    ];
+    for (i, &(input, encoding, strip_bom, expected)) in examples.iter().enumerate() {
+        let path = dir.join(format!("{}.csv", i));
+        std::fs::write(&path, input).unwrap();
+        let location = Location {
+            path: Some(path),
+            compression: Compression::Auto,
+            format: Format::Csv,
+            encoding,
+            strip_bom,
+            csv_dialect: CsvDialect::default(),
+        };
+        let mut data = String::new();
+        location
+            .open_reader()
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, expected);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -3,31 +3,150 @@
 use std::{
     borrow::Cow,
     collections::HashMap,
-    time::{Duration, Instant},
+    convert::TryFrom,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{format_err, Context};
 use async_trait::async_trait;
 use bigtable_rs::{
-    bigtable::{self, BigTable as BigTableClient, BigTableConnection},
+    bigtable::{self, BigTable as BigTableClient, BigTableConnection, RowCell},
     google::bigtable::v2::{
         mutate_rows_request::Entry,
-        mutation::{self, SetCell},
-        row_filter::{Chain, Filter},
-        MutateRowsRequest, Mutation, ReadRowsRequest, RowFilter, RowSet,
+        mutation::{self, DeleteFromRow, SetCell},
+        row_filter::{Chain, Filter, Interleave},
+        row_range::{EndKey, StartKey},
+        MutateRowsRequest, Mutation, ReadRowsRequest, RowFilter, RowRange, RowSet,
+        TimestampRange,
     },
 };
 use metrics::{counter, describe_histogram, histogram, Unit};
 use tracing::{instrument, trace};
 use url::Url;
 
-use crate::{pipeline::CONCURRENCY, Result};
+use crate::Result;
 
-use super::{KeyValueStore, KeyValueStoreNew, PipelinedGet, PipelinedSet};
+use super::{
+    prefix_end, KeyValueStore, KeyValueStoreNew, PipelinedGet, PipelinedSet, ScanPage,
+};
 
 const GEOCODE_CSV_FAMILY_NAME: &str = "geocode_csv";
 const GEOCODE_CSV_COLUMN_NAME: &[u8] = b"v";
 
+/// The column we use for values which expire. The timestamp of each cell in
+/// this column is the time at which it expires, so that we can filter out
+/// expired values when reading. (And if every value in a table expires, a
+/// garbage collection rule with a maximum age will delete them eventually.)
+const GEOCODE_CSV_EXPIRING_COLUMN_NAME: &[u8] = b"e";
+
+/// The current time, in BigTable's default timestamp format: microseconds
+/// since the Unix epoch, rounded down to the nearest millisecond.
+fn now_micros() -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    i64::try_from(now.as_millis())
+        .unwrap_or(i64::MAX / 1000)
+        .saturating_mul(1000)
+}
+
+/// Build a filter which returns the latest cell in `qualifier`, with
+/// timestamps in `timestamp_range` if specified.
+fn latest_cell_filter(
+    qualifier: &[u8],
+    timestamp_range: Option<TimestampRange>,
+) -> RowFilter {
+    let mut filters = vec![RowFilter {
+        filter: Some(Filter::ColumnQualifierRegexFilter(qualifier.to_vec())),
+    }];
+    if let Some(timestamp_range) = timestamp_range {
+        filters.push(RowFilter {
+            filter: Some(Filter::TimestampRangeFilter(timestamp_range)),
+        });
+    }
+    filters.push(RowFilter {
+        filter: Some(Filter::CellsPerColumnLimitFilter(1)),
+    });
+    RowFilter {
+        filter: Some(Filter::Chain(Chain { filters })),
+    }
+}
+
+/// Build a filter which returns the latest permanent value, and the latest
+/// expiring value which hasn't expired yet.
+fn unexpired_value_filter() -> RowFilter {
+    let unexpired = TimestampRange {
+        start_timestamp_micros: now_micros(),
+        end_timestamp_micros: 0,
+    };
+    RowFilter {
+        filter: Some(Filter::Chain(Chain {
+            filters: vec![
+                RowFilter {
+                    filter: Some(Filter::FamilyNameRegexFilter(
+                        GEOCODE_CSV_FAMILY_NAME.to_owned(),
+                    )),
+                },
+                RowFilter {
+                    filter: Some(Filter::Interleave(Interleave {
+                        filters: vec![
+                            latest_cell_filter(GEOCODE_CSV_COLUMN_NAME, None),
+                            latest_cell_filter(
+                                GEOCODE_CSV_EXPIRING_COLUMN_NAME,
+                                Some(unexpired),
+                            ),
+                        ],
+                    })),
+                },
+            ],
+        })),
+    }
+}
+
+/// Given the cells returned for a row using [`unexpired_value_filter`], choose
+/// the value of that row.
+fn row_value(row_cells: Vec<RowCell>) -> Result<Option<Vec<u8>>> {
+    let mut value = None;
+    for row_cell in row_cells {
+        trace!(
+            "bigtable row found: [{:?}:{:?}]={:?} @ {}",
+            row_cell.family_name,
+            String::from_utf8_lossy(&row_cell.qualifier),
+            row_cell.value,
+            row_cell.timestamp_micros,
+        );
+
+        // Check to make sure we got the right data.
+        if row_cell.family_name != GEOCODE_CSV_FAMILY_NAME {
+            return Err(format_err!(
+                "expected column family name {:?}, found {:?}",
+                GEOCODE_CSV_FAMILY_NAME,
+                row_cell.family_name,
+            ));
+        }
+        let expiring = if row_cell.qualifier == GEOCODE_CSV_COLUMN_NAME {
+            false
+        } else if row_cell.qualifier == GEOCODE_CSV_EXPIRING_COLUMN_NAME {
+            true
+        } else {
+            return Err(format_err!(
+                "expected qualifier {:?} or {:?}, found {:?}",
+                GEOCODE_CSV_COLUMN_NAME,
+                GEOCODE_CSV_EXPIRING_COLUMN_NAME,
+                row_cell.qualifier,
+            ));
+        };
+
+        // If we have both a permanent value and an unexpired value, the
+        // unexpired value was probably written with newer settings, so it
+        // wins.
+        if expiring || value.is_none() {
+            value = Some(row_cell.value);
+        }
+    }
+    Ok(value)
+}
+
 /// BigTable configuration information.
 struct BigTableConfig {
     project_id: String,
@@ -99,6 +218,7 @@ impl BigTable {
     }
 }
 
+#[async_trait]
 impl KeyValueStore for BigTable {
     fn new_pipelined_get<'store>(
         &'store self,
@@ -118,6 +238,84 @@ impl KeyValueStore for BigTable {
         })
     }
 
+    #[instrument(name = "BigTable::scan", level = "trace", skip_all)]
+    async fn scan(
+        &self,
+        prefix: &str,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        // Our cursor is the last key we returned. Like `add_get`, we don't use
+        // our key prefix.
+        let start_key = match cursor {
+            Some(cursor) => StartKey::StartKeyOpen(cursor.into_bytes()),
+            None => StartKey::StartKeyClosed(prefix.as_bytes().to_vec()),
+        };
+        let end_key =
+            prefix_end(prefix).map(|end| EndKey::EndKeyOpen(end.into_bytes()));
+        let mut client = self.client();
+        let request = ReadRowsRequest {
+            table_name: client.get_full_table_name(&self.table_name),
+            rows: Some(RowSet {
+                row_keys: vec![],
+                row_ranges: vec![RowRange {
+                    start_key: Some(start_key),
+                    end_key,
+                }],
+            }),
+            filter: Some(unexpired_value_filter()),
+            rows_limit: i64::try_from(limit).unwrap_or(i64::MAX),
+            ..ReadRowsRequest::default()
+        };
+        let response = client
+            .read_rows(request)
+            .await
+            .context("error scanning BigTable")?;
+
+        let full_page = response.len() >= limit;
+        let mut last_key = None;
+        let mut entries = Vec::with_capacity(response.len());
+        for (key, row_cells) in response {
+            let key = String::from_utf8(key).context("non-UTF-8 BigTable row key")?;
+            if let Some(value) = row_value(row_cells)? {
+                entries.push((key.clone(), value));
+            }
+            last_key = Some(key);
+        }
+        Ok(ScanPage {
+            entries,
+            cursor: last_key.filter(|_| full_page),
+        })
+    }
+
+    #[instrument(name = "BigTable::delete", level = "trace", skip_all)]
+    async fn delete(&self, keys: Vec<String>) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let mut client = self.client();
+        let request = MutateRowsRequest {
+            table_name: client.get_full_table_name(&self.table_name),
+            app_profile_id: "".to_owned(),
+            entries: keys
+                .into_iter()
+                .map(|key| Entry {
+                    row_key: key.into_bytes(),
+                    mutations: vec![Mutation {
+                        mutation: Some(mutation::Mutation::DeleteFromRow(
+                            DeleteFromRow {},
+                        )),
+                    }],
+                })
+                .collect(),
+        };
+        client
+            .mutate_rows(request)
+            .await
+            .context("error deleting cached values from BigTable")?;
+        Ok(())
+    }
+
     fn key_prefix(&self) -> &str {
         &self.key_prefix
     }
@@ -127,7 +325,7 @@ impl KeyValueStore for BigTable {
 impl KeyValueStoreNew for BigTable {
     /// Create a new key/value store client.
     #[instrument(level = "debug", skip_all)]
-    async fn new(url: Url, key_prefix: String) -> Result<Self> {
+    async fn new(url: Url, key_prefix: String, pool_size: usize) -> Result<Self> {
         describe_histogram!(
             "geocodecsv.bigtable.get_request.duration_seconds",
             Unit::Seconds,
@@ -144,7 +342,7 @@ impl KeyValueStoreNew for BigTable {
             &config.project_id,
             &config.instance_id,
             /* read_only */ false,
-            CONCURRENCY,
+            pool_size,
             Some(Duration::from_secs(60)),
         )
         .await
@@ -184,25 +382,7 @@ impl<'store> PipelinedGet<'store> for BigTablePipelinedGet<'store> {
                 row_keys: self.row_keys.to_owned(),
                 row_ranges: vec![],
             }),
-            filter: Some(RowFilter {
-                filter: Some(Filter::Chain(Chain {
-                    filters: vec![
-                        RowFilter {
-                            filter: Some(Filter::FamilyNameRegexFilter(
-                                GEOCODE_CSV_FAMILY_NAME.to_owned(),
-                            )),
-                        },
-                        RowFilter {
-                            filter: Some(Filter::ColumnQualifierRegexFilter(
-                                GEOCODE_CSV_COLUMN_NAME.to_vec(),
-                            )),
-                        },
-                        RowFilter {
-                            filter: Some(Filter::CellsPerColumnLimitFilter(1)),
-                        },
-                    ],
-                })),
-            }),
+            filter: Some(unexpired_value_filter()),
             ..ReadRowsRequest::default()
         };
         trace!("bigtable request: {:?}", request);
@@ -246,38 +426,13 @@ impl<'store> PipelinedGet<'store> for BigTablePipelinedGet<'store> {
         }
 
         // Extract the found keys and store them in our result.
-        for (key, data) in response {
-            for row_cell in data {
-                trace!(
-                    "bigtable row found: [{:?}:{:?}]={:?} @ {}",
-                    row_cell.family_name,
-                    String::from_utf8_lossy(&row_cell.qualifier),
-                    row_cell.value,
-                    row_cell.timestamp_micros,
-                );
-
-                // Check to make sure we got the right data.
-                if row_cell.family_name != GEOCODE_CSV_FAMILY_NAME {
-                    return Err(format_err!(
-                        "expected column family name {:?}, found {:?}",
-                        GEOCODE_CSV_FAMILY_NAME,
-                        row_cell.family_name,
-                    ));
-                }
-                if row_cell.qualifier != GEOCODE_CSV_COLUMN_NAME {
-                    return Err(format_err!(
-                        "expected qualifier {:?}, found {:?}",
-                        GEOCODE_CSV_COLUMN_NAME,
-                        row_cell.qualifier,
-                    ));
-                }
-
-                // Write this match to our result array.
+        for (key, row_cells) in response {
+            if let Some(value) = row_value(row_cells)? {
                 let indices = row_key_indices
                     .get(&key)
                     .expect("we should always have a known key");
                 for idx in indices {
-                    result[*idx] = Some(row_cell.value.clone());
+                    result[*idx] = Some(value.clone());
                 }
             }
         }
@@ -294,15 +449,25 @@ struct BigTablePipelinedSet<'store> {
 /// A series of "set" requests that we'll send in a single batch.
 #[async_trait]
 impl<'store> PipelinedSet<'store> for BigTablePipelinedSet<'store> {
-    fn add_set(&mut self, key: String, value: Vec<u8>) {
+    fn add_set(&mut self, key: String, value: Vec<u8>, ttl: Option<Duration>) {
         trace!("bigtable: writing {} ({} bytes)", key, value.len());
+        let (column_qualifier, timestamp_micros) = match ttl {
+            // Let the server choose the timestamp.
+            None => (GEOCODE_CSV_COLUMN_NAME, -1),
+            Some(ttl) => {
+                let ttl_millis = i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX);
+                let expires_at =
+                    now_micros().saturating_add(ttl_millis.saturating_mul(1000));
+                (GEOCODE_CSV_EXPIRING_COLUMN_NAME, expires_at)
+            }
+        };
         self.entries.push(Entry {
             row_key: key.into_bytes(),
             mutations: vec![Mutation {
                 mutation: Some(mutation::Mutation::SetCell(SetCell {
                     family_name: GEOCODE_CSV_FAMILY_NAME.to_owned(),
-                    column_qualifier: GEOCODE_CSV_COLUMN_NAME.to_owned(),
-                    timestamp_micros: -1,
+                    column_qualifier: column_qualifier.to_owned(),
+                    timestamp_micros,
                     value,
                 })),
             }],
@@ -1,5 +1,7 @@
 //! Common interface to key/value stores used for caching.
 
+use std::{sync::Arc, time::Duration};
+
 use anyhow::format_err;
 use async_trait::async_trait;
 use url::Url;
@@ -8,11 +10,13 @@ use crate::Result;
 
 mod bigtable;
 mod redis;
+mod sqlite;
 
-/// A key/value store, like Redis or BigTable.
+/// A key/value store, like Redis, BigTable or SQLite.
 ///
 /// We focus only on "pipelined" operations, where many requests are sent at
 /// once, to avoid minimize network round trips.
+#[async_trait]
 pub trait KeyValueStore: Send + Sync + 'static {
     /// Create a new "pipelined" get request.
     fn new_pipelined_get<'store>(
@@ -24,6 +28,23 @@ pub trait KeyValueStore: Send + Sync + 'static {
         &'store self,
     ) -> Box<dyn PipelinedSet<'store> + 'store>;
 
+    /// Scan for entries with keys starting with `prefix`, returning roughly
+    /// `limit` at a time. Pass `None` as `cursor` to start, and then pass the
+    /// cursor from each page to get the next one. Keys and prefixes are the
+    /// same ones we pass to [`PipelinedGet::add_get`], and expired values are
+    /// never returned.
+    ///
+    /// This is only intended for administrative tasks, and may be slow.
+    async fn scan(
+        &self,
+        prefix: &str,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> Result<ScanPage>;
+
+    /// Delete `keys`, ignoring any which don't exist.
+    async fn delete(&self, keys: Vec<String>) -> Result<()>;
+
     /// Get a prefix to use for all our keys. This should be the `key_prefix`
     /// parameter passed to `KeyValueStore::new_from_url`.
     fn key_prefix(&self) -> &str;
@@ -35,16 +56,23 @@ pub trait KeyValueStore: Send + Sync + 'static {
 }
 
 impl dyn KeyValueStore {
-    /// Create an appropriate `KeyValueStore` instance based on `url`.
+    /// Create an appropriate `KeyValueStore` instance based on `url`, with
+    /// room for `pool_size` concurrent requests.
     pub async fn new_from_url(
         url: Url,
         key_prefix: String,
+        pool_size: usize,
     ) -> Result<Box<dyn KeyValueStore>> {
         match url.scheme() {
-            "redis" => Ok(Box::new(redis::Redis::new(url, key_prefix).await?)),
-            "bigtable" => {
-                Ok(Box::new(bigtable::BigTable::new(url, key_prefix).await?))
-            }
+            "redis" => Ok(Box::new(
+                redis::Redis::new(url, key_prefix, pool_size).await?,
+            )),
+            "bigtable" => Ok(Box::new(
+                bigtable::BigTable::new(url, key_prefix, pool_size).await?,
+            )),
+            "sqlite" => Ok(Box::new(
+                sqlite::Sqlite::new(url, key_prefix, pool_size).await?,
+            )),
             scheme => {
                 Err(format_err!("don't know how to connect to {}: URLs", scheme))
             }
@@ -52,6 +80,75 @@ impl dyn KeyValueStore {
     }
 }
 
+/// Shared stores, so that several caches can use the same connection pool.
+#[async_trait]
+impl KeyValueStore for Arc<dyn KeyValueStore> {
+    fn new_pipelined_get<'store>(
+        &'store self,
+    ) -> Box<dyn PipelinedGet<'store> + 'store> {
+        self.as_ref().new_pipelined_get()
+    }
+
+    fn new_pipelined_set<'store>(
+        &'store self,
+    ) -> Box<dyn PipelinedSet<'store> + 'store> {
+        self.as_ref().new_pipelined_set()
+    }
+
+    async fn scan(
+        &self,
+        prefix: &str,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        self.as_ref().scan(prefix, cursor, limit).await
+    }
+
+    async fn delete(&self, keys: Vec<String>) -> Result<()> {
+        self.as_ref().delete(keys).await
+    }
+
+    fn key_prefix(&self) -> &str {
+        self.as_ref().key_prefix()
+    }
+}
+
+/// A page of entries returned by [`KeyValueStore::scan`].
+#[derive(Debug, Default)]
+pub struct ScanPage {
+    /// The keys and values we found.
+    pub entries: Vec<(String, Vec<u8>)>,
+
+    /// Pass this to `scan` to get the next page, or `None` if we're done.
+    /// Pages may be empty even when there are more entries to come.
+    pub cursor: Option<String>,
+}
+
+/// Find the smallest string greater than every string starting with
+/// `prefix`, or `None` if there isn't one. All our stores compare keys as
+/// bytes, and UTF-8 sorts by code point, so we can use this as the end of a
+/// key range.
+fn prefix_end(prefix: &str) -> Option<String> {
+    let mut end = prefix.to_owned();
+    while let Some(last) = end.pop() {
+        let next =
+            (u32::from(last) + 1..=u32::from(char::MAX)).find_map(char::from_u32);
+        if let Some(next) = next {
+            end.push(next);
+            return Some(end);
+        }
+    }
+    None
+}
+
+#[test]
+fn prefix_end_is_after_all_prefixed_keys() {
+    assert_eq!(prefix_end("gcsv:sm:"), Some("gcsv:sm;".to_owned()));
+    assert_eq!(prefix_end("a\u{d7ff}"), Some("a\u{e000}".to_owned()));
+    assert_eq!(prefix_end("a\u{10ffff}"), Some("b".to_owned()));
+    assert_eq!(prefix_end(""), None);
+}
+
 /// An interface for creating a `KeyValueStore`.
 ///
 /// This can't be part of `KeyValueStore` because we can't have static methods
@@ -59,8 +156,9 @@ impl dyn KeyValueStore {
 /// traits](https://doc.rust-lang.org/book/ch17-02-trait-objects.html)).
 #[async_trait]
 pub trait KeyValueStoreNew: KeyValueStore + Sized {
-    /// Create a new key/value store client.
-    async fn new(url: Url, key_prefix: String) -> Result<Self>;
+    /// Create a new key/value store client, with a connection pool large
+    /// enough for `pool_size` concurrent requests.
+    async fn new(url: Url, key_prefix: String, pool_size: usize) -> Result<Self>;
 }
 
 /// A series of "get" requests that we'll send in a single batch.
@@ -77,8 +175,9 @@ pub trait PipelinedGet<'store>: Send + Sync {
 /// A series of "set" requests that we'll send in a single batch.
 #[async_trait]
 pub trait PipelinedSet<'store>: Send + Sync {
-    /// Add a "set" request to our pipeline.
-    fn add_set(&mut self, key: String, value: Vec<u8>);
+    /// Add a "set" request to our pipeline. If `ttl` is specified, the value
+    /// should expire after that long. Otherwise, it should never expire.
+    fn add_set(&mut self, key: String, value: Vec<u8>, ttl: Option<Duration>);
 
     /// Execute all our requests.
     async fn execute(&self) -> Result<()>;
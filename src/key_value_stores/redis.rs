@@ -1,19 +1,23 @@
 //! A simple Redis client.
 
-use std::time::Instant;
+use std::{
+    cmp::max,
+    convert::TryFrom,
+    time::{Duration, Instant},
+};
 
 use anyhow::Context;
 use async_trait::async_trait;
 use bb8::{Pool, PooledConnection};
 use bb8_redis::RedisConnectionManager;
 use metrics::{describe_histogram, histogram, Unit};
-use redis::{pipe, Pipeline};
+use redis::{cmd, pipe, Pipeline};
 use tracing::instrument;
 use url::Url;
 
 use crate::Result;
 
-use super::{KeyValueStore, KeyValueStoreNew, PipelinedGet, PipelinedSet};
+use super::{KeyValueStore, KeyValueStoreNew, PipelinedGet, PipelinedSet, ScanPage};
 
 /// A simple Redis client.
 pub struct Redis {
@@ -31,6 +35,7 @@ impl Redis {
     }
 }
 
+#[async_trait]
 impl KeyValueStore for Redis {
     fn new_pipelined_get<'store>(
         &'store self,
@@ -50,6 +55,71 @@ impl KeyValueStore for Redis {
         })
     }
 
+    #[instrument(name = "Redis::scan", level = "trace", skip_all)]
+    async fn scan(
+        &self,
+        prefix: &str,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        let mut pattern = String::with_capacity(self.key_prefix.len() + prefix.len());
+        for c in self.key_prefix.chars().chain(prefix.chars()) {
+            if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+                pattern.push('\\');
+            }
+            pattern.push(c);
+        }
+        pattern.push('*');
+
+        // Redis cursors are numbers, and 0 means we're done.
+        let mut client = self.client().await?;
+        let (next_cursor, keys): (String, Vec<String>) = cmd("SCAN")
+            .arg(cursor.as_deref().unwrap_or("0"))
+            .arg("MATCH")
+            .arg(pattern)
+            .arg("COUNT")
+            .arg(limit)
+            .query_async(&mut *client)
+            .await
+            .context("could not scan Redis")?;
+
+        // Fetch our values, skipping any keys which were deleted or expired
+        // in the meantime.
+        let mut entries = Vec::with_capacity(keys.len());
+        if !keys.is_empty() {
+            let values: Vec<Option<Vec<u8>>> = cmd("MGET")
+                .arg(&keys)
+                .query_async(&mut *client)
+                .await
+                .context("could not fetch keys from Redis")?;
+            for (key, value) in keys.into_iter().zip(values) {
+                if let Some(value) = value {
+                    entries.push((key[self.key_prefix.len()..].to_owned(), value));
+                }
+            }
+        }
+        Ok(ScanPage {
+            entries,
+            cursor: Some(next_cursor).filter(|cursor| cursor != "0"),
+        })
+    }
+
+    #[instrument(name = "Redis::delete", level = "trace", skip_all)]
+    async fn delete(&self, mut keys: Vec<String>) -> Result<()> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        for key in &mut keys {
+            self.prefix_key(key);
+        }
+        let mut client = self.client().await?;
+        cmd("DEL")
+            .arg(keys)
+            .query_async(&mut *client)
+            .await
+            .context("could not delete keys from Redis")
+    }
+
     fn key_prefix(&self) -> &str {
         &self.key_prefix
     }
@@ -58,7 +128,7 @@ impl KeyValueStore for Redis {
 #[async_trait]
 impl KeyValueStoreNew for Redis {
     #[instrument(name = "Redis::new", level = "trace", skip_all)]
-    async fn new(url: Url, key_prefix: String) -> Result<Self> {
+    async fn new(url: Url, key_prefix: String, pool_size: usize) -> Result<Self> {
         describe_histogram!(
             "geocodecsv.redis.get_request.duration_seconds",
             Unit::Seconds,
@@ -72,7 +142,9 @@ impl KeyValueStoreNew for Redis {
 
         let manager = RedisConnectionManager::new(url)
             .context("could not create Redis connection manager")?;
+        let pool_size = u32::try_from(pool_size).context("Redis pool too large")?;
         let pool = Pool::builder()
+            .max_size(pool_size)
             .build(manager)
             .await
             .context("could not create Redis connection pool")?;
@@ -121,9 +193,15 @@ struct RedisPipelinedSet<'store> {
 
 #[async_trait]
 impl<'store> PipelinedSet<'store> for RedisPipelinedSet<'store> {
-    fn add_set(&mut self, mut key: String, value: Vec<u8>) {
+    fn add_set(&mut self, mut key: String, value: Vec<u8>, ttl: Option<Duration>) {
         self.redis.prefix_key(&mut key);
-        self.pipeline.cmd("SET").arg(key).arg(value).ignore();
+        let cmd = self.pipeline.cmd("SET").arg(key).arg(value);
+        if let Some(ttl) = ttl {
+            // Redis rejects an expiry time of 0.
+            let millis = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+            cmd.arg("PX").arg(max(millis, 1));
+        }
+        cmd.ignore();
     }
 
     #[instrument(name = "PipelinedSet::execute", level = "trace", skip_all)]
@@ -0,0 +1,473 @@
+//! A local SQLite cache, for single-machine runs without Redis or BigTable.
+
+use std::{
+    convert::TryFrom,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{format_err, Context};
+use async_trait::async_trait;
+use metrics::{describe_histogram, histogram, Unit};
+use rusqlite::{params, Connection, OptionalExtension};
+use tokio::task::spawn_blocking;
+use tracing::instrument;
+use url::Url;
+
+use crate::Result;
+
+use super::{
+    prefix_end, KeyValueStore, KeyValueStoreNew, PipelinedGet, PipelinedSet, ScanPage,
+};
+
+/// How long should we wait for other processes to release a lock on our
+/// database?
+const BUSY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often should we delete expired entries while we're writing?
+const PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The current time, in milliseconds since the Unix epoch.
+fn now_millis() -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    i64::try_from(now.as_millis()).unwrap_or(i64::MAX)
+}
+
+/// Delete all expired entries. We already ignore these when reading, but
+/// without this, a long-lived cache would grow forever.
+fn purge_expired(connection: &Connection) -> Result<()> {
+    connection
+        .execute(
+            "DELETE FROM cache WHERE expires_at IS NOT NULL AND expires_at <= ?1",
+            params![now_millis()],
+        )
+        .context("could not delete expired SQLite cache entries")?;
+    Ok(())
+}
+
+/// A key/value store in a local SQLite database.
+///
+/// SQLite only allows one writer at a time, so we use a single connection,
+/// and run each pipeline as a single transaction on a blocking thread.
+pub struct Sqlite {
+    /// Our database connection.
+    connection: Arc<Mutex<Connection>>,
+
+    /// The prefix to use for our keys.
+    key_prefix: String,
+
+    /// When we last deleted expired entries.
+    last_purged: Mutex<Instant>,
+}
+
+impl Sqlite {
+    /// Run `f` with our connection on a thread where it's OK to block.
+    async fn with_connection<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        spawn_blocking(move || {
+            let mut connection = connection
+                .lock()
+                .map_err(|_| format_err!("SQLite connection lock poisoned"))?;
+            f(&mut connection)
+        })
+        .await
+        .context("SQLite thread panicked")?
+    }
+}
+
+#[async_trait]
+impl KeyValueStore for Sqlite {
+    fn new_pipelined_get<'store>(
+        &'store self,
+    ) -> Box<dyn super::PipelinedGet<'store> + 'store> {
+        Box::new(SqlitePipelinedGet {
+            sqlite: self,
+            keys: vec![],
+        })
+    }
+
+    fn new_pipelined_set<'store>(
+        &'store self,
+    ) -> Box<dyn super::PipelinedSet<'store> + 'store> {
+        Box::new(SqlitePipelinedSet {
+            sqlite: self,
+            entries: vec![],
+        })
+    }
+
+    #[instrument(name = "Sqlite::scan", level = "trace", skip_all)]
+    async fn scan(
+        &self,
+        prefix: &str,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> Result<ScanPage> {
+        // Our cursor is the last key we returned, with our key prefix.
+        let mut start = prefix.to_owned();
+        self.prefix_key(&mut start);
+        let end = prefix_end(&start);
+        let key_prefix_len = self.key_prefix.len();
+        let limit = i64::try_from(limit).unwrap_or(i64::MAX);
+        self.with_connection(move |connection| {
+            let mut stmt = connection.prepare_cached(
+                "SELECT key, value FROM cache
+                WHERE key >= ?1 AND (?2 IS NULL OR key < ?2) AND (?3 IS NULL OR key > ?3)
+                    AND (expires_at IS NULL OR expires_at > ?4)
+                ORDER BY key
+                LIMIT ?5",
+            )?;
+            let entries = stmt
+                .query_map(params![start, end, cursor, now_millis(), limit], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get(1)?))
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let cursor = if entries.len() as i64 == limit {
+                entries.last().map(|(key, _)| key.clone())
+            } else {
+                None
+            };
+            Ok(ScanPage {
+                entries: entries
+                    .into_iter()
+                    .map(|(key, value)| (key[key_prefix_len..].to_owned(), value))
+                    .collect(),
+                cursor,
+            })
+        })
+        .await
+        .context("could not scan SQLite")
+    }
+
+    #[instrument(name = "Sqlite::delete", level = "trace", skip_all)]
+    async fn delete(&self, mut keys: Vec<String>) -> Result<()> {
+        for key in &mut keys {
+            self.prefix_key(key);
+        }
+        self.with_connection(move |connection| {
+            let tx = connection.transaction()?;
+            {
+                let mut stmt =
+                    tx.prepare_cached("DELETE FROM cache WHERE key = ?1")?;
+                for key in &keys {
+                    stmt.execute(params![key])?;
+                }
+            }
+            tx.commit()?;
+            Ok(())
+        })
+        .await
+        .context("could not delete keys from SQLite")
+    }
+
+    fn key_prefix(&self) -> &str {
+        &self.key_prefix
+    }
+}
+
+#[async_trait]
+impl KeyValueStoreNew for Sqlite {
+    /// Open the database at `url`, which may be either `sqlite:///abs/path.db`
+    /// or `sqlite:relative/path.db`. We only use one connection, so
+    /// `pool_size` is ignored.
+    #[instrument(name = "Sqlite::new", level = "trace", skip_all)]
+    async fn new(url: Url, key_prefix: String, _pool_size: usize) -> Result<Self> {
+        describe_histogram!(
+            "geocodecsv.sqlite.get_request.duration_seconds",
+            Unit::Seconds,
+            "Time required for SQLite GET requests"
+        );
+        describe_histogram!(
+            "geocodecsv.sqlite.set_request.duration_seconds",
+            Unit::Seconds,
+            "Time required for SQLite SET requests"
+        );
+
+        let path = url
+            .to_file_path()
+            .unwrap_or_else(|()| PathBuf::from(url.path()));
+        if path.as_os_str().is_empty() {
+            return Err(format_err!("no database path in {}", url));
+        }
+        let connection = spawn_blocking(move || -> Result<Connection> {
+            let connection = Connection::open(&path).with_context(|| {
+                format!("could not open SQLite cache {}", path.display())
+            })?;
+            connection.busy_timeout(BUSY_TIMEOUT)?;
+            // Write-ahead logging allows other processes to read while we
+            // write, and makes each commit much cheaper.
+            connection
+                .pragma_update(None, "journal_mode", "WAL")
+                .context("could not enable SQLite write-ahead logging")?;
+            connection.pragma_update(None, "synchronous", "NORMAL")?;
+            connection
+                .execute(
+                    "CREATE TABLE IF NOT EXISTS cache (
+                        key TEXT PRIMARY KEY NOT NULL,
+                        value BLOB NOT NULL,
+                        expires_at INTEGER
+                    ) WITHOUT ROWID",
+                    [],
+                )
+                .context("could not create SQLite cache table")?;
+            connection
+                .execute(
+                    "CREATE INDEX IF NOT EXISTS cache_expires_at
+                    ON cache (expires_at) WHERE expires_at IS NOT NULL",
+                    [],
+                )
+                .context("could not create SQLite cache index")?;
+            purge_expired(&connection)?;
+            Ok(connection)
+        })
+        .await
+        .context("SQLite thread panicked")??;
+        Ok(Sqlite {
+            connection: Arc::new(Mutex::new(connection)),
+            key_prefix,
+            last_purged: Mutex::new(Instant::now()),
+        })
+    }
+}
+
+/// A batch of GET operations.
+struct SqlitePipelinedGet<'store> {
+    sqlite: &'store Sqlite,
+    keys: Vec<String>,
+}
+
+#[async_trait]
+impl<'store> PipelinedGet<'store> for SqlitePipelinedGet<'store> {
+    fn add_get(&mut self, mut key: String) {
+        self.sqlite.prefix_key(&mut key);
+        self.keys.push(key);
+    }
+
+    #[instrument(name = "PipelinedGet::execute", level = "trace", skip_all)]
+    async fn execute(&self) -> Result<Vec<Option<Vec<u8>>>> {
+        let start = Instant::now();
+
+        let keys = self.keys.clone();
+        let result = self
+            .sqlite
+            .with_connection(move |connection| {
+                let now = now_millis();
+                let tx = connection.transaction()?;
+                let mut values = Vec::with_capacity(keys.len());
+                {
+                    // `expires_at` is in milliseconds since the epoch, or NULL
+                    // if the value never expires.
+                    let mut stmt = tx.prepare_cached(
+                        "SELECT value FROM cache
+                        WHERE key = ?1 AND (expires_at IS NULL OR expires_at > ?2)",
+                    )?;
+                    for key in &keys {
+                        values.push(
+                            stmt.query_row(params![key, now], |row| row.get(0))
+                                .optional()?,
+                        );
+                    }
+                }
+                tx.commit()?;
+                Ok(values)
+            })
+            .await
+            .context("could not fetch keys from SQLite")?;
+
+        histogram!(
+            "geocodecsv.sqlite.get_request.duration_seconds",
+            (Instant::now() - start).as_secs_f64(),
+        );
+
+        Ok(result)
+    }
+}
+
+/// A batch of SET operations.
+struct SqlitePipelinedSet<'store> {
+    sqlite: &'store Sqlite,
+    entries: Vec<(String, Vec<u8>, Option<Duration>)>,
+}
+
+#[async_trait]
+impl<'store> PipelinedSet<'store> for SqlitePipelinedSet<'store> {
+    fn add_set(&mut self, mut key: String, value: Vec<u8>, ttl: Option<Duration>) {
+        self.sqlite.prefix_key(&mut key);
+        self.entries.push((key, value, ttl));
+    }
+
+    #[instrument(name = "PipelinedSet::execute", level = "trace", skip_all)]
+    async fn execute(&self) -> Result<()> {
+        let start = Instant::now();
+
+        // If we've been running for a while, delete expired entries along with
+        // this batch.
+        let purge = {
+            let mut last_purged =
+                self.sqlite.last_purged.lock().expect("lock poisoned");
+            let purge = last_purged.elapsed() >= PURGE_INTERVAL;
+            if purge {
+                *last_purged = Instant::now();
+            }
+            purge
+        };
+
+        let entries = self.entries.clone();
+        self.sqlite
+            .with_connection(move |connection| {
+                if purge {
+                    purge_expired(connection)?;
+                }
+                let now = now_millis();
+                let tx = connection.transaction()?;
+                {
+                    let mut stmt = tx.prepare_cached(
+                        "INSERT OR REPLACE INTO cache (key, value, expires_at)
+                        VALUES (?1, ?2, ?3)",
+                    )?;
+                    for (key, value, ttl) in &entries {
+                        let expires_at = ttl.map(|ttl| {
+                            let ttl =
+                                i64::try_from(ttl.as_millis()).unwrap_or(i64::MAX);
+                            now.saturating_add(ttl)
+                        });
+                        stmt.execute(params![key, value, expires_at])?;
+                    }
+                }
+                tx.commit()?;
+                Ok(())
+            })
+            .await
+            .context("could not store keys in SQLite")?;
+
+        histogram!(
+            "geocodecsv.sqlite.set_request.duration_seconds",
+            (Instant::now() - start).as_secs_f64(),
+        );
+
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn get_and_set() {
+    let dir = std::env::temp_dir()
+        .join(format!("geocode-csv-sqlite-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("cache.db");
+    let url = format!("sqlite://{}", path.display()).parse().unwrap();
+    let store = Sqlite::new(url, "test:".to_owned(), 1).await.unwrap();
+
+    let mut set = store.new_pipelined_set();
+    set.add_set("a".to_owned(), b"1".to_vec(), None);
+    set.add_set(
+        "b".to_owned(),
+        b"2".to_vec(),
+        Some(Duration::from_secs(3600)),
+    );
+    set.add_set("expired".to_owned(), b"3".to_vec(), Some(Duration::ZERO));
+    set.execute().await.unwrap();
+
+    let mut get = store.new_pipelined_get();
+    get.add_get("b".to_owned());
+    get.add_get("missing".to_owned());
+    get.add_get("a".to_owned());
+    get.add_get("expired".to_owned());
+    assert_eq!(
+        get.execute().await.unwrap(),
+        vec![Some(b"2".to_vec()), None, Some(b"1".to_vec()), None],
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn scan_and_delete() {
+    let dir = std::env::temp_dir()
+        .join(format!("geocode-csv-sqlite-scan-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("cache.db");
+    let url = format!("sqlite://{}", path.display()).parse().unwrap();
+    let store = Sqlite::new(url, "test:".to_owned(), 1).await.unwrap();
+
+    let mut set = store.new_pipelined_set();
+    for key in ["a:1", "a:2", "a:3", "ab", "b:1"] {
+        set.add_set(key.to_owned(), key.as_bytes().to_vec(), None);
+    }
+    set.add_set("a:expired".to_owned(), b"x".to_vec(), Some(Duration::ZERO));
+    set.execute().await.unwrap();
+
+    // Scan a page at a time.
+    let mut keys = vec![];
+    let mut cursor = None;
+    loop {
+        let page = store.scan("a:", cursor, 2).await.unwrap();
+        for (key, value) in page.entries {
+            assert_eq!(key.as_bytes(), value);
+            keys.push(key);
+        }
+        cursor = page.cursor;
+        if cursor.is_none() {
+            break;
+        }
+    }
+    assert_eq!(keys, &["a:1", "a:2", "a:3"]);
+
+    // Delete some keys, including one which doesn't exist.
+    store
+        .delete(vec!["a:1".to_owned(), "a:3".to_owned(), "zzz".to_owned()])
+        .await
+        .unwrap();
+    let page = store.scan("", None, 100).await.unwrap();
+    let keys = page.entries.into_iter().map(|(k, _)| k).collect::<Vec<_>>();
+    assert_eq!(keys, &["a:2", "ab", "b:1"]);
+    assert!(page.cursor.is_none());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn purge_expired_on_open() {
+    let dir = std::env::temp_dir()
+        .join(format!("geocode-csv-sqlite-purge-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("cache.db");
+    let url: Url = format!("sqlite://{}", path.display()).parse().unwrap();
+    let store = Sqlite::new(url.clone(), "test:".to_owned(), 1)
+        .await
+        .unwrap();
+
+    let mut set = store.new_pipelined_set();
+    set.add_set("a".to_owned(), b"1".to_vec(), None);
+    set.add_set(
+        "b".to_owned(),
+        b"2".to_vec(),
+        Some(Duration::from_secs(3600)),
+    );
+    set.add_set("expired".to_owned(), b"3".to_vec(), Some(Duration::ZERO));
+    set.execute().await.unwrap();
+    drop(set);
+    drop(store);
+
+    // Reopening our cache deletes the expired entry.
+    let store = Sqlite::new(url, "test:".to_owned(), 1).await.unwrap();
+    let count = store
+        .with_connection(|connection| {
+            Ok(
+                connection.query_row("SELECT COUNT(*) FROM cache", [], |row| {
+                    row.get::<_, i64>(0)
+                })?,
+            )
+        })
+        .await
+        .unwrap();
+    assert_eq!(count, 2);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
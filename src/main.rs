@@ -4,16 +4,15 @@
 #![recursion_limit = "128"]
 
 pub use anyhow::Result;
-use anyhow::{format_err, Error};
+use anyhow::{format_err, Context, Error};
 use clap::{Parser, Subcommand, ValueEnum};
-use leaky_bucket::RateLimiter;
 use metrics::describe_counter;
 use opinionated_metrics::Mode;
-use std::cmp::max;
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tracing::{debug, info_span, warn};
 use tracing_subscriber::{
     fmt::{format::FmtSpan, Subscriber},
@@ -24,23 +23,53 @@ use url::Url;
 
 mod addresses;
 mod async_util;
+mod checkpoint;
+mod concurrency;
 mod errors;
+mod formats;
 mod geocoders;
+mod io_util;
 mod key_value_stores;
 #[cfg(debug_assertions)]
 mod memory_used;
 mod pipeline;
+mod progress;
+mod rate_limiter;
+mod retry;
 mod server;
+mod shutdown;
+mod summary;
 mod unpack_vec;
 
+use crate::checkpoint::{fingerprint, Checkpointer};
+use crate::formats::{
+    bad_rows::{BadRows, OnBadRow},
+    csv::{headerless_column_name, CsvChar, CsvDialect},
+    rejects::Rejects,
+    Format,
+};
 use crate::geocoders::{
-    cache::Cache, invalid_record_skipper::InvalidRecordSkipper, libpostal::LibPostal,
-    normalizer::Normalizer, shared_http_client, smarty::Smarty, Geocoder,
-    MatchStrategy,
+    cache::{admin as cache_admin, Cache, CacheCompression, CacheTtls},
+    cascade::Cascade,
+    invalid_record_skipper::InvalidRecordSkipper,
+    libpostal::LibPostal,
+    memory_cache::MemoryCache,
+    normalizer::{AddressNormalizer, Normalizer},
+    shared_http_client, smarty,
+    smarty::structure::Structure,
+    smarty::Smarty,
+    Geocoder, MatchStrategy, SharedHttpClient,
 };
+use crate::io_util::{Compression, Encoding, Location};
 use crate::key_value_stores::KeyValueStore;
-use crate::pipeline::{geocode_stdio, OnDuplicateColumns, CONCURRENCY, GEOCODE_SIZE};
+use crate::pipeline::{
+    geocode_stdio, ColumnOptions, OnDuplicateColumns, OnError, PipelineOptions,
+};
+use crate::progress::{ProgressMode, ProgressReporter};
+use crate::rate_limiter::AdaptiveRateLimiter;
+use crate::retry::RetryPolicy;
 use crate::server::run_server;
+use crate::summary::SUMMARY;
 use crate::{addresses::AddressColumnSpec, geocoders::paired::Paired};
 
 #[cfg(all(feature = "jemallocator", not(target_env = "msvc")))]
@@ -54,6 +83,10 @@ enum GeocoderName {
     Smarty,
     #[value(name = "libpostal")]
     LibPostal,
+    /// Look up Smarty results in `--cache`, without calling Smarty. Only
+    /// allowed in a cascade.
+    #[value(name = "cache")]
+    Cache,
 }
 
 impl FromStr for GeocoderName {
@@ -63,11 +96,23 @@ impl FromStr for GeocoderName {
         match s {
             "smarty" => Ok(GeocoderName::Smarty),
             "libpostal" => Ok(GeocoderName::LibPostal),
+            "cache" => Ok(GeocoderName::Cache),
             _ => Err(format_err!("unknown geocoder {:?}", s)),
         }
     }
 }
 
+impl GeocoderName {
+    /// The name of this geocoder, as used on the command line.
+    fn name(self) -> &'static str {
+        match self {
+            GeocoderName::Smarty => "smarty",
+            GeocoderName::LibPostal => "libpostal",
+            GeocoderName::Cache => "cache",
+        }
+    }
+}
+
 /// Key/value pairs used to annotate reported metrics. These are of the form
 /// `KEY=VALUE`. (Helper struct for argument parsing.)
 #[derive(Clone, Debug)]
@@ -93,8 +138,86 @@ impl FromStr for MetricsLabel {
 
 /// Our command-line arguments.
 #[derive(Debug, Parser)]
-#[command(author, version, about = "geocode CSV files passed on standard input")]
+#[command(
+    author,
+    version,
+    about = "geocode CSV files passed on standard input",
+    subcommand_negates_reqs = true
+)]
 struct Opt {
+    /// Read input from this file instead of standard input.
+    #[arg(long = "input", value_name = "PATH")]
+    input_path: Option<PathBuf>,
+
+    /// How the input is compressed. `auto` looks at the file extension (`.gz`
+    /// or `.zst`). [auto, none, gzip, zstd]
+    #[arg(long = "input-compression", default_value = "auto")]
+    input_compression: Compression,
+
+    /// The input file format. `auto` looks at the file extension (`.parquet`,
+    /// `.arrow`, `.jsonl`), and otherwise uses CSV. [auto, csv, parquet,
+    /// arrow, jsonl]
+    #[arg(long = "input-format", default_value = "auto")]
+    input_format: Format,
+
+    /// The text encoding of the input. [utf-8, latin1, windows-1252]
+    #[arg(long = "encoding", default_value = "utf-8")]
+    encoding: Encoding,
+
+    /// Strip any byte-order mark from the start of the input.
+    #[arg(long = "strip-bom")]
+    strip_bom: bool,
+
+    /// The field delimiter for CSV input. Use `tab` for tab-separated files.
+    #[arg(long = "delimiter", default_value = ",")]
+    delimiter: CsvChar,
+
+    /// The quote character for CSV input.
+    #[arg(long = "quote", default_value = "\"")]
+    quote: CsvChar,
+
+    /// The character used to escape quotes in CSV input, such as `\`. By
+    /// default, quotes are escaped by doubling them.
+    #[arg(long = "escape")]
+    escape: Option<CsvChar>,
+
+    /// The CSV input has no header row. The spec must refer to columns by
+    /// index, starting from 0.
+    #[arg(long = "no-headers")]
+    no_headers: bool,
+
+    /// Write output to this file instead of standard output.
+    #[arg(long = "output", value_name = "PATH")]
+    output_path: Option<PathBuf>,
+
+    /// How to compress the output. `auto` looks at the file extension (`.gz`
+    /// or `.zst`). [auto, none, gzip, zstd]
+    #[arg(long = "output-compression", default_value = "auto")]
+    output_compression: Compression,
+
+    /// The output file format. `auto` looks at the file extension
+    /// (`.parquet`, `.arrow`, `.jsonl`), and otherwise uses CSV. Standard
+    /// output uses the input format. [auto, csv, parquet, arrow, jsonl]
+    #[arg(long = "output-format", default_value = "auto")]
+    output_format: Format,
+
+    /// The field delimiter for CSV output. Use `tab` for tab-separated files.
+    #[arg(long = "output-delimiter", default_value = ",")]
+    output_delimiter: CsvChar,
+
+    /// The quote character for CSV output.
+    #[arg(long = "output-quote", default_value = "\"")]
+    output_quote: CsvChar,
+
+    /// The character used to escape quotes in CSV output, such as `\`. By
+    /// default, quotes are escaped by doubling them.
+    #[arg(long = "output-escape")]
+    output_escape: Option<CsvChar>,
+
+    /// Do not write a header row to CSV output.
+    #[arg(long = "output-no-headers")]
+    output_no_headers: bool,
+
     /// `strict` for valid postal addresses only, `range` for unknown addresses
     /// within a street's known range, `invalid` to always generate some
     /// match, and `enhanced` (Smarty-only) if you've paid for it.
@@ -106,14 +229,64 @@ struct Opt {
     #[arg(long = "duplicate-columns", default_value = "error")]
     on_duplicate_columns: OnDuplicateColumns,
 
+    /// What should we do with input rows that can't be parsed, such as rows
+    /// with the wrong number of columns or invalid UTF-8? [fail, skip,
+    /// quarantine]
+    #[arg(long = "on-bad-row", default_value = "fail")]
+    on_bad_row: OnBadRow,
+
+    /// With `--on-bad-row=quarantine`, write bad rows to this CSV file, with
+    /// their line number, error and raw text.
+    #[arg(long = "quarantine-path", value_name = "PATH")]
+    quarantine_path: Option<PathBuf>,
+
+    /// Write rows with addresses that couldn't be geocoded to this CSV file,
+    /// with the prefix that failed and the reason (`invalid_record`,
+    /// `cached_unknown`, `not_cached`, `no_match`, `rejected_by_geocoder` or
+    /// `geocoder_error`).
+    #[arg(long = "rejects-path", value_name = "PATH")]
+    rejects_path: Option<PathBuf>,
+
+    /// Periodically record how much of the input has been written in this
+    /// file, so that an interrupted run can be continued with `--resume`.
+    /// Requires `--input` and `--output`, and CSV or JSON Lines output.
+    #[arg(
+        long = "checkpoint",
+        value_name = "PATH",
+        requires_all = ["input_path", "output_path"]
+    )]
+    checkpoint_path: Option<PathBuf>,
+
+    /// How many input rows to write between checkpoints.
+    #[arg(
+        long = "checkpoint-every",
+        value_name = "ROWS",
+        default_value = "50000"
+    )]
+    checkpoint_every: u64,
+
+    /// Continue an interrupted run from the last `--checkpoint`, appending to
+    /// the existing output. The input and options must not have changed.
+    #[arg(long = "resume", requires = "checkpoint_path")]
+    resume: bool,
+
     /// A JSON file describing what columns to geocode.
-    #[arg(long = "spec")]
-    spec_path: PathBuf,
+    #[arg(long = "spec", required = true)]
+    spec_path: Option<PathBuf>,
 
     /// The geocoder to use.
     #[arg(long = "geocoder", default_value = "smarty")]
     geocoder: GeocoderName,
 
+    /// A geocoder to try for addresses that earlier geocoders couldn't match.
+    /// May be repeated. Output columns from all geocoders are merged, and a
+    /// `source` column records which geocoder matched. In a cascade, `cache`
+    /// looks up Smarty results in `--cache` without calling Smarty, and
+    /// `libpostal` only matches addresses that parse into a complete street
+    /// address.
+    #[arg(long = "fallback-geocoder", value_name = "GEOCODER")]
+    fallback_geocoders: Vec<GeocoderName>,
+
     /// What license to use. Leave blank for standard, `us-rooftop-geocoding-enterprise-cloud` for Rooftop.
     #[arg(
         long = "smarty-license",
@@ -122,8 +295,30 @@ struct Opt {
     )]
     smarty_license: String,
 
-    /// Cache geocoding results in the specified location (either redis: or
-    /// bigtable:).
+    /// A JSON file listing which Smarty fields to output, in the same format
+    /// as the built-in `complete.json`, with nested objects of `true` and
+    /// `false` values.
+    #[arg(long = "smarty-structure", value_name = "PATH")]
+    smarty_structure: Option<PathBuf>,
+
+    /// Ask Smarty for up to this many candidates per address (at most 10).
+    /// If this is more than 1, we output a numbered group of columns for
+    /// each candidate, like `gc_1_latitude` and `gc_2_latitude`, ordered from
+    /// best to worst.
+    #[arg(long = "max-candidates", value_name = "N", default_value = "1")]
+    max_candidates: usize,
+
+    /// The Smarty street address endpoint to use. Must use `https:`, except
+    /// for local test servers.
+    #[arg(
+        long = "smarty-url",
+        value_name = "URL",
+        default_value = smarty::client::DEFAULT_URL
+    )]
+    smarty_url: Url,
+
+    /// Cache geocoding results in the specified location (redis:, bigtable:,
+    /// or a local sqlite:///path/to/cache.db).
     #[arg(long = "cache", value_name = "CACHE_URL")]
     cache_url: Option<Url>,
 
@@ -139,6 +334,41 @@ struct Opt {
     #[arg(long = "cache-key-prefix", requires = "cache_url")]
     cache_key_prefix: Option<String>,
 
+    /// How to compress new cache entries. Entries in either format can always
+    /// be read, but geocode-csv 1.4.0 and earlier can only read `none`.
+    /// [zstd, none]
+    #[arg(long = "cache-compression", default_value = "zstd")]
+    cache_compression: CacheCompression,
+
+    /// Expire cached results for addresses we could geocode after this many
+    /// seconds. By default, they never expire.
+    #[arg(long = "cache-ttl", value_name = "SECS", requires = "cache_url")]
+    cache_ttl: Option<NonZeroU64>,
+
+    /// Expire cached results for addresses we couldn't geocode after this many
+    /// seconds, so that we try them again later. By default, they never
+    /// expire.
+    #[arg(
+        long = "cache-unknown-ttl",
+        value_name = "SECS",
+        requires = "cache_url"
+    )]
+    cache_unknown_ttl: Option<NonZeroU64>,
+
+    /// Don't cache addresses we couldn't geocode.
+    #[arg(
+        long = "no-cache-unknown",
+        requires = "cache_url",
+        conflicts_with = "cache_unknown_ttl"
+    )]
+    no_cache_unknown: bool,
+
+    /// Keep up to this many megabytes of recent results in memory, in front of
+    /// any `--cache`. This also makes sure that we only geocode each distinct
+    /// address once, even if it appears in several chunks at the same time.
+    #[arg(long = "memory-cache-mb", value_name = "MB")]
+    memory_cache_mb: Option<usize>,
+
     /// Before processing addresses, normalize them using libpostal.
     #[arg(long = "normalize")]
     normalize: bool,
@@ -148,16 +378,79 @@ struct Opt {
     include_libpostal: bool,
 
     /// Limit the speed with which we access external geocoding APIs. Does not
-    /// affect the cache or local geocoding.
+    /// affect the cache or local geocoding. If an API throttles us, we slow
+    /// down automatically, and then gradually speed back up.
     #[arg(long = "max-addresses-per-second")]
     max_addresses_per_second: Option<usize>,
 
-    /// How many times should we retry a failed geocoding block? Each retry
-    /// takes twice as long as the last. The current default value will result
-    /// in giving up after about 30 seconds.
+    /// The maximum number of chunks of addresses to geocode at once. We start
+    /// with fewer, and adjust based on how quickly our geocoder responds. This
+    /// also sets the size of our HTTP and cache connection pools.
+    #[arg(long = "concurrency", value_name = "N", default_value = "48")]
+    concurrency: NonZeroUsize,
+
+    /// Always geocode `--concurrency` chunks at once, instead of adjusting
+    /// based on how quickly our geocoder responds.
+    #[arg(long = "fixed-concurrency")]
+    fixed_concurrency: bool,
+
+    /// The maximum number of addresses to send to a geocoder at once. Each
+    /// input row contains one address per prefix. Smarty allows at most 100.
+    #[arg(long = "batch-size", value_name = "N", default_value = "72")]
+    batch_size: NonZeroUsize,
+
+    /// How many chunks to buffer between reading, geocoding and writing.
+    #[arg(long = "channel-depth", value_name = "N", default_value = "8")]
+    channel_depth: NonZeroUsize,
+
+    /// How many times should we retry a geocoding block that failed with a
+    /// temporary error, such as a network or server error? Each retry takes
+    /// about twice as long as the last. The current default values will result
+    /// in giving up after about 30 seconds. Errors which will never succeed,
+    /// such as bad credentials, are not retried.
     #[arg(long = "max-retries", default_value = "4")]
     max_retries: u8,
 
+    /// How many seconds to wait before the first retry.
+    #[arg(long = "retry-initial-wait", value_name = "SECS", default_value = "2")]
+    retry_initial_wait: f64,
+
+    /// The maximum number of seconds to wait between retries.
+    #[arg(long = "retry-max-wait", value_name = "SECS", default_value = "60")]
+    retry_max_wait: f64,
+
+    /// How many times should we retry a geocoding block when a remote service
+    /// asks us to slow down? These retries don't count against
+    /// `--max-retries`.
+    #[arg(long = "max-throttled-retries", default_value = "32")]
+    max_throttled_retries: u8,
+
+    /// What should we do if we still can't geocode addresses after retrying?
+    /// With `continue`, we leave their geocoding columns empty, and write a
+    /// short error code to an `error` column for each prefix. [fail, continue]
+    #[arg(long = "on-error", default_value = "fail")]
+    on_error: OnError,
+
+    /// With `--on-error=continue`, exit with an error status at the end if
+    /// more than this many addresses couldn't be geocoded because of errors.
+    #[arg(long = "max-errors", value_name = "COUNT")]
+    max_errors: Option<u64>,
+
+    /// How to report progress on standard error. `auto` draws a progress bar
+    /// if standard error is a terminal, and prints log lines otherwise.
+    /// [auto, bar, log, none]
+    #[arg(long = "progress", default_value = "auto")]
+    progress: ProgressMode,
+
+    /// How many seconds to wait between progress log lines.
+    #[arg(long = "progress-interval", value_name = "SECS", default_value = "60")]
+    progress_interval: f64,
+
+    /// Write a JSON summary of the run to this file when we finish, including
+    /// row counts, match rates and retries.
+    #[arg(long = "summary-json", value_name = "PATH")]
+    summary_json_path: Option<PathBuf>,
+
     /// Labels to attach to reported metrics. Recommended: "source=$SOURCE".
     #[arg(long = "metrics-label", value_name = "KEY=VALUE")]
     metrics_labels: Vec<MetricsLabel>,
@@ -176,6 +469,54 @@ enum Command {
         #[arg(long = "listen-address", default_value = "127.0.0.1:8787")]
         listen_address: String,
     },
+    /// Inspect or manage the cache specified by `--cache`.
+    Cache {
+        #[command(subcommand)]
+        cmd: CacheCommand,
+    },
+}
+
+/// Subcommands for managing a cache.
+#[derive(Debug, Subcommand)]
+enum CacheCommand {
+    /// Scan cache entries, and print CSV statistics for each geocoder prefix.
+    Stats {
+        /// The maximum number of entries to scan. We scan entries in key
+        /// order, so if we stop early, this isn't a random sample.
+        #[arg(long = "sample", value_name = "N", default_value = "100000")]
+        sample: usize,
+    },
+    /// Export cache entries to NDJSON, with their decoded `column_values`.
+    Export {
+        /// Only export entries for this geocoder prefix, as shown by `stats`.
+        #[arg(long = "prefix")]
+        prefix: Option<String>,
+
+        /// Write entries to this file instead of standard output.
+        #[arg(long = "output", value_name = "PATH")]
+        output_path: Option<PathBuf>,
+    },
+    /// Import cache entries from NDJSON written by `export`. Uses
+    /// `--cache-compression`, `--cache-ttl`, `--cache-unknown-ttl` and
+    /// `--no-cache-unknown`.
+    Import {
+        /// Read entries from this file instead of standard input.
+        #[arg(long = "input", value_name = "PATH")]
+        input_path: Option<PathBuf>,
+    },
+    /// Delete cache entries for a geocoder prefix.
+    Invalidate {
+        /// The geocoder prefix to delete entries for, as shown by `stats`.
+        #[arg(long = "prefix")]
+        prefix: String,
+
+        /// Only delete entries for the addresses in this CSV file, using the
+        /// columns listed in `--spec`. The file is read using the same options
+        /// as geocoding input, such as `--delimiter`, `--no-headers` and
+        /// `--encoding`, and addresses are normalized if `--normalize` is set.
+        #[arg(long = "addresses", value_name = "PATH")]
+        addresses_path: Option<PathBuf>,
+    },
 }
 
 // Our main entrypoint. We rely on the fact that `anyhow::Error` has a `Debug`
@@ -183,6 +524,8 @@ enum Command {
 // with an error.
 #[tokio::main]
 async fn main() -> Result<()> {
+    let start = Instant::now();
+
     // Configure tracing.
     let filter = EnvFilter::from_default_env();
     Subscriber::builder()
@@ -196,7 +539,6 @@ async fn main() -> Result<()> {
 
     // Parse our command-line arguments.
     let opt = Opt::parse();
-    let spec = AddressColumnSpec::from_path(&opt.spec_path)?;
 
     // Set up metrics recording.
     let mut metrics_builder = opinionated_metrics::Builder::new(Mode::Cli);
@@ -212,62 +554,101 @@ async fn main() -> Result<()> {
         "Particularly interesting errors, by component and cause"
     );
 
-    // Set up any rate limiting.
+    // Cache administration doesn't need a geocoder, so handle it first.
+    if let Some(Command::Cache { cmd }) = &opt.cmd {
+        let result = run_cache_command(cmd, &opt).await;
+        if let Err(err) = metrics_handle.report().await {
+            warn!("could not report metrics: {:?}", err);
+        }
+        return result;
+    }
+
+    // Decide how much work to do at once.
+    let pipeline_options = PipelineOptions {
+        concurrency: opt.concurrency.get(),
+        adaptive_concurrency: !opt.fixed_concurrency,
+        batch_size: opt.batch_size.get(),
+        channel_depth: opt.channel_depth.get(),
+    };
+    let uses_smarty = matches!(opt.geocoder, GeocoderName::Smarty)
+        || opt
+            .fallback_geocoders
+            .iter()
+            .any(|name| matches!(name, GeocoderName::Smarty));
+    if uses_smarty && pipeline_options.batch_size > smarty::MAX_BATCH_SIZE {
+        return Err(format_err!(
+            "--batch-size must be at most {} when using Smarty",
+            smarty::MAX_BATCH_SIZE,
+        ));
+    }
+
+    // Set up rate limiting. Even if we have no limit, we still slow down if a
+    // remote geocoder throttles us.
     //
     // TODO: If this is low enough, consider reducing our internal parallelism?
-    let rate_limiter = opt.max_addresses_per_second.map(|limit| {
-        // Always allow geocoding at least one full `GEOCODODE_SIZE`
-        // chunk (eventually). We want to make sure that we can
-        // accumulate enough tokens to geocode a chunk or two, to
-        // prevent a situation where we have a chunk waiting that
-        // exceeds our bucket size, blocking it from ever being
-        // geocoded.
-        let max = max(limit, GEOCODE_SIZE);
-        Arc::new(
-            RateLimiter::builder()
-                .initial(max)
-                // The docs recommend twice our refill rate or our
-                // initial value, whichever is larger.
-                .max(2 * max)
-                .refill(limit)
-                .interval(Duration::from_secs(1))
-                // Since this is all the same geocoding job, don't worry about
-                // fair scheduling between different worker tasks.
-                .fair(false)
-                .build(),
-        )
-    });
-
-    // Choose our main geocoding client.
-    let mut geocoder: Box<dyn Geocoder> = match opt.geocoder {
-        GeocoderName::Smarty => Box::new(Smarty::new(
-            opt.match_strategy,
-            opt.smarty_license.clone(),
-            rate_limiter.clone(),
-            shared_http_client(CONCURRENCY),
-        )?),
-        GeocoderName::LibPostal => Box::new(LibPostal::new()),
+    let rate_limiter = Arc::new(AdaptiveRateLimiter::new(
+        opt.max_addresses_per_second,
+        pipeline_options.batch_size,
+    ));
+
+    // Decide how to retry failed requests.
+    let retry_policy = RetryPolicy {
+        max_retries: opt.max_retries,
+        max_throttled_retries: opt.max_throttled_retries,
+        initial_wait: Duration::try_from_secs_f64(opt.retry_initial_wait)
+            .context("invalid --retry-initial-wait")?,
+        max_wait: Duration::try_from_secs_f64(opt.retry_max_wait)
+            .context("invalid --retry-max-wait")?,
     };
 
-    // If we were asked, place a cache in front.
-    if let Some(cache_url) = &opt.cache_url {
-        let cache_key_prefix = opt
-            .cache_key_prefix
-            .as_deref()
-            .unwrap_or_default()
-            .to_owned();
-        let key_value_store =
-            <dyn KeyValueStore>::new_from_url(cache_url.to_owned(), cache_key_prefix)
-                .await?;
-        geocoder = Box::new(
-            Cache::new(
-                key_value_store,
-                geocoder,
-                opt.cache_output_keys,
-                opt.cache_hits_only,
+    // Connect to our cache, if we have one.
+    let key_value_store = match &opt.cache_url {
+        Some(cache_url) => Some(Arc::<dyn KeyValueStore>::from(
+            <dyn KeyValueStore>::new_from_url(
+                cache_url.to_owned(),
+                cache_key_prefix(&opt),
+                pipeline_options.concurrency,
             )
             .await?,
-        );
+        )),
+        None => None,
+    };
+
+    // Choose our main geocoding client, and if we were asked, place a cache in
+    // front.
+    let mut geocoder = if opt.fallback_geocoders.is_empty() {
+        let geocoder = new_geocoder(opt.geocoder, &opt, &rate_limiter)?;
+        match &key_value_store {
+            Some(store) => {
+                Box::new(new_cache(store, geocoder, opt.cache_hits_only, &opt).await?)
+            }
+            None => geocoder,
+        }
+    } else {
+        // If we have fallback geocoders, only pass them the addresses that
+        // earlier geocoders couldn't match. Each remote geocoder gets its own
+        // cache, so that it uses the same cache entries as it would on its
+        // own.
+        let mut geocoders = vec![];
+        let mut after_cache_tier = false;
+        for &name in std::iter::once(&opt.geocoder).chain(&opt.fallback_geocoders) {
+            let tier = new_cascade_tier(
+                name,
+                &opt,
+                &rate_limiter,
+                key_value_store.as_ref(),
+                after_cache_tier,
+            )
+            .await?;
+            geocoders.push((name.name().to_owned(), tier));
+            after_cache_tier |= matches!(name, GeocoderName::Cache);
+        }
+        Box::new(Cascade::new(geocoders)?)
+    };
+
+    // If we were asked, keep recent results in memory, too.
+    if let Some(memory_cache_mb) = opt.memory_cache_mb {
+        geocoder = Box::new(MemoryCache::new(geocoder, memory_cache_mb * 1024 * 1024));
     }
 
     // Always skip invalid records. This needs to happen after we do
@@ -299,15 +680,88 @@ async fn main() -> Result<()> {
             LibPostal::prime().await;
             run_server(&listen_address, geocoder).await
         }
+        Some(Command::Cache { .. }) => unreachable!("handled above"),
         // Run in CLI pipeline mode.
         None => {
-            geocode_stdio(
+            let spec = load_spec(&opt)?;
+            let input = input_location(&opt, opt.input_path.clone(), opt.input_format);
+            let output = Location {
+                path: opt.output_path,
+                compression: opt.output_compression,
+                format: opt.output_format,
+                encoding: Encoding::Utf8,
+                strip_bom: false,
+                csv_dialect: CsvDialect {
+                    delimiter: opt.output_delimiter.0,
+                    quote: opt.output_quote.0,
+                    escape: opt.output_escape.map(|c| c.0),
+                    has_headers: !opt.output_no_headers,
+                },
+            };
+            let column_options = ColumnOptions {
+                on_duplicate_columns: opt.on_duplicate_columns,
+                on_error: opt.on_error,
+            };
+            let checkpointer = match opt.checkpoint_path {
+                Some(path) => {
+                    // Include everything else that affects our output.
+                    let config = format!(
+                        "{:?} {:?} {:?} {:?}",
+                        output, spec, column_options, opt.on_bad_row,
+                    );
+                    let fingerprint = fingerprint(&input, geocoder.as_ref(), &config)?;
+                    Checkpointer::new(
+                        path,
+                        fingerprint,
+                        opt.checkpoint_every,
+                        opt.resume,
+                    )?
+                }
+                None => Checkpointer::disabled(),
+            };
+            let bad_rows = BadRows::new(
+                opt.on_bad_row,
+                opt.quarantine_path.as_deref(),
+                checkpointer.resume_from().and_then(|c| c.quarantine_bytes),
+            )?;
+            let rejects = match &opt.rejects_path {
+                Some(path) => Some(Rejects::new(
+                    path,
+                    checkpointer.resume_from().and_then(|c| c.rejects_bytes),
+                )?),
+                None => None,
+            };
+            let progress_interval = Duration::try_from_secs_f64(opt.progress_interval)
+                .ok()
+                .filter(|interval| !interval.is_zero())
+                .ok_or_else(|| format_err!("--progress-interval must be positive"))?;
+            shutdown::handle_signals()?;
+            let progress = ProgressReporter::start(opt.progress, progress_interval);
+            let result = geocode_stdio(
                 spec,
                 Arc::from(geocoder),
-                opt.on_duplicate_columns,
-                opt.max_retries,
+                input,
+                output,
+                column_options,
+                bad_rows,
+                checkpointer,
+                rejects,
+                retry_policy,
+                opt.max_errors,
+                pipeline_options,
             )
-            .await
+            .await;
+            progress.finish();
+
+            // Write our summary even if we failed, so that it can be checked
+            // by whatever started us.
+            match &opt.summary_json_path {
+                Some(path) => {
+                    let success = result.is_ok();
+                    result.and(SUMMARY.write_json(path, success, start.elapsed()))
+                }
+                None => result,
+            }
         }
     };
 
@@ -318,3 +772,206 @@ async fn main() -> Result<()> {
 
     result
 }
+
+/// Load the spec specified by `--spec`, which clap requires unless we're running
+/// a subcommand.
+fn load_spec(opt: &Opt) -> Result<AddressColumnSpec<String>> {
+    let spec_path = opt
+        .spec_path
+        .as_deref()
+        .ok_or_else(|| format_err!("--spec is required"))?;
+    Ok(if opt.no_headers {
+        AddressColumnSpec::<usize>::from_path(spec_path)?
+            .convert_to_names(headerless_column_name)
+    } else {
+        AddressColumnSpec::from_path(spec_path)?
+    })
+}
+
+/// Where and how to read our input, using the input options in `opt`.
+fn input_location(opt: &Opt, path: Option<PathBuf>, format: Format) -> Location {
+    Location {
+        path,
+        compression: opt.input_compression,
+        format,
+        encoding: opt.encoding,
+        strip_bom: opt.strip_bom,
+        csv_dialect: CsvDialect {
+            delimiter: opt.delimiter.0,
+            quote: opt.quote.0,
+            escape: opt.escape.map(|c| c.0),
+            has_headers: !opt.no_headers,
+        },
+    }
+}
+
+/// The key prefix to use for `--cache`.
+fn cache_key_prefix(opt: &Opt) -> String {
+    opt.cache_key_prefix
+        .as_deref()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// How long to keep cached results, based on our cache options.
+fn cache_ttls(opt: &Opt) -> CacheTtls {
+    CacheTtls {
+        found: opt.cache_ttl.map(|secs| Duration::from_secs(secs.get())),
+        unknown: opt
+            .cache_unknown_ttl
+            .map(|secs| Duration::from_secs(secs.get())),
+        cache_unknown: !opt.no_cache_unknown,
+    }
+}
+
+/// Run one of our `cache` subcommands.
+async fn run_cache_command(cmd: &CacheCommand, opt: &Opt) -> Result<()> {
+    let cache_url = opt
+        .cache_url
+        .as_ref()
+        .ok_or_else(|| format_err!("cache commands require --cache"))?;
+    // We only run one request at a time.
+    let key_value_store = <dyn KeyValueStore>::new_from_url(
+        cache_url.to_owned(),
+        cache_key_prefix(opt),
+        1,
+    )
+    .await?;
+    let store = key_value_store.as_ref();
+    match cmd {
+        CacheCommand::Stats { sample } => cache_admin::stats(store, *sample).await,
+        CacheCommand::Export {
+            prefix,
+            output_path,
+        } => {
+            cache_admin::export(store, prefix.as_deref(), output_path.as_deref()).await
+        }
+        CacheCommand::Import { input_path } => {
+            cache_admin::import(
+                store,
+                input_path.as_deref(),
+                opt.cache_compression,
+                cache_ttls(opt),
+            )
+            .await
+        }
+        CacheCommand::Invalidate {
+            prefix,
+            addresses_path,
+        } => match addresses_path {
+            Some(path) => {
+                let spec = load_spec(opt)
+                    .context("--addresses requires --spec to find address columns")?;
+                let input = input_location(opt, Some(path.to_owned()), Format::Csv);
+                let normalizer = opt.normalize.then(AddressNormalizer::new);
+                let addresses = cache_admin::AddressesToInvalidate {
+                    input: &input,
+                    spec: &spec,
+                    normalizer: normalizer.as_ref(),
+                };
+                cache_admin::invalidate(store, prefix, Some(addresses)).await
+            }
+            None => cache_admin::invalidate(store, prefix, None).await,
+        },
+    }
+}
+
+/// Wrap `geocoder` in a cache stored in `store`. If `hits_only` is true, we
+/// never call `geocoder`, and only return cached results.
+async fn new_cache(
+    store: &Arc<dyn KeyValueStore>,
+    geocoder: Box<dyn Geocoder>,
+    hits_only: bool,
+    opt: &Opt,
+) -> Result<Cache> {
+    Cache::new(
+        Box::new(store.clone()),
+        geocoder,
+        opt.cache_output_keys,
+        hits_only,
+        opt.cache_compression,
+        cache_ttls(opt),
+    )
+    .await
+}
+
+/// Create the geocoder named `name` for use as one tier of a cascade. Remote
+/// geocoders are cached individually, using `store`. If `after_cache_tier` is
+/// true, an earlier `cache` tier has already looked up the same keys, so we
+/// don't count those lookups again.
+async fn new_cascade_tier(
+    name: GeocoderName,
+    opt: &Opt,
+    rate_limiter: &Arc<AdaptiveRateLimiter>,
+    store: Option<&Arc<dyn KeyValueStore>>,
+    after_cache_tier: bool,
+) -> Result<Box<dyn Geocoder>> {
+    match (name, store) {
+        (GeocoderName::Smarty, Some(store)) => {
+            let smarty = new_geocoder(name, opt, rate_limiter)?;
+            let cache = new_cache(store, smarty, opt.cache_hits_only, opt).await?;
+            if after_cache_tier {
+                Ok(Box::new(cache.without_counting_lookups()))
+            } else {
+                Ok(Box::new(cache))
+            }
+        }
+        (GeocoderName::Smarty, None) => new_geocoder(name, opt, rate_limiter),
+        // libpostal is cheap, so we don't cache it. But it would happily
+        // "match" anything, so only accept complete addresses.
+        (GeocoderName::LibPostal, _) => Ok(Box::new(LibPostal::complete_only())),
+        // Look up Smarty's cache entries, without ever calling Smarty. We
+        // don't need Smarty credentials for this.
+        (GeocoderName::Cache, Some(store)) => {
+            let smarty = new_smarty(opt, rate_limiter, None)?;
+            Ok(Box::new(
+                new_cache(store, Box::new(smarty), true, opt).await?,
+            ))
+        }
+        (GeocoderName::Cache, None) => {
+            Err(format_err!("a \"cache\" geocoder requires --cache"))
+        }
+    }
+}
+
+/// Create a Smarty geocoder. If `http_client` is `None`, it can only be used
+/// to read Smarty's cache entries.
+fn new_smarty(
+    opt: &Opt,
+    rate_limiter: &Arc<AdaptiveRateLimiter>,
+    http_client: Option<SharedHttpClient>,
+) -> Result<Smarty> {
+    Smarty::new(
+        opt.match_strategy,
+        opt.smarty_license.clone(),
+        match &opt.smarty_structure {
+            Some(path) => Structure::from_path(path)?,
+            None => Structure::complete()?,
+        },
+        opt.max_candidates,
+        opt.smarty_url.clone(),
+        rate_limiter.clone(),
+        http_client,
+    )
+}
+
+/// Create the underlying geocoder named `name`.
+fn new_geocoder(
+    name: GeocoderName,
+    opt: &Opt,
+    rate_limiter: &Arc<AdaptiveRateLimiter>,
+) -> Result<Box<dyn Geocoder>> {
+    Ok(match name {
+        GeocoderName::Smarty => Box::new(new_smarty(
+            opt,
+            rate_limiter,
+            Some(shared_http_client(opt.concurrency.get())),
+        )?),
+        GeocoderName::LibPostal => Box::new(LibPostal::new()),
+        GeocoderName::Cache => {
+            return Err(format_err!(
+                "a \"cache\" geocoder can only be used with --fallback-geocoder"
+            ));
+        }
+    })
+}
@@ -1,47 +1,81 @@
 //! Geocoding support.
 
 use anyhow::{format_err, Context, Error};
-use csv::{self, StringRecord};
-use futures::{executor::block_on, future, FutureExt, StreamExt};
+use arrow::{datatypes::SchemaRef, record_batch::RecordBatch};
+use csv::StringRecord;
+use futures::{executor::block_on, future, future::BoxFuture, FutureExt, StreamExt};
 use metrics::{counter, describe_counter};
-use std::sync::atomic::AtomicI64;
-use std::{
-    cmp::max, io, iter::FromIterator, sync::Arc, thread::sleep, time::Duration,
-};
+use serde_json::{Map, Value};
+use std::sync::atomic::{AtomicI64, AtomicUsize};
+use std::{cmp::max, iter::FromIterator, sync::Arc};
 use strum_macros::EnumString;
-use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::{
+    sync::mpsc::{self, Sender},
+    time::sleep,
+};
 use tokio_stream::wrappers::ReceiverStream;
-use tracing::{debug, error, instrument, trace, warn};
+use tracing::{debug, instrument, trace, warn};
 
-use crate::addresses::AddressColumnSpec;
+use crate::addresses::{prefix_column_name, Address, AddressColumnSpec};
 use crate::async_util::run_sync_fn_in_background;
+use crate::checkpoint::Checkpointer;
+use crate::concurrency::ConcurrencyController;
 use crate::errors::display_causes_and_backtrace;
-use crate::geocoders::Geocoder;
+use crate::formats::{
+    bad_rows::BadRows, read_input, rejects::Rejects, write_output, Format,
+};
+use crate::geocoders::{ColumnType, GeocodeResult, Geocoder, Unmatched};
+use crate::io_util::Location;
+use crate::progress::PROGRESS;
+use crate::retry::{error_code, ErrorClass, RetryPolicy};
+use crate::shutdown;
+use crate::summary::{PrefixSummary, SUMMARY};
 use crate::Result;
 
-/// The number of chunks to buffer on our internal channels.
-const CHANNEL_BUFFER: usize = 8;
-
-/// The number of concurrent workers to run.
-pub const CONCURRENCY: usize = 48;
-
-/// The number of addresses to pass to our geocoder at one time.
-pub const GEOCODE_SIZE: usize = 72;
+/// The default number of chunks to buffer on our internal channels.
+pub const DEFAULT_CHANNEL_DEPTH: usize = 8;
+
+/// The default number of concurrent workers to run.
+pub const DEFAULT_CONCURRENCY: usize = 48;
+
+/// The default number of addresses to pass to our geocoder at one time.
+pub const DEFAULT_BATCH_SIZE: usize = 72;
+
+/// How much work should our pipeline do at once?
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PipelineOptions {
+    /// The maximum number of chunks to geocode concurrently. This is also used
+    /// to size our HTTP and key/value store connection pools.
+    pub concurrency: usize,
+    /// Should we start with fewer chunks, and adjust our concurrency based on
+    /// latency and errors?
+    pub adaptive_concurrency: bool,
+    /// The maximum number of addresses to pass to our geocoder at one time.
+    pub batch_size: usize,
+    /// The number of chunks to buffer on our internal channels.
+    pub channel_depth: usize,
+}
 
-/// This is the maximum number of chunks that we expect to see in our pipeline
-/// at any one time. If we see more than this, it means that backpressure isn't
-/// working correctly somewhere.
-///
-/// Here's how we compute this:
-///
-/// - We have up to `CONCURRENCY` workers, each of which can process a chunk.
-/// - We have two channels, one between the CSV reader and the workers, and one
-///   between the workers and the CSV writer. Each of these channels can buffer
-///   up to `CHANNEL_BUFFER` chunks.
-/// - We may have one chunk in the CSV reader and one chunk in the CSV writer.
-/// - We allow up to 10 chunks just in case we're overlooking something
-///   in the async machinery that allows a few extra chunks.
-const MAX_EXPECTED_CHUNKS: usize = CHANNEL_BUFFER * 2 + CONCURRENCY + 2 + 10;
+impl PipelineOptions {
+    /// This is the maximum number of chunks that we expect to see in our
+    /// pipeline at any one time. If we see more than this, it means that
+    /// backpressure isn't working correctly somewhere.
+    ///
+    /// Here's how we compute this:
+    ///
+    /// - We have up to `concurrency` workers, each of which can process a
+    ///   chunk.
+    /// - We have two channels, one between the CSV reader and the workers, and
+    ///   one between the workers and the CSV writer. Each of these channels can
+    ///   buffer up to `channel_depth` chunks.
+    /// - We may have one chunk in the CSV reader and one chunk in the CSV
+    ///   writer.
+    /// - We allow up to 10 chunks just in case we're overlooking something in
+    ///   the async machinery that allows a few extra chunks.
+    pub const fn max_expected_chunks(&self) -> usize {
+        self.channel_depth * 2 + self.concurrency + 2 + 10
+    }
+}
 
 /// What should we do if a geocoding output column has the same as a column in
 /// the input?
@@ -56,12 +90,49 @@ pub enum OnDuplicateColumns {
     Append,
 }
 
-/// Data about the CSV file that we include with every chunk to be geocoded.
+/// What should we do if we can't geocode a chunk, even after retrying?
+#[derive(Debug, Clone, Copy, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum OnError {
+    /// Fail with an error.
+    Fail,
+    /// Leave the geocoding columns empty, and record a short error code in an
+    /// `error` column for each prefix.
+    Continue,
+}
+
+/// Options which affect the columns in our output.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ColumnOptions {
+    /// What to do if a geocoding output column has the same name as an input
+    /// column.
+    pub on_duplicate_columns: OnDuplicateColumns,
+    /// What to do if we can't geocode a chunk. With `OnError::Continue`, we
+    /// add an `error` column for each prefix.
+    pub on_error: OnError,
+}
+
+/// The name of the column where we record errors with `--on-error=continue`.
+const ERROR_COLUMN: &str = "error";
+
+/// Data about the input file that we include with every chunk to be geocoded.
 pub struct Shared {
     /// Which columns contain addresses that we need to geocode?
     pub spec: AddressColumnSpec<usize>,
-    /// The header of the output CSV file.
+    /// The header of the output file.
     pub out_headers: StringRecord,
+    /// The number of input columns at the start of each row, after removing
+    /// any duplicate columns. Everything after this was added by our geocoder.
+    pub in_column_count: usize,
+    /// The schema of our input columns, if the input had typed columns.
+    pub in_schema: Option<SchemaRef>,
+    /// The types of the columns added by our geocoder, for all prefixes.
+    pub geocoded_column_types: Vec<ColumnType>,
+    /// The unprefixed names of the columns added by our geocoder for each
+    /// prefix, plus our `error` column if we have one.
+    pub geocoder_column_names: Vec<String>,
+    /// What to do if we can't geocode a chunk.
+    pub on_error: OnError,
 }
 
 /// We use an atomic counter to keep track of how many chunks currently exist.
@@ -71,26 +142,92 @@ pub struct Shared {
 /// up.
 static TOTAL_CHUNKS_EXISTING: AtomicI64 = AtomicI64::new(0);
 
+/// The maximum number of chunks we expect to exist at once. Set by
+/// `geocode_stdio` from its [`PipelineOptions`].
+static MAX_EXPECTED_CHUNKS: AtomicUsize = AtomicUsize::new(
+    PipelineOptions {
+        concurrency: DEFAULT_CONCURRENCY,
+        adaptive_concurrency: true,
+        batch_size: DEFAULT_BATCH_SIZE,
+        channel_depth: DEFAULT_CHANNEL_DEPTH,
+    }
+    .max_expected_chunks(),
+);
+
+/// The original input rows in a chunk, in whatever format we read them.
+pub enum SourceRows {
+    /// Text input. The `rows` of our `Chunk` are the original data.
+    Text,
+    /// Typed input, with one row in this batch for each of our `rows`. Any
+    /// duplicate columns have already been removed.
+    Arrow(RecordBatch),
+    /// JSON Lines input, with one object for each of our `rows`. The `rows`
+    /// only contain the fields mentioned in our `AddressColumnSpec`.
+    Json(Vec<Map<String, Value>>),
+}
+
 /// A chunk to geocode.
 pub struct Chunk {
-    /// Shared information about the CSV file, including headers.
+    /// Shared information about the input file, including headers.
     pub shared: Arc<Shared>,
-    /// The rows to geocode.
+    /// The rows to geocode. For typed input formats, these contain each value
+    /// converted to a string.
     pub rows: Vec<StringRecord>,
+    /// The original rows, for input formats that need more than strings.
+    pub source: SourceRows,
+    /// The number of input rows read up to the end of this chunk, including
+    /// any bad rows that we skipped. Once this chunk has been written, we can
+    /// resume from this point.
+    pub input_end: u64,
+    /// The length of our quarantine file after reading this chunk, if we have
+    /// one.
+    pub quarantine_end: Option<u64>,
+    /// The length of our rejects file after writing this chunk, if we have
+    /// one. Filled in by our output thread, just before we write the chunk.
+    pub rejects_end: Option<u64>,
+    /// Addresses in this chunk which we couldn't geocode. Filled in by
+    /// [`geocode_chunk`].
+    pub unmatched: Vec<UnmatchedAddress>,
+}
+
+/// An address which we couldn't geocode.
+pub struct UnmatchedAddress {
+    /// The index of the row in `Chunk::rows`.
+    pub row: usize,
+    /// The prefix of the address which failed.
+    pub prefix: String,
+    /// Why we couldn't geocode the address.
+    pub reason: Unmatched,
 }
 
 impl Chunk {
     /// Create a new `Chunk`.
-    fn new(shared: Arc<Shared>, rows: Vec<StringRecord>) -> Chunk {
+    fn new(
+        shared: Arc<Shared>,
+        rows: Vec<StringRecord>,
+        source: SourceRows,
+        input_end: u64,
+        quarantine_end: Option<u64>,
+    ) -> Chunk {
         let existing =
             TOTAL_CHUNKS_EXISTING.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-        if existing > MAX_EXPECTED_CHUNKS as i64 {
+        let max_expected =
+            MAX_EXPECTED_CHUNKS.load(std::sync::atomic::Ordering::SeqCst);
+        if existing > max_expected as i64 {
             panic!(
                 "too many chunks in the pipeline: found {}, expected at most {}",
-                existing, MAX_EXPECTED_CHUNKS
+                existing, max_expected
             );
         }
-        Chunk { shared, rows }
+        Chunk {
+            shared,
+            rows,
+            source,
+            input_end,
+            quarantine_end,
+            rejects_end: None,
+            unmatched: vec![],
+        }
     }
 }
 
@@ -108,7 +245,7 @@ impl Drop for Chunk {
 }
 
 /// A message sent on our channel.
-enum Message {
+pub enum Message {
     /// A chunk to geocode.
     Chunk(Chunk),
 
@@ -117,13 +254,21 @@ enum Message {
     EndOfStream,
 }
 
-/// Read CSVs from standard input, geocode them, and write them to standard
-/// output.
+/// Read CSVs (or other formats) from `input` (typically standard input),
+/// geocode them, and write them to `output` (typically standard output).
+#[allow(clippy::too_many_arguments)]
 pub async fn geocode_stdio(
     spec: AddressColumnSpec<String>,
     geocoder: Arc<dyn Geocoder>,
-    on_duplicate_columns: OnDuplicateColumns,
-    max_retries: u8,
+    input: Location,
+    output: Location,
+    column_options: ColumnOptions,
+    bad_rows: BadRows,
+    checkpointer: Checkpointer,
+    rejects: Option<Rejects>,
+    retry_policy: RetryPolicy,
+    max_errors: Option<u64>,
+    pipeline_options: PipelineOptions,
 ) -> Result<()> {
     describe_counter!("geocodecsv.addresses.total", "Total addresses processed");
     describe_counter!("geocodecsv.chunks.total", "Total address chunks processed");
@@ -131,46 +276,134 @@ pub async fn geocode_stdio(
         "geocodecsv.chunks_retried.total",
         "Total address chunks retried"
     );
+    describe_counter!(
+        "geocodecsv.chunks_throttled.total",
+        "Total address chunks retried because a remote service throttled us"
+    );
+    describe_counter!(
+        "geocodecsv.chunks_split.total",
+        "Total address chunks split in half to find addresses rejected by the geocoder"
+    );
+    describe_counter!(
+        "geocodecsv.addresses_rejected_by_geocoder.total",
+        "Total addresses rejected by the geocoder as invalid input"
+    );
     describe_counter!(
         "geocodecsv.chunks_failed.total",
         "total address chunks that failed after all retries"
     );
+    describe_counter!(
+        "geocodecsv.addresses_failed.total",
+        "Total addresses left empty because of errors, with --on-error=continue"
+    );
+
+    // When writing to standard output, default to the same format as our
+    // input.
+    let output = output.with_default_format(input.format());
+
+    // We only keep the fields of JSON objects that are needed for geocoding,
+    // so we can't write them out in other formats.
+    if input.format() == Format::JsonLines && output.format() != Format::JsonLines {
+        return Err(format_err!(
+            "JSON Lines input can only be written as JSON Lines output"
+        ));
+    }
+
+    // We can only append to formats without a footer.
+    if checkpointer.is_enabled()
+        && !matches!(output.format(), Format::Csv | Format::JsonLines)
+    {
+        return Err(format_err!(
+            "--checkpoint only supports CSV and JSON Lines output"
+        ));
+    }
 
     // Set up bounded channels for communication between the sync and async
     // worlds.
-    let (in_tx, in_rx) = mpsc::channel::<Message>(CHANNEL_BUFFER);
-    let (out_tx, out_rx) = mpsc::channel::<Message>(CHANNEL_BUFFER);
+    let (in_tx, in_rx) = mpsc::channel::<Message>(pipeline_options.channel_depth);
+    let (out_tx, out_rx) = mpsc::channel::<Message>(pipeline_options.channel_depth);
+    MAX_EXPECTED_CHUNKS.store(
+        pipeline_options.max_expected_chunks(),
+        std::sync::atomic::Ordering::SeqCst,
+    );
 
     // Hook up our inputs and outputs, which are synchronous functions running
     // in their own threads.
     let geocoder2 = geocoder.clone();
-    let read_fut = run_sync_fn_in_background("read CSV".to_owned(), move || {
-        read_csv_from_stdin(spec, geocoder2.as_ref(), on_duplicate_columns, in_tx)
+    let skip_rows = checkpointer.skip_rows();
+    let input_description = input.description();
+    let output_description = output.description();
+    let read_fut = run_sync_fn_in_background("read input".to_owned(), move || {
+        read_input(
+            spec,
+            geocoder2.as_ref(),
+            &input,
+            column_options,
+            pipeline_options.batch_size,
+            bad_rows,
+            skip_rows,
+            in_tx,
+        )
     });
-    let write_fut = run_sync_fn_in_background("write CSV".to_owned(), move || {
-        write_csv_to_stdout(out_rx)
+    let write_fut = run_sync_fn_in_background("write output".to_owned(), move || {
+        write_output(&output, checkpointer, rejects, out_rx)
     });
 
-    // Geocode each chunk that we see, with up to `CONCURRENCY` chunks being
-    // geocoded at a time.
+    // Geocode each chunk that we see, with up to `concurrency` chunks being
+    // geocoded at a time. Our `controller` may allow fewer chunks to call our
+    // geocoder, depending on how our geocoder is coping.
+    let controller = Arc::new(if pipeline_options.adaptive_concurrency {
+        ConcurrencyController::adaptive(pipeline_options.concurrency)
+    } else {
+        ConcurrencyController::fixed(pipeline_options.concurrency)
+    });
     let geocode_fut = async move {
         let geocoder = geocoder.clone();
         let in_rx = ReceiverStream::new(in_rx);
         let mut stream = in_rx
             // Turn input messages into futures that yield output messages.
             .map(move |message| {
-                geocode_message(geocoder.clone(), message, max_retries).boxed()
+                geocode_message(
+                    geocoder.clone(),
+                    message,
+                    retry_policy,
+                    controller.clone(),
+                )
+                .boxed()
             })
-            // Turn output message futures into output messages in parallel.
-            .buffered(CONCURRENCY);
+            // Turn output message futures into output messages in parallel,
+            // keeping them in order.
+            .buffered(pipeline_options.concurrency);
 
-        // Forward our results to our output.
+        // Forward our results to our output, counting any errors.
+        let mut error_count: u64 = 0;
         while let Some(result) = stream.next().await {
+            let message = result?;
+            if let Message::Chunk(chunk) = &message {
+                error_count += chunk
+                    .unmatched
+                    .iter()
+                    .filter(|u| u.reason == Unmatched::GeocoderError)
+                    .count() as u64;
+            }
             out_tx
-                .send(result?)
+                .send(message)
                 .await
                 .map_err(|_| format_err!("could not send message to output thread"))?;
         }
+        // With `--on-error=continue`, we only fail if we saw too many errors.
+        if error_count > 0 {
+            warn!("{} addresses could not be geocoded because of errors", error_count);
+        }
+        if let Some(max_errors) = max_errors {
+            if error_count > max_errors {
+                return Err(format_err!(
+                    "{} addresses could not be geocoded because of errors, which is more than --max-errors={}",
+                    error_count,
+                    max_errors,
+                ));
+            }
+        }
 
         Ok::<_, Error>(())
     }
@@ -181,9 +414,11 @@ pub async fn geocode_stdio(
         future::join3(read_fut, geocode_fut, write_fut).await;
 
     // Wrap any errors with context.
-    let read_result: Result<()> = read_result.context("error reading input");
+    let read_result: Result<()> = read_result
+        .with_context(|| format!("error reading input from {}", input_description));
     let geocode_result: Result<()> = geocode_result.context("error geocoding");
-    let write_result: Result<()> = write_result.context("error writing output");
+    let write_result: Result<()> = write_result
+        .with_context(|| format!("error writing output to {}", output_description));
 
     // Print if one of the processes fails, it will usually cause the other two
     // to fail. We could try to figure out the "root" cause for the user, or we
@@ -206,22 +441,66 @@ pub async fn geocode_stdio(
         Err(format_err!(
             "geocoding stdio failed because of the above errors"
         ))
+    } else if shutdown::stopped_reading_early() {
+        // Our output is complete up to this point, but we didn't read all our
+        // input.
+        Err(format_err!(
+            "stopped early after writing {} rows in this run",
+            PROGRESS.rows_written(),
+        ))
     } else {
         Ok(())
     }
 }
 
-/// Read a CSV file and write it as messages to `tx`.
-fn read_csv_from_stdin(
+/// Input columns, and how we plan to process them. Built by
+/// [`prepare_input`] once we know the input headers.
+pub struct PreparedInput {
+    /// Shared information about the input, which will be attached to every
+    /// chunk.
+    pub shared: Arc<Shared>,
+
+    /// How many rows should we put in each chunk?
+    pub chunk_size: usize,
+
+    /// If we need to remove duplicate columns from our input, this has one
+    /// flag for each input column, set to true if that column should be
+    /// removed.
+    remove_column_flags: Option<Vec<bool>>,
+}
+
+impl PreparedInput {
+    /// Remove any duplicate columns from `row`.
+    pub fn strip_row(&self, row: StringRecord) -> StringRecord {
+        match &self.remove_column_flags {
+            Some(flags) => remove_columns(&row, flags),
+            None => row,
+        }
+    }
+
+    /// Remove any duplicate columns from `batch`.
+    pub fn strip_batch(&self, batch: RecordBatch) -> Result<RecordBatch> {
+        match &self.remove_column_flags {
+            Some(flags) => Ok(batch.project(&kept_column_indices(flags))?),
+            None => Ok(batch),
+        }
+    }
+}
+
+/// Given the headers of our input, figure out how to handle duplicate
+/// columns, which columns contain addresses, and what our output will look
+/// like.
+///
+/// `in_schema` should be present if our input has typed columns, and it should
+/// match `in_headers`.
+pub fn prepare_input(
     spec: AddressColumnSpec<String>,
     geocoder: &dyn Geocoder,
-    on_duplicate_columns: OnDuplicateColumns,
-    tx: Sender<Message>,
-) -> Result<()> {
-    // Open up our CSV file and get the headers.
-    let stdin = io::stdin();
-    let mut rdr = csv::Reader::from_reader(stdin.lock());
-    let mut in_headers = rdr.headers()?.to_owned();
+    column_options: ColumnOptions,
+    batch_size: usize,
+    mut in_headers: StringRecord,
+    mut in_schema: Option<SchemaRef>,
+) -> Result<PreparedInput> {
     debug!("input headers: {:?}", in_headers);
 
     // Figure out if we have any duplicate columns.
@@ -240,10 +519,9 @@ fn read_csv_from_stdin(
     };
 
     // If we do have duplicate columns, figure out what to do about it.
-    let mut should_remove_columns = false;
-    let mut remove_column_flags = vec![false; in_headers.len()];
+    let mut remove_column_flags = None;
     if !duplicate_column_indices.is_empty() {
-        match on_duplicate_columns {
+        match column_options.on_duplicate_columns {
             OnDuplicateColumns::Error => {
                 return Err(format_err!(
                     "input columns would conflict with geocoding columns: {}",
@@ -252,10 +530,11 @@ fn read_csv_from_stdin(
             }
             OnDuplicateColumns::Replace => {
                 warn!("replacing input columns: {}", duplicate_column_names);
-                should_remove_columns = true;
+                let mut flags = vec![false; in_headers.len()];
                 for i in duplicate_column_indices.iter().cloned() {
-                    remove_column_flags[i] = true;
+                    flags[i] = true;
                 }
+                remove_column_flags = Some(flags);
             }
             OnDuplicateColumns::Append => {
                 warn!(
@@ -267,69 +546,58 @@ fn read_csv_from_stdin(
     }
 
     // Remove any duplicate columns from our input headers.
-    if should_remove_columns {
-        in_headers = remove_columns(&in_headers, &remove_column_flags);
+    if let Some(flags) = &remove_column_flags {
+        in_headers = remove_columns(&in_headers, flags);
+        if let Some(schema) = &in_schema {
+            in_schema = Some(Arc::new(schema.project(&kept_column_indices(flags))?));
+        }
     }
+    let in_column_count = in_headers.len();
 
     // Convert our column spec from using header names to header indices.
     //
     // This needs to happen _after_ `remove_columns` on our headers!
     let spec = spec.convert_to_indices_using_headers(&in_headers)?;
 
-    // Decide how big to make our chunks. We want to geocode no more
-    // `GEOCODE`-size addresses at a time, and each input row may generate up to
+    // Decide how big to make our chunks. We want to geocode no more than
+    // `batch_size` addresses at a time, and each input row may generate up to
     // `spec.prefix_count()` addresses.
-    let chunk_size = max(1, GEOCODE_SIZE / max(spec.prefix_count(), 1));
-    assert!(chunk_size > 0 && chunk_size <= GEOCODE_SIZE);
+    let chunk_size = max(1, batch_size / max(spec.prefix_count(), 1));
+    assert!(chunk_size > 0 && chunk_size <= batch_size);
 
     // Build our output headers.
+    let on_error = column_options.on_error;
     let mut out_headers = in_headers;
+    let mut geocoded_column_types = vec![];
+    let mut geocoder_column_names = geocoder.column_names().to_owned();
     for prefix in spec.prefixes() {
         geocoder.add_header_columns(prefix, &mut out_headers);
-    }
-    debug!("output headers: {:?}", out_headers);
-
-    // Build our shared CSV file metadata, and wrap it with a reference count.
-    let shared = Arc::new(Shared { spec, out_headers });
-
-    // Group up the rows into chunks and send them to `tx`.
-    let mut sent_chunk = false;
-    let mut rows = Vec::with_capacity(chunk_size);
-    for row in rdr.records() {
-        let mut row = row?;
-        if should_remove_columns {
-            // Strip out any duplicate columns.
-            row = remove_columns(&row, &remove_column_flags);
-        }
-        rows.push(row);
-        if rows.len() >= chunk_size {
-            trace!("sending {} input rows", rows.len());
-            block_on(tx.send(Message::Chunk(Chunk::new(shared.clone(), rows))))
-                .map_err(|_| {
-                    format_err!("could not send rows to geocoder (perhaps it failed)")
-                })?;
-            sent_chunk = true;
-            rows = Vec::with_capacity(chunk_size);
+        geocoded_column_types.extend(geocoder.column_types());
+        if on_error == OnError::Continue {
+            out_headers.push_field(&prefix_column_name(prefix, ERROR_COLUMN));
+            geocoded_column_types.push(ColumnType::Text);
         }
     }
-
-    // Send a final chunk if either (1) we never sent a chunk, or (2) we have
-    // rows that haven't been sent yet.
-    if !sent_chunk || !rows.is_empty() {
-        trace!("sending final {} input rows", rows.len());
-        block_on(tx.send(Message::Chunk(Chunk { shared, rows }))).map_err(|_| {
-            format_err!("could not send rows to geocoder (perhaps it failed)")
-        })?;
+    if on_error == OnError::Continue {
+        geocoder_column_names.push(ERROR_COLUMN.to_owned());
     }
+    debug!("output headers: {:?}", out_headers);
 
-    // Confirm that we've seen the end of the stream.
-    trace!("sending end-of-stream for input");
-    block_on(tx.send(Message::EndOfStream)).map_err(|_| {
-        format_err!("could not send end-of-stream to geocoder (perhaps it failed)")
-    })?;
-
-    debug!("done sending input");
-    Ok(())
+    // Build our shared metadata, and wrap it with a reference count.
+    let shared = Arc::new(Shared {
+        spec,
+        out_headers,
+        in_column_count,
+        in_schema,
+        geocoded_column_types,
+        geocoder_column_names,
+        on_error,
+    });
+    Ok(PreparedInput {
+        shared,
+        chunk_size,
+        remove_column_flags,
+    })
 }
 
 /// Remove columns from `row` if they're set to true in `remove_column_flags`.
@@ -346,56 +614,141 @@ fn remove_columns(row: &StringRecord, remove_column_flags: &[bool]) -> StringRec
     ))
 }
 
-/// Receive chunks of a CSV file from `rx` and write them to standard output.
-fn write_csv_to_stdout(rx: Receiver<Message>) -> Result<()> {
-    let stdout = io::stdout();
-    let mut wtr = csv::Writer::from_writer(stdout.lock());
-
-    let mut headers_written = false;
-    let mut end_of_stream_seen = false;
-    let mut rx = ReceiverStream::new(rx);
-    while let Some(message) = block_on(rx.next()) {
-        match message {
-            Message::Chunk(chunk) => {
-                trace!("received {} output rows", chunk.rows.len());
-                if !headers_written {
-                    wtr.write_record(&chunk.shared.out_headers)?;
-                    headers_written = true;
-                }
-                for row in &chunk.rows {
-                    wtr.write_record(row)?;
-                }
-            }
-            Message::EndOfStream => {
-                trace!("received end-of-stream for output");
-                assert!(headers_written);
-                end_of_stream_seen = true;
-                break;
-            }
+/// Return the indices of all columns we're not removing.
+fn kept_column_indices(remove_column_flags: &[bool]) -> Vec<usize> {
+    remove_column_flags
+        .iter()
+        .enumerate()
+        .filter_map(|(i, &remove)| if remove { None } else { Some(i) })
+        .collect()
+}
+
+/// Sends chunks of input rows to our geocoder.
+pub struct ChunkSender {
+    /// Shared information about our input.
+    shared: Arc<Shared>,
+
+    /// Where to send our chunks.
+    tx: Sender<Message>,
+
+    /// Have we sent any chunks yet?
+    sent_chunk: bool,
+
+    /// How many input rows have we read, including bad rows?
+    input_rows: u64,
+
+    /// How many input rows were already processed before we resumed?
+    skip_rows: u64,
+
+    /// What to do with malformed input rows.
+    bad_rows: BadRows,
+}
+
+impl ChunkSender {
+    /// Create a new `ChunkSender`. If we're resuming an earlier run, the first
+    /// `skip_rows` input rows have already been written. Malformed rows are
+    /// passed to `bad_rows`.
+    pub fn new(
+        shared: Arc<Shared>,
+        tx: Sender<Message>,
+        skip_rows: u64,
+        bad_rows: BadRows,
+    ) -> ChunkSender {
+        ChunkSender {
+            shared,
+            tx,
+            sent_chunk: false,
+            input_rows: 0,
+            skip_rows,
+            bad_rows,
         }
     }
-    if !end_of_stream_seen {
-        // The background thread exitted without sending anything. This
-        // shouldn't happen.
-        error!("did not receive end-of-stream");
-        return Err(format_err!(
-            "did not receive end-of-stream from geocoder (perhaps it failed)"
-        ));
+
+    /// Record that we've read `count` more input rows (including bad rows),
+    /// and return how many of them we should skip because they were
+    /// processed before we resumed. Skipped rows always come first.
+    pub fn read_rows(&mut self, count: usize) -> usize {
+        let skip = self
+            .skip_rows
+            .saturating_sub(self.input_rows)
+            .min(count as u64);
+        self.input_rows += count as u64;
+        PROGRESS.read_rows(count, skip as usize);
+        skip as usize
+    }
+
+    /// Handle a malformed row starting on `line`, which failed with `err`.
+    /// The row should already have been counted by
+    /// [`ChunkSender::read_rows`].
+    pub fn bad_row(&mut self, line: u64, raw: &[u8], err: Error) -> Result<()> {
+        self.bad_rows.handle(line, raw, err)
+    }
+
+    /// Should we stop reading input? This is true once we've been asked to
+    /// shut down. Readers should check this after each chunk they send, and
+    /// call [`ChunkSender::finish`] if it's true.
+    pub fn is_stopping(&self) -> bool {
+        shutdown::should_stop_reading()
+    }
+
+    /// Send a chunk of rows to our geocoder, blocking until there's room.
+    pub fn send(&mut self, rows: Vec<StringRecord>, source: SourceRows) -> Result<()> {
+        trace!("sending {} input rows", rows.len());
+        let quarantine_end = self.bad_rows.checkpoint()?;
+        let chunk = Chunk::new(
+            self.shared.clone(),
+            rows,
+            source,
+            self.input_rows,
+            quarantine_end,
+        );
+        block_on(self.tx.send(Message::Chunk(chunk))).map_err(|_| {
+            format_err!("could not send rows to geocoder (perhaps it failed)")
+        })?;
+        self.sent_chunk = true;
+        Ok(())
+    }
+
+    /// Send any remaining `rows` (with their `source`), and then send
+    /// end-of-stream.
+    pub fn finish(
+        mut self,
+        rows: Vec<StringRecord>,
+        source: SourceRows,
+    ) -> Result<()> {
+        // Send a final chunk if either (1) we never sent a chunk, or (2) we
+        // have rows that haven't been sent yet. We need to send at least one
+        // chunk so that our output gets headers.
+        if !self.sent_chunk || !rows.is_empty() {
+            trace!("sending final {} input rows", rows.len());
+            self.send(rows, source)?;
+        }
+        self.bad_rows.finish()?;
+
+        // Confirm that we've seen the end of the stream.
+        trace!("sending end-of-stream for input");
+        block_on(self.tx.send(Message::EndOfStream)).map_err(|_| {
+            format_err!("could not send end-of-stream to geocoder (perhaps it failed)")
+        })?;
+
+        debug!("done sending input");
+        Ok(())
     }
-    Ok(())
 }
 
 /// Geocode a `Message`. This is just a wrapper around `geocode_chunk`.
 async fn geocode_message(
     geocoder: Arc<dyn Geocoder>,
     message: Message,
-    max_retries: u8,
+    retry_policy: RetryPolicy,
+    controller: Arc<ConcurrencyController>,
 ) -> Result<Message> {
     match message {
         Message::Chunk(chunk) => {
             trace!("geocoding {} rows", chunk.rows.len());
             Ok(Message::Chunk(
-                geocode_chunk(geocoder.as_ref(), chunk, max_retries).await?,
+                geocode_chunk(geocoder.as_ref(), chunk, retry_policy, &controller)
+                    .await?,
             ))
         }
         Message::EndOfStream => {
@@ -414,8 +767,15 @@ async fn geocode_message(
 pub async fn geocode_chunk(
     geocoder: &dyn Geocoder,
     mut chunk: Chunk,
-    max_retries: u8,
+    retry_policy: RetryPolicy,
+    controller: &ConcurrencyController,
 ) -> Result<Chunk> {
+    // We may see empty chunks when our input is empty, or when we're skipping
+    // rows after resuming.
+    if chunk.rows.is_empty() {
+        return Ok(chunk);
+    }
+
     // Build a list of addresses to geocode.
     let prefixes = chunk.shared.spec.prefixes();
     let mut addresses = vec![];
@@ -433,47 +793,179 @@ pub async fn geocode_chunk(
 
     // Geocode our addresses.
     trace!("geocoding {} addresses", addresses_len);
-    let mut failures: u8 = 0;
-    let mut retry_wait = Duration::from_secs(2);
-    let geocoded = loop {
-        // TODO: The `clone` here is expensive. We might want to move the
-        // `retry` loop inside of `street_addresses`.
-        let result = geocoder.geocode_addresses(&addresses).await;
-        match result {
-            Err(ref err) if failures < max_retries => {
-                failures += 1;
-                debug!(
-                    "retrying geocoder error (waiting {} secs): {:?}",
-                    retry_wait.as_secs(),
-                    err
-                );
-                counter!("geocodecsv.chunks_retried.total", 1);
-                sleep(retry_wait);
-                retry_wait *= 2;
-            }
-            Err(err) => {
-                counter!("geocodecsv.chunks_failed.total", 1);
-                return Err(err).context("geocoder error");
-            }
-            Ok(geocoded) => {
-                counter!("geocodecsv.chunks.total", 1);
-                break geocoded;
-            }
+    let on_error = chunk.shared.on_error;
+    let (geocoded, error_code) = match geocode_addresses_with_retries(
+        geocoder,
+        &addresses,
+        retry_policy,
+        controller,
+    )
+    .await
+    {
+        Ok(geocoded) => (geocoded, None),
+        // If we've been asked to continue, record the error in each row.
+        Err(err) if on_error == OnError::Continue => {
+            let code = error_code(&err);
+            warn!(
+                "could not geocode {} addresses, continuing: {:?}",
+                addresses_len, err,
+            );
+            counter!("geocodecsv.addresses_failed.total", addresses_len as u64, "error" => code.clone());
+            let failed = vec![Err(Unmatched::GeocoderError); addresses_len];
+            (failed, Some(code))
         }
+        Err(err) => return Err(err),
     };
     counter!("geocodecsv.addresses.total", addresses_len as u64);
+    PROGRESS.geocoded_addresses(addresses_len);
     trace!("geocoded {} addresses", addresses_len);
 
     // Add address information to our output rows.
-    for geocoded_for_prefix in geocoded.chunks(chunk.rows.len()) {
+    let precision_idx = geocoder.precision_column();
+    for (prefix, geocoded_for_prefix) in
+        prefixes.iter().zip(geocoded.chunks(chunk.rows.len()))
+    {
         assert_eq!(geocoded_for_prefix.len(), chunk.rows.len());
-        for (response, row) in geocoded_for_prefix.iter().zip(&mut chunk.rows) {
-            if let Some(response) = response {
-                geocoder.add_value_columns_to_row(response, row);
-            } else {
-                geocoder.add_empty_columns_to_row(row);
+        let mut summary = PrefixSummary::default();
+        for (i, (response, row)) in
+            geocoded_for_prefix.iter().zip(&mut chunk.rows).enumerate()
+        {
+            summary.add(response, precision_idx);
+            match response {
+                Ok(response) => geocoder.add_value_columns_to_row(response, row),
+                Err(reason) => {
+                    geocoder.add_empty_columns_to_row(row);
+                    chunk.unmatched.push(UnmatchedAddress {
+                        row: i,
+                        prefix: (*prefix).to_owned(),
+                        reason: *reason,
+                    });
+                }
+            }
+            if on_error == OnError::Continue {
+                // An error for the whole chunk applies to every row, but we
+                // may also have isolated addresses that our geocoder
+                // rejected.
+                match (&error_code, response) {
+                    (Some(code), _) => row.push_field(code),
+                    (None, Err(reason @ Unmatched::RejectedByGeocoder)) => {
+                        row.push_field(&reason.to_string())
+                    }
+                    (None, _) => row.push_field(""),
+                }
             }
         }
+        SUMMARY.add_prefix(prefix, summary);
     }
     Ok(chunk)
 }
+
+/// Geocode `addresses`, retrying as described by `retry_policy`.
+///
+/// If our geocoder rejects the request because of bad input, we split
+/// `addresses` in half and try each half separately, until we find the
+/// addresses that caused the problem. These are marked as
+/// [`Unmatched::RejectedByGeocoder`], so that one bad address doesn't prevent
+/// us from geocoding the rest.
+fn geocode_addresses_with_retries<'a>(
+    geocoder: &'a dyn Geocoder,
+    addresses: &'a [Address],
+    retry_policy: RetryPolicy,
+    controller: &'a ConcurrencyController,
+) -> BoxFuture<'a, Result<Vec<GeocodeResult>>> {
+    async move {
+        let mut failures: u8 = 0;
+        let mut throttled: u8 = 0;
+        loop {
+            // Wait until our controller allows another request, and let it
+            // know how the request went.
+            let permit = controller.acquire().await;
+            // TODO: The `clone` here is expensive. We might want to move the
+            // `retry` loop inside of `street_addresses`.
+            let err = match permit.run(geocoder.geocode_addresses(addresses)).await {
+                Ok(geocoded) => {
+                    permit.finish(false);
+                    counter!("geocodecsv.chunks.total", 1);
+                    SUMMARY.chunk();
+                    return Ok(geocoded);
+                }
+                Err(err) => err,
+            };
+            let class = ErrorClass::of(&err);
+            permit.finish(matches!(
+                class,
+                ErrorClass::Throttled | ErrorClass::Retryable
+            ));
+            match class {
+                // Throttling isn't a real failure, and our geocoder's rate
+                // limiter will make us wait before trying again, so we don't
+                // count it against `max_retries`.
+                ErrorClass::Throttled
+                    if throttled < retry_policy.max_throttled_retries =>
+                {
+                    throttled += 1;
+                    debug!("retrying throttled request: {:?}", err);
+                    counter!("geocodecsv.chunks_throttled.total", 1);
+                    SUMMARY.chunk_throttled();
+                }
+                ErrorClass::Retryable if failures < retry_policy.max_retries => {
+                    let retry_wait = retry_policy.wait_before_retry(failures);
+                    failures += 1;
+                    debug!(
+                        "retrying geocoder error (waiting {:.1} secs): {:?}",
+                        retry_wait.as_secs_f64(),
+                        err
+                    );
+                    counter!("geocodecsv.chunks_retried.total", 1);
+                    SUMMARY.chunk_retried();
+                    sleep(retry_wait).await;
+                }
+                ErrorClass::BadRequest if addresses.len() == 1 => {
+                    warn!("geocoder rejected an address: {}", err);
+                    counter!("geocodecsv.addresses_rejected_by_geocoder.total", 1);
+                    return Ok(vec![Err(Unmatched::RejectedByGeocoder)]);
+                }
+                ErrorClass::BadRequest if addresses.len() > 1 => {
+                    debug!(
+                        "splitting {} addresses to find bad input: {:?}",
+                        addresses.len(),
+                        err,
+                    );
+                    counter!("geocodecsv.chunks_split.total", 1);
+                    SUMMARY.chunk_split();
+                    let (first, second) = addresses.split_at(addresses.len() / 2);
+                    let mut geocoded = geocode_addresses_with_retries(
+                        geocoder,
+                        first,
+                        retry_policy,
+                        controller,
+                    )
+                    .await?;
+                    geocoded.extend(
+                        geocode_addresses_with_retries(
+                            geocoder,
+                            second,
+                            retry_policy,
+                            controller,
+                        )
+                        .await?,
+                    );
+                    return Ok(geocoded);
+                }
+                ErrorClass::Fatal => {
+                    counter!("geocodecsv.chunks_failed.total", 1);
+                    SUMMARY.chunk_failed();
+                    return Err(err).context("geocoder error (not retrying)");
+                }
+                ErrorClass::Throttled
+                | ErrorClass::Retryable
+                | ErrorClass::BadRequest => {
+                    counter!("geocodecsv.chunks_failed.total", 1);
+                    SUMMARY.chunk_failed();
+                    return Err(err).context("geocoder error");
+                }
+            }
+        }
+    }
+    .boxed()
+}
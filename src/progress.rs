@@ -0,0 +1,482 @@
+//! Live progress reporting for long-running jobs.
+//!
+//! Our pipeline updates a set of global counters, alongside the metrics it
+//! already records. While we run, a background task periodically prints these
+//! counters to standard error, either as a progress bar (if standard error is
+//! a terminal) or as `key=value` log lines.
+
+use std::{
+    fmt::Write as _,
+    io::{self, IsTerminal, Write},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use strum_macros::EnumString;
+use tokio::{
+    task::JoinHandle,
+    time::{interval_at, Instant, MissedTickBehavior},
+};
+
+/// Progress counters for the current job.
+pub static PROGRESS: Progress = Progress::new();
+
+/// How often should we redraw our progress bar?
+const BAR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How wide should our progress bar be, in characters?
+const BAR_WIDTH: usize = 30;
+
+/// How should we report progress?
+#[derive(Debug, Clone, Copy, EnumString, Eq, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum ProgressMode {
+    /// Use `bar` if standard error is a terminal, and `log` otherwise.
+    Auto,
+    /// Redraw a progress bar on standard error.
+    Bar,
+    /// Print a `key=value` log line to standard error every so often.
+    Log,
+    /// Don't report progress.
+    None,
+}
+
+/// Counters updated by our pipeline. All counts start at 0.
+pub struct Progress {
+    /// Input rows read, including bad rows and rows skipped when resuming.
+    rows_read: AtomicU64,
+
+    /// Input rows skipped because they were processed before we resumed.
+    rows_skipped: AtomicU64,
+
+    /// Rows written to our output.
+    rows_written: AtomicU64,
+
+    /// Addresses geocoded (one per prefix per row).
+    addresses: AtomicU64,
+
+    /// Addresses we looked up in our cache.
+    cache_lookups: AtomicU64,
+
+    /// Addresses we found in our cache, including cached failures.
+    cache_hits: AtomicU64,
+
+    /// Bytes read from our input file, before decompression.
+    input_bytes_read: AtomicU64,
+
+    /// The size of our input file, or 0 if unknown.
+    input_bytes_total: AtomicU64,
+
+    /// The number of rows in our input, or 0 if unknown.
+    input_rows_total: AtomicU64,
+
+    /// Bytes read from our input file when we skipped our last row that was
+    /// processed before we resumed.
+    input_bytes_at_resume: AtomicU64,
+
+    /// When we skipped our last row that was processed before we resumed, or
+    /// `None` if we didn't resume.
+    resumed_at: Mutex<Option<Instant>>,
+}
+
+impl Progress {
+    /// Create a new set of counters.
+    const fn new() -> Progress {
+        Progress {
+            rows_read: AtomicU64::new(0),
+            rows_skipped: AtomicU64::new(0),
+            rows_written: AtomicU64::new(0),
+            addresses: AtomicU64::new(0),
+            cache_lookups: AtomicU64::new(0),
+            cache_hits: AtomicU64::new(0),
+            input_bytes_read: AtomicU64::new(0),
+            input_bytes_total: AtomicU64::new(0),
+            input_rows_total: AtomicU64::new(0),
+            input_bytes_at_resume: AtomicU64::new(0),
+            resumed_at: Mutex::new(None),
+        }
+    }
+
+    /// Record that we've read `count` input rows, `skipped` of which were
+    /// processed before we resumed.
+    pub fn read_rows(&self, count: usize, skipped: usize) {
+        self.rows_read.fetch_add(count as u64, Ordering::Relaxed);
+        self.rows_skipped
+            .fetch_add(skipped as u64, Ordering::Relaxed);
+        if skipped > 0 {
+            // Skipped rows always come first, so this is where our real work
+            // starts. Remember it, so that we don't count skipped rows when
+            // estimating our rate.
+            let bytes_read = self.input_bytes_read.load(Ordering::Relaxed);
+            self.input_bytes_at_resume
+                .store(bytes_read, Ordering::Relaxed);
+            *self.resumed_at.lock().expect("lock poisoned") = Some(Instant::now());
+        }
+    }
+
+    /// Record that we've written `count` output rows.
+    pub fn wrote_rows(&self, count: usize) {
+        self.rows_written.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// How many output rows have we written?
+    pub fn rows_written(&self) -> u64 {
+        self.rows_written.load(Ordering::Relaxed)
+    }
+
+    /// Record that we've geocoded `count` addresses.
+    pub fn geocoded_addresses(&self, count: usize) {
+        self.addresses.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Record that we looked up `lookups` addresses in our cache, and found
+    /// `hits` of them.
+    pub fn cache_lookups(&self, lookups: usize, hits: usize) {
+        self.cache_lookups
+            .fetch_add(lookups as u64, Ordering::Relaxed);
+        self.cache_hits.fetch_add(hits as u64, Ordering::Relaxed);
+    }
+
+    /// Record that we've read `count` bytes of our input file.
+    pub fn read_input_bytes(&self, count: usize) {
+        self.input_bytes_read
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// Record the size of our input file, so that we can estimate how long we
+    /// have left.
+    pub fn set_input_bytes(&self, total: u64) {
+        self.input_bytes_total.store(total, Ordering::Relaxed);
+    }
+
+    /// Record the number of rows in our input, for formats which tell us this
+    /// up front.
+    pub fn set_input_rows(&self, total: u64) {
+        self.input_rows_total.store(total, Ordering::Relaxed);
+    }
+
+    /// Take a snapshot of our counters, `elapsed` after we started.
+    pub fn snapshot(&self, elapsed: Duration) -> Snapshot {
+        let rows_read = self.rows_read.load(Ordering::Relaxed);
+        let rows_skipped = self.rows_skipped.load(Ordering::Relaxed);
+        let input_bytes_total = self.input_bytes_total.load(Ordering::Relaxed);
+        let input_rows_total = self.input_rows_total.load(Ordering::Relaxed);
+        let fraction = |bytes: &AtomicU64, rows: u64| {
+            if input_bytes_total > 0 {
+                let read = bytes.load(Ordering::Relaxed);
+                Some((read as f64 / input_bytes_total as f64).min(1.0))
+            } else if input_rows_total > 0 {
+                Some((rows as f64 / input_rows_total as f64).min(1.0))
+            } else {
+                None
+            }
+        };
+        let fraction_done = fraction(&self.input_bytes_read, rows_read);
+        let fraction_at_resume =
+            fraction(&self.input_bytes_at_resume, rows_skipped).unwrap_or(0.0);
+        let elapsed_since_resume =
+            match *self.resumed_at.lock().expect("lock poisoned") {
+                Some(resumed_at) => resumed_at.elapsed().min(elapsed),
+                None => elapsed,
+            };
+        Snapshot {
+            elapsed,
+            elapsed_since_resume,
+            rows_read,
+            rows_skipped,
+            rows_written: self.rows_written.load(Ordering::Relaxed),
+            addresses: self.addresses.load(Ordering::Relaxed),
+            cache_lookups: self.cache_lookups.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            fraction_done,
+            fraction_at_resume,
+        }
+    }
+}
+
+/// Our progress at a moment in time. See [`Progress`] for details.
+#[derive(Debug)]
+pub struct Snapshot {
+    /// How long we've been running.
+    pub elapsed: Duration,
+    /// How long we've been running since we skipped the rows processed
+    /// before we resumed. The same as `elapsed` if we didn't resume.
+    pub elapsed_since_resume: Duration,
+    pub rows_read: u64,
+    pub rows_skipped: u64,
+    pub rows_written: u64,
+    pub addresses: u64,
+    pub cache_lookups: u64,
+    pub cache_hits: u64,
+    /// How much of our input we've read, from 0.0 to 1.0, if we know.
+    pub fraction_done: Option<f64>,
+    /// How much of our input we'd already read when we resumed, or 0.0.
+    pub fraction_at_resume: f64,
+}
+
+impl Snapshot {
+    /// Our average geocoding rate since we started (or resumed).
+    fn addresses_per_second(&self) -> f64 {
+        let secs = self.elapsed_since_resume.as_secs_f64();
+        if secs > 0.0 {
+            self.addresses as f64 / secs
+        } else {
+            0.0
+        }
+    }
+
+    /// What fraction of our cache lookups were hits? `None` if we don't have a
+    /// cache.
+    fn cache_hit_ratio(&self) -> Option<f64> {
+        if self.cache_lookups > 0 {
+            Some(self.cache_hits as f64 / self.cache_lookups as f64)
+        } else {
+            None
+        }
+    }
+
+    /// Estimate how long we have left, assuming we keep going at our average
+    /// rate since we started (or resumed).
+    fn eta(&self) -> Option<Duration> {
+        match self.fraction_done {
+            Some(done) if done > self.fraction_at_resume => {
+                let progress = done - self.fraction_at_resume;
+                Some(self.elapsed_since_resume.mul_f64((1.0 - done) / progress))
+            }
+            _ => None,
+        }
+    }
+
+    /// Format this snapshot as a one-line progress bar.
+    fn to_bar(&self) -> String {
+        let mut out = String::new();
+        if let Some(done) = self.fraction_done {
+            let filled = (done * BAR_WIDTH as f64) as usize;
+            let _ = write!(
+                out,
+                "[{}{}] {:3.0}% ",
+                "#".repeat(filled),
+                "-".repeat(BAR_WIDTH - filled),
+                done * 100.0,
+            );
+        }
+        let _ = write!(out, "{} rows read", self.rows_read);
+        if self.rows_skipped > 0 {
+            let _ = write!(out, " ({} skipped)", self.rows_skipped);
+        }
+        let _ = write!(
+            out,
+            ", {} written, {:.0} addresses/s",
+            self.rows_written,
+            self.addresses_per_second(),
+        );
+        if let Some(ratio) = self.cache_hit_ratio() {
+            let _ = write!(out, ", {:.0}% cached", ratio * 100.0);
+        }
+        if let Some(eta) = self.eta() {
+            let _ = write!(out, ", ETA {}", format_duration(eta));
+        }
+        out
+    }
+
+    /// Format this snapshot as a `key=value` log line.
+    fn to_log_line(&self) -> String {
+        let mut out = format!(
+            "progress elapsed_secs={} rows_read={}",
+            self.elapsed.as_secs(),
+            self.rows_read,
+        );
+        if self.rows_skipped > 0 {
+            let _ = write!(out, " rows_skipped={}", self.rows_skipped);
+        }
+        let _ = write!(
+            out,
+            " rows_written={} addresses_per_second={:.1}",
+            self.rows_written,
+            self.addresses_per_second(),
+        );
+        if let Some(ratio) = self.cache_hit_ratio() {
+            let _ = write!(out, " cache_hit_ratio={:.3}", ratio);
+        }
+        if let Some(done) = self.fraction_done {
+            let _ = write!(out, " percent_done={:.1}", done * 100.0);
+        }
+        if let Some(eta) = self.eta() {
+            let _ = write!(out, " eta_secs={}", eta.as_secs());
+        }
+        out
+    }
+}
+
+/// Format `duration` compactly, like `1h02m` or `3m05s`.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, mins, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h{:02}m", hours, mins)
+    } else {
+        format!("{}m{:02}s", mins, secs)
+    }
+}
+
+#[test]
+fn format_progress() {
+    let snapshot = Snapshot {
+        elapsed: Duration::from_secs(100),
+        elapsed_since_resume: Duration::from_secs(100),
+        rows_read: 1000,
+        rows_skipped: 0,
+        rows_written: 900,
+        addresses: 2000,
+        cache_lookups: 2000,
+        cache_hits: 500,
+        fraction_done: Some(0.25),
+        fraction_at_resume: 0.0,
+    };
+    assert_eq!(snapshot.eta(), Some(Duration::from_secs(300)));
+    assert_eq!(
+        snapshot.to_bar(),
+        "[#######-----------------------]  25% 1000 rows read, 900 written, 20 addresses/s, 25% cached, ETA 5m00s",
+    );
+    assert_eq!(
+        snapshot.to_log_line(),
+        "progress elapsed_secs=100 rows_read=1000 rows_written=900 addresses_per_second=20.0 cache_hit_ratio=0.250 percent_done=25.0 eta_secs=300",
+    );
+
+    // Leave out anything we don't know.
+    let snapshot = Snapshot {
+        cache_lookups: 0,
+        cache_hits: 0,
+        fraction_done: None,
+        ..snapshot
+    };
+    assert_eq!(snapshot.eta(), None);
+    assert_eq!(
+        snapshot.to_bar(),
+        "1000 rows read, 900 written, 20 addresses/s",
+    );
+    assert_eq!(
+        snapshot.to_log_line(),
+        "progress elapsed_secs=100 rows_read=1000 rows_written=900 addresses_per_second=20.0",
+    );
+
+    // When we resume, only count the work we've done since then.
+    let snapshot = Snapshot {
+        elapsed_since_resume: Duration::from_secs(50),
+        rows_skipped: 500,
+        fraction_done: Some(0.75),
+        fraction_at_resume: 0.5,
+        ..snapshot
+    };
+    assert_eq!(snapshot.eta(), Some(Duration::from_secs(50)));
+    assert_eq!(
+        snapshot.to_bar(),
+        "[######################--------]  75% 1000 rows read (500 skipped), 900 written, 40 addresses/s, ETA 0m50s",
+    );
+    assert_eq!(
+        snapshot.to_log_line(),
+        "progress elapsed_secs=100 rows_read=1000 rows_skipped=500 rows_written=900 addresses_per_second=40.0 percent_done=75.0 eta_secs=50",
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn eta_ignores_rows_skipped_when_resuming() {
+    let progress = Progress::new();
+    progress.set_input_rows(100);
+    tokio::time::advance(Duration::from_secs(1)).await;
+    progress.read_rows(90, 90);
+    tokio::time::advance(Duration::from_secs(10)).await;
+    progress.read_rows(5, 0);
+    let snapshot = progress.snapshot(Duration::from_secs(11));
+    assert_eq!(snapshot.elapsed_since_resume, Duration::from_secs(10));
+    let eta = snapshot.eta().expect("should have an ETA");
+    assert_eq!(eta.as_secs_f64().round(), 10.0);
+}
+
+#[test]
+fn format_durations() {
+    assert_eq!(format_duration(Duration::from_secs(65)), "1m05s");
+    assert_eq!(format_duration(Duration::from_secs(3720)), "1h02m");
+}
+
+/// A background task which reports our progress.
+pub struct ProgressReporter {
+    /// How we're reporting progress.
+    mode: ProgressMode,
+
+    /// When we started.
+    start: Instant,
+
+    /// Our background task, if we have one.
+    task: Option<JoinHandle<()>>,
+}
+
+impl ProgressReporter {
+    /// Start reporting progress. In `log` mode, we print a line every
+    /// `log_interval`.
+    pub fn start(mode: ProgressMode, log_interval: Duration) -> ProgressReporter {
+        let mode = match mode {
+            ProgressMode::Auto if io::stderr().is_terminal() => ProgressMode::Bar,
+            ProgressMode::Auto => ProgressMode::Log,
+            mode => mode,
+        };
+        let start = Instant::now();
+        let period = match mode {
+            ProgressMode::Bar => BAR_INTERVAL,
+            ProgressMode::Log => log_interval,
+            ProgressMode::Auto | ProgressMode::None => {
+                return ProgressReporter {
+                    mode,
+                    start,
+                    task: None,
+                }
+            }
+        };
+        let task = tokio::spawn(async move {
+            let mut interval = interval_at(start + period, period);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                report(mode, start, false);
+            }
+        });
+        ProgressReporter {
+            mode,
+            start,
+            task: Some(task),
+        }
+    }
+
+    /// Stop reporting progress. If we're drawing a progress bar, draw it one
+    /// last time and leave it on the screen.
+    pub fn finish(self) {
+        if let Some(task) = self.task {
+            task.abort();
+            if self.mode == ProgressMode::Bar {
+                report(self.mode, self.start, true);
+            }
+        }
+    }
+}
+
+/// Print our current progress to standard error.
+fn report(mode: ProgressMode, start: Instant, last: bool) {
+    let snapshot = PROGRESS.snapshot(start.elapsed());
+    let stderr = io::stderr();
+    let mut stderr = stderr.lock();
+    // Ignore errors, because there's nothing useful we can do about them.
+    let _ = match mode {
+        ProgressMode::Bar => write!(
+            stderr,
+            "\r{}\x1b[K{}",
+            snapshot.to_bar(),
+            if last { "\n" } else { "" },
+        ),
+        ProgressMode::Log => writeln!(stderr, "{}", snapshot.to_log_line()),
+        ProgressMode::Auto | ProgressMode::None => Ok(()),
+    };
+    let _ = stderr.flush();
+}
@@ -0,0 +1,272 @@
+//! A shared rate limiter which adapts to throttling by remote services.
+//!
+//! We use AIMD ("additive increase, multiplicative decrease"), the same
+//! strategy TCP uses for congestion control. Every time a remote service
+//! throttles us, we halve our rate and pause all workers until the service's
+//! `Retry-After` time has passed. While requests succeed, we add one batch of
+//! addresses per second back to our rate, up to any limit set by
+//! `--max-addresses-per-second`.
+
+use std::{
+    cmp::{max, min},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use leaky_bucket::RateLimiter;
+use metrics::{counter, describe_counter, describe_gauge, gauge};
+use tokio::time::{sleep_until, Instant};
+use tracing::{trace_span, warn, Instrument};
+
+/// How long should we pause if we're throttled without a `Retry-After` time?
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(2);
+
+/// The slowest rate we'll fall back to, in addresses per second.
+const MIN_RATE: usize = 1;
+
+/// How often should we increase our rate?
+const INCREASE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A rate limiter shared by all our workers, which slows down when a remote
+/// service throttles us, and speeds back up once the throttling stops.
+pub struct AdaptiveRateLimiter {
+    /// The maximum rate requested by the user, in addresses per second.
+    max_rate: Option<usize>,
+
+    /// The number of addresses we geocode at once. We add this many addresses
+    /// per second to our rate every `INCREASE_INTERVAL` without being
+    /// throttled.
+    batch_size: usize,
+
+    /// Our current state.
+    state: Mutex<State>,
+}
+
+/// The mutable state of an `AdaptiveRateLimiter`.
+struct State {
+    /// Our current rate, in addresses per second. If this is `None`, we have
+    /// never been throttled and there is no user-specified limit.
+    rate: Option<usize>,
+
+    /// The `leaky_bucket` limiter enforcing `rate`. We replace this whenever
+    /// our rate changes.
+    limiter: Option<Arc<RateLimiter>>,
+
+    /// Don't allow any requests until this time.
+    paused_until: Option<Instant>,
+
+    /// When were we last throttled?
+    last_throttled: Option<Instant>,
+
+    /// When did we last change our rate?
+    last_changed: Instant,
+
+    /// How many addresses have we allowed through since `last_changed`? We use
+    /// this to estimate our rate if we're throttled without a limit.
+    acquired_since_change: usize,
+}
+
+impl AdaptiveRateLimiter {
+    /// Create a new rate limiter for requests of up to `batch_size`
+    /// addresses. If `max_rate` is specified, we never allow more than that
+    /// many addresses per second.
+    pub fn new(max_rate: Option<usize>, batch_size: usize) -> AdaptiveRateLimiter {
+        describe_counter!(
+            "geocodecsv.throttled_requests.total",
+            "Requests which were throttled by a remote service"
+        );
+        describe_gauge!(
+            "geocodecsv.rate_limit.addresses_per_second",
+            "Current rate limit for remote geocoding services"
+        );
+
+        let max_rate = max_rate.map(|rate| max(rate, MIN_RATE));
+        AdaptiveRateLimiter {
+            max_rate,
+            batch_size,
+            state: Mutex::new(State {
+                rate: max_rate,
+                limiter: max_rate.map(|rate| build_limiter(rate, None, batch_size)),
+                paused_until: None,
+                last_throttled: None,
+                last_changed: Instant::now(),
+                acquired_since_change: 0,
+            }),
+        }
+    }
+
+    /// Our current rate, or `None` if we have no limit.
+    #[cfg(test)]
+    fn rate(&self) -> Option<usize> {
+        self.state.lock().expect("lock poisoned").rate
+    }
+
+    /// Wait until we're allowed to geocode `permits` addresses.
+    pub async fn acquire(&self, permits: usize) {
+        loop {
+            let (paused_until, limiter) = {
+                let state = self.state.lock().expect("lock poisoned");
+                (state.paused_until, state.limiter.clone())
+            };
+            if let Some(paused_until) = paused_until {
+                if paused_until > Instant::now() {
+                    let span = trace_span!("rate_limiter::paused");
+                    sleep_until(paused_until).instrument(span).await;
+                    // Our rate may have changed while we were sleeping.
+                    continue;
+                }
+            }
+            if let Some(limiter) = limiter {
+                let span =
+                    trace_span!("rate_limiter::acquire", permits_needed = permits);
+                limiter.acquire(permits).instrument(span).await;
+            }
+            self.state
+                .lock()
+                .expect("lock poisoned")
+                .acquired_since_change += permits;
+            return;
+        }
+    }
+
+    /// Record that a remote service throttled us, and asked us to wait for
+    /// `retry_after` before trying again. Halves our rate, and pauses all
+    /// workers.
+    pub fn throttled(&self, retry_after: Option<Duration>) {
+        counter!("geocodecsv.throttled_requests.total", 1);
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("lock poisoned");
+
+        // If we didn't have a rate, estimate the rate we were actually
+        // achieving.
+        let current_rate = state.rate.unwrap_or_else(|| {
+            let elapsed = (now - state.last_changed).as_secs_f64().max(1.0);
+            (state.acquired_since_change as f64 / elapsed) as usize
+        });
+        let new_rate = max(current_rate / 2, MIN_RATE);
+        let paused_until = now + retry_after.unwrap_or(DEFAULT_RETRY_AFTER);
+        state.paused_until = Some(
+            state
+                .paused_until
+                .map_or(paused_until, |p| max(p, paused_until)),
+        );
+
+        // Several workers may be throttled at once, but we only want to slow
+        // down once for each burst.
+        if state
+            .last_throttled
+            .is_some_and(|t| now - t < INCREASE_INTERVAL)
+        {
+            return;
+        }
+        state.last_throttled = Some(now);
+        warn!(
+            "throttled by remote service, slowing to {} addresses/second",
+            new_rate,
+        );
+        state.set_rate(new_rate, now, self.batch_size);
+    }
+
+    /// Record a successful request. If it has been long enough since our last
+    /// change, speed up a bit.
+    pub fn succeeded(&self) {
+        let now = Instant::now();
+        let mut state = self.state.lock().expect("lock poisoned");
+        let rate = match state.rate {
+            Some(rate) => rate,
+            None => return,
+        };
+        if now - state.last_changed < INCREASE_INTERVAL {
+            return;
+        }
+        let mut new_rate = rate + self.batch_size;
+        if let Some(max_rate) = self.max_rate {
+            new_rate = min(new_rate, max_rate);
+        }
+        if new_rate != rate {
+            state.set_rate(new_rate, now, self.batch_size);
+        }
+    }
+}
+
+impl State {
+    /// Switch to a new `rate`, for requests of up to `batch_size` addresses.
+    fn set_rate(&mut self, rate: usize, now: Instant, batch_size: usize) {
+        gauge!("geocodecsv.rate_limit.addresses_per_second", rate as f64);
+        let balance = self.limiter.as_ref().map(|limiter| limiter.balance());
+        self.rate = Some(rate);
+        self.limiter = Some(build_limiter(rate, balance, batch_size));
+        self.last_changed = now;
+        self.acquired_since_change = 0;
+    }
+}
+
+/// Build a `leaky_bucket` limiter allowing `rate` addresses per second. If
+/// `balance` is specified, start with that many permits available.
+fn build_limiter(
+    rate: usize,
+    balance: Option<usize>,
+    batch_size: usize,
+) -> Arc<RateLimiter> {
+    // Always allow geocoding at least one full `batch_size` chunk
+    // (eventually). We want to make sure that we can accumulate enough tokens
+    // to geocode a chunk or two, to prevent a situation where we have a chunk
+    // waiting that exceeds our bucket size, blocking it from ever being
+    // geocoded.
+    let max = max(rate, batch_size);
+    Arc::new(
+        RateLimiter::builder()
+            .initial(min(balance.unwrap_or(max), max))
+            // The docs recommend twice our refill rate or our initial value,
+            // whichever is larger.
+            .max(2 * max)
+            .refill(rate)
+            .interval(Duration::from_secs(1))
+            // Since this is all the same geocoding job, don't worry about fair
+            // scheduling between different worker tasks.
+            .fair(false)
+            .build(),
+    )
+}
+
+#[tokio::test(start_paused = true)]
+async fn multiplicative_decrease_and_additive_increase() {
+    use crate::pipeline::DEFAULT_BATCH_SIZE;
+    use tokio::time::advance;
+
+    let limiter = AdaptiveRateLimiter::new(Some(1000), DEFAULT_BATCH_SIZE);
+    assert_eq!(limiter.rate(), Some(1000));
+
+    // Throttling halves our rate, but only once per burst.
+    advance(INCREASE_INTERVAL).await;
+    limiter.throttled(Some(Duration::from_secs(5)));
+    limiter.throttled(None);
+    assert_eq!(limiter.rate(), Some(500));
+
+    // We wait for `Retry-After` before allowing more requests.
+    let start = Instant::now();
+    limiter.acquire(1).await;
+    assert!(Instant::now() - start >= Duration::from_secs(5));
+
+    // Successes speed us up gradually, up to our maximum.
+    limiter.succeeded();
+    assert_eq!(limiter.rate(), Some(500 + DEFAULT_BATCH_SIZE));
+    limiter.succeeded();
+    assert_eq!(limiter.rate(), Some(500 + DEFAULT_BATCH_SIZE));
+    for _ in 0..20 {
+        advance(INCREASE_INTERVAL).await;
+        limiter.succeeded();
+    }
+    assert_eq!(limiter.rate(), Some(1000));
+}
+
+#[tokio::test(start_paused = true)]
+async fn unlimited_until_throttled() {
+    use crate::pipeline::DEFAULT_BATCH_SIZE;
+
+    let limiter = AdaptiveRateLimiter::new(None, DEFAULT_BATCH_SIZE);
+    limiter.succeeded();
+    assert_eq!(limiter.rate(), None);
+    limiter.throttled(None);
+    assert_eq!(limiter.rate(), Some(MIN_RATE));
+}
@@ -0,0 +1,198 @@
+//! Deciding which errors to retry, and how long to wait between retries.
+
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    time::Duration,
+};
+
+use anyhow::Error;
+use bigtable_rs::bigtable;
+use hyper::StatusCode;
+
+use crate::errors::{RemoteStatusError, Throttled};
+
+/// What kind of error is this?
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorClass {
+    /// A remote service asked us to slow down. Our rate limiter will make us
+    /// wait before we try again.
+    Throttled,
+
+    /// A temporary problem, such as a network error or a server error, which
+    /// may go away if we wait and try again.
+    Retryable,
+
+    /// A remote service rejected something in our request, such as a malformed
+    /// address. Retrying won't help, but we may still be able to geocode the
+    /// other addresses in the request without it.
+    BadRequest,
+
+    /// A problem which will never go away by itself, and which affects every
+    /// request, such as bad credentials or an unknown URL.
+    Fatal,
+}
+
+impl ErrorClass {
+    /// Classify `err`. We treat errors we don't recognize as retryable, so
+    /// that we don't give up on anything which might have worked.
+    pub fn of(err: &Error) -> ErrorClass {
+        if err.is::<Throttled>() {
+            ErrorClass::Throttled
+        } else if let Some(err) = err.downcast_ref::<RemoteStatusError>() {
+            match err.status {
+                status if status.is_server_error() => ErrorClass::Retryable,
+                StatusCode::BAD_REQUEST
+                | StatusCode::PAYLOAD_TOO_LARGE
+                | StatusCode::UNPROCESSABLE_ENTITY => ErrorClass::BadRequest,
+                _ => ErrorClass::Fatal,
+            }
+        } else if let Some(err) = err.downcast_ref::<bigtable::Error>() {
+            match err {
+                bigtable::Error::AccessTokenError(_)
+                | bigtable::Error::IoError(_)
+                | bigtable::Error::TransportError(_)
+                | bigtable::Error::RowWriteFailed
+                | bigtable::Error::RpcError(_)
+                | bigtable::Error::TimeoutError(_) => ErrorClass::Retryable,
+                _ => ErrorClass::Fatal,
+            }
+        } else {
+            ErrorClass::Retryable
+        }
+    }
+}
+
+#[test]
+fn classify_errors() {
+    let remote = |status| -> Error {
+        RemoteStatusError {
+            status,
+            body: String::new(),
+        }
+        .into()
+    };
+    let throttled: Error = Throttled {
+        status: StatusCode::TOO_MANY_REQUESTS,
+        retry_after: None,
+    }
+    .into();
+    assert_eq!(ErrorClass::of(&throttled), ErrorClass::Throttled);
+    assert_eq!(
+        ErrorClass::of(&remote(StatusCode::BAD_GATEWAY)),
+        ErrorClass::Retryable,
+    );
+    assert_eq!(
+        ErrorClass::of(&remote(StatusCode::SERVICE_UNAVAILABLE)),
+        ErrorClass::Retryable,
+    );
+    assert_eq!(
+        ErrorClass::of(&remote(StatusCode::UNAUTHORIZED).context("bad credentials")),
+        ErrorClass::Fatal,
+    );
+    assert_eq!(
+        ErrorClass::of(&remote(StatusCode::PAYMENT_REQUIRED)),
+        ErrorClass::Fatal,
+    );
+    assert_eq!(
+        ErrorClass::of(&remote(StatusCode::NOT_FOUND)),
+        ErrorClass::Fatal,
+    );
+    assert_eq!(
+        ErrorClass::of(&remote(StatusCode::UNPROCESSABLE_ENTITY)),
+        ErrorClass::BadRequest,
+    );
+    assert_eq!(
+        ErrorClass::of(&bigtable::Error::TimeoutError(30).into()),
+        ErrorClass::Retryable,
+    );
+    assert_eq!(
+        ErrorClass::of(&anyhow::format_err!("mystery")),
+        ErrorClass::Retryable,
+    );
+}
+
+/// Return a short, low-arity code describing `err`, suitable for an output
+/// column or a metrics label.
+pub fn error_code(err: &Error) -> String {
+    if err.is::<Throttled>() {
+        "throttled".to_owned()
+    } else if let Some(err) = err.downcast_ref::<RemoteStatusError>() {
+        format!("http_{}", err.status.as_u16())
+    } else if err.is::<bigtable::Error>() {
+        "bigtable".to_owned()
+    } else if err.is::<hyper::Error>() {
+        "transport".to_owned()
+    } else {
+        "error".to_owned()
+    }
+}
+
+#[test]
+fn error_codes() {
+    let remote: Error = RemoteStatusError {
+        status: StatusCode::BAD_GATEWAY,
+        body: String::new(),
+    }
+    .into();
+    assert_eq!(error_code(&remote.context("geocoder error")), "http_502");
+    assert_eq!(
+        error_code(&bigtable::Error::TimeoutError(30).into()),
+        "bigtable",
+    );
+    assert_eq!(error_code(&anyhow::format_err!("mystery")), "error");
+}
+
+/// How should we retry failed geocoding requests?
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// How many times should we retry an error which might be temporary?
+    pub max_retries: u8,
+
+    /// How many times should we retry a request which was throttled? These
+    /// don't count against `max_retries`.
+    pub max_throttled_retries: u8,
+
+    /// How long should we wait before our first retry? Each retry waits about
+    /// twice as long as the last.
+    pub initial_wait: Duration,
+
+    /// The longest we should ever wait between retries.
+    pub max_wait: Duration,
+}
+
+impl RetryPolicy {
+    /// How long should we wait before retry number `retry` (starting from 0)?
+    ///
+    /// We add random "jitter", so that workers which failed at the same time
+    /// don't all retry at the same time.
+    pub fn wait_before_retry(&self, retry: u8) -> Duration {
+        let wait = self
+            .initial_wait
+            .saturating_mul(1 << retry.min(31))
+            .min(self.max_wait);
+        wait / 2 + wait.mul_f64(random_fraction() / 2.0)
+    }
+}
+
+#[test]
+fn wait_before_retry_grows_with_jitter() {
+    let policy = RetryPolicy {
+        max_retries: 10,
+        max_throttled_retries: 10,
+        initial_wait: Duration::from_secs(2),
+        max_wait: Duration::from_secs(10),
+    };
+    for (retry, max) in [(0, 2), (1, 4), (2, 8), (3, 10), (50, 10)] {
+        let wait = policy.wait_before_retry(retry);
+        let max = Duration::from_secs(max);
+        assert!(wait >= max / 2 && wait <= max, "{}: {:?}", retry, wait);
+    }
+}
+
+/// Return a random number in the range `[0, 1)`. This isn't a good source of
+/// randomness, but it's good enough for jitter, and it saves us a dependency.
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -5,8 +5,8 @@ use std::iter::FromIterator;
 use std::sync::Arc;
 
 use crate::addresses::Address;
-use crate::geocoders::Geocoded;
 use crate::geocoders::Geocoder;
+use crate::geocoders::{GeocodeResult, Geocoded};
 use anyhow::{format_err, Context, Result};
 use axum::{
     extract::DefaultBodyLimit,
@@ -49,7 +49,7 @@ pub async fn run_server(listen_addr: &str, geocoder: Box<dyn Geocoder>) -> Resul
         // Assumes ~128 addresses at ~128 bytes each. More than this would (1)
         // need to be streamed instead of read into memory, and (2) chunked
         // before passing to the geocoder layer which expects chunks in the
-        // rough size of `[crate::pipeline::GEOCODE_SIZE]`.
+        // rough size of `[crate::pipeline::DEFAULT_BATCH_SIZE]`.
         .layer(DefaultBodyLimit::max(16384));
 
     let listen_addr = listen_addr.parse().with_context(|| {
@@ -106,8 +106,8 @@ async fn handle_post_geocode(
             let response = GeocodeResponse {
                 results: geocoded
                     .into_iter()
-                    .map(|g: Option<Geocoded>| -> Option<HashMap<String, String>> {
-                        g.map(|g| hash_from_geocoded(column_names, &g))
+                    .map(|g: GeocodeResult| -> Option<HashMap<String, String>> {
+                        g.ok().map(|g| hash_from_geocoded(column_names, &g))
                     })
                     .collect(),
             };
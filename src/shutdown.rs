@@ -0,0 +1,114 @@
+//! Graceful shutdown on SIGINT or SIGTERM.
+//!
+//! The first signal asks our input reader to stop. Chunks which have already
+//! been read are geocoded and written as usual, so our output ends on a row
+//! boundary, and any checkpoint records exactly how much input we processed.
+//! A second signal exits immediately.
+
+use std::{
+    process,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::Result;
+
+/// Has somebody asked us to stop?
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Did our input reader stop before the end of its input?
+static STOPPED_READING_EARLY: AtomicBool = AtomicBool::new(false);
+
+/// The exit status we use when we're killed by a second signal. This is the
+/// shell convention for SIGINT.
+const ABORT_EXIT_STATUS: i32 = 130;
+
+/// Has a graceful shutdown been requested?
+pub fn is_requested() -> bool {
+    SHUTDOWN_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Should our input reader stop? If this returns true, the reader must stop,
+/// and we remember that we didn't read all our input.
+pub fn should_stop_reading() -> bool {
+    let requested = is_requested();
+    if requested {
+        STOPPED_READING_EARLY.store(true, Ordering::SeqCst);
+    }
+    requested
+}
+
+/// Did our input reader stop before the end of its input? This may be false
+/// even if a shutdown was requested, if the signal arrived after we had read
+/// everything.
+pub fn stopped_reading_early() -> bool {
+    STOPPED_READING_EARLY.load(Ordering::SeqCst)
+}
+
+/// Listen for signals in the background. This must be called from inside our
+/// `tokio` runtime.
+pub fn handle_signals() -> Result<()> {
+    let mut signals = Signals::new()?;
+    tokio::spawn(async move {
+        signals.recv().await;
+        eprintln!(
+            "geocode-csv: stopping after in-flight rows are written (signal again to abort)"
+        );
+        SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
+
+        signals.recv().await;
+        eprintln!("geocode-csv: aborting");
+        process::exit(ABORT_EXIT_STATUS);
+    });
+    Ok(())
+}
+
+/// The signals which ask us to shut down.
+#[cfg(unix)]
+struct Signals {
+    interrupt: tokio::signal::unix::Signal,
+    terminate: tokio::signal::unix::Signal,
+}
+
+#[cfg(unix)]
+impl Signals {
+    /// Start listening for SIGINT and SIGTERM.
+    fn new() -> Result<Signals> {
+        use anyhow::Context;
+        use tokio::signal::unix::{signal, SignalKind};
+
+        Ok(Signals {
+            interrupt: signal(SignalKind::interrupt())
+                .context("could not listen for SIGINT")?,
+            terminate: signal(SignalKind::terminate())
+                .context("could not listen for SIGTERM")?,
+        })
+    }
+
+    /// Wait for the next signal.
+    async fn recv(&mut self) {
+        tokio::select! {
+            _ = self.interrupt.recv() => {}
+            _ = self.terminate.recv() => {}
+        }
+    }
+}
+
+/// The signals which ask us to shut down.
+#[cfg(not(unix))]
+struct Signals;
+
+#[cfg(not(unix))]
+impl Signals {
+    /// Start listening for Control-C.
+    fn new() -> Result<Signals> {
+        Ok(Signals)
+    }
+
+    /// Wait for the next Control-C.
+    async fn recv(&mut self) {
+        if tokio::signal::ctrl_c().await.is_err() {
+            // We can't listen for Control-C, so never return.
+            futures::future::pending::<()>().await
+        }
+    }
+}
@@ -0,0 +1,273 @@
+//! A machine-readable summary of a geocoding run, for `--summary-json`.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::Serialize;
+
+use crate::geocoders::{GeocodeResult, Unmatched};
+use crate::progress::PROGRESS;
+use crate::shutdown;
+use crate::Result;
+
+/// Statistics for the current job.
+pub static SUMMARY: Summary = Summary::new();
+
+/// Statistics which aren't already tracked by [`PROGRESS`].
+pub struct Summary {
+    /// Statistics for each address prefix.
+    prefixes: Mutex<BTreeMap<String, PrefixSummary>>,
+
+    /// Chunks geocoded successfully, counting each half of a split chunk.
+    chunks: AtomicU64,
+
+    /// Chunks we retried because of a temporary error.
+    chunks_retried: AtomicU64,
+
+    /// Chunks we retried because a remote service throttled us.
+    chunks_throttled: AtomicU64,
+
+    /// Chunks we split in half to find addresses rejected by our geocoder.
+    chunks_split: AtomicU64,
+
+    /// Chunks which failed after all retries.
+    chunks_failed: AtomicU64,
+}
+
+impl Summary {
+    /// Create an empty summary.
+    const fn new() -> Summary {
+        Summary {
+            prefixes: Mutex::new(BTreeMap::new()),
+            chunks: AtomicU64::new(0),
+            chunks_retried: AtomicU64::new(0),
+            chunks_throttled: AtomicU64::new(0),
+            chunks_split: AtomicU64::new(0),
+            chunks_failed: AtomicU64::new(0),
+        }
+    }
+
+    /// Record that we sent a chunk to our geocoder.
+    pub fn chunk(&self) {
+        self.chunks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that we retried a chunk because of a temporary error.
+    pub fn chunk_retried(&self) {
+        self.chunks_retried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that we retried a chunk because we were throttled.
+    pub fn chunk_throttled(&self) {
+        self.chunks_throttled.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that we split a chunk in half.
+    pub fn chunk_split(&self) {
+        self.chunks_split.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that a chunk failed after all retries.
+    pub fn chunk_failed(&self) {
+        self.chunks_failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the results of geocoding addresses for `prefix`.
+    pub fn add_prefix(&self, prefix: &str, summary: PrefixSummary) {
+        let mut prefixes = self.prefixes.lock().expect("lock poisoned");
+        prefixes
+            .entry(prefix.to_owned())
+            .or_default()
+            .merge(summary);
+    }
+
+    /// Build our report.
+    fn report(&self, success: bool, wall_time: Duration) -> Report {
+        let progress = PROGRESS.snapshot(wall_time);
+        let mut prefixes = self.prefixes.lock().expect("lock poisoned").clone();
+        for summary in prefixes.values_mut() {
+            summary.match_rate = if summary.addresses > 0 {
+                summary.found as f64 / summary.addresses as f64
+            } else {
+                0.0
+            };
+        }
+        Report {
+            success,
+            interrupted: shutdown::stopped_reading_early(),
+            wall_time_secs: wall_time.as_secs_f64(),
+            rows: RowsReport {
+                // Don't count rows that an earlier run already processed.
+                read: progress.rows_read - progress.rows_skipped,
+                written: progress.rows_written,
+            },
+            prefixes,
+            cache: CacheReport {
+                hits: progress.cache_hits,
+                misses: progress.cache_lookups - progress.cache_hits,
+            },
+            chunks: ChunksReport {
+                total: self.chunks.load(Ordering::Relaxed),
+                retried: self.chunks_retried.load(Ordering::Relaxed),
+                throttled: self.chunks_throttled.load(Ordering::Relaxed),
+                split: self.chunks_split.load(Ordering::Relaxed),
+                failed: self.chunks_failed.load(Ordering::Relaxed),
+            },
+        }
+    }
+
+    /// Write our report to `path` as JSON.
+    pub fn write_json(
+        &self,
+        path: &Path,
+        success: bool,
+        wall_time: Duration,
+    ) -> Result<()> {
+        let report = self.report(success, wall_time);
+        let mut json = serde_json::to_vec_pretty(&report)?;
+        json.push(b'\n');
+        fs::write(path, json)
+            .with_context(|| format!("cannot write summary to {}", path.display()))
+    }
+}
+
+/// Statistics for the addresses with a single prefix.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PrefixSummary {
+    /// How many addresses did we see?
+    pub addresses: u64,
+
+    /// Addresses which were geocoded successfully.
+    pub found: u64,
+
+    /// Addresses which our geocoder (or our cache) couldn't match.
+    pub unknown: u64,
+
+    /// Addresses which were missing required fields, or which our geocoder
+    /// rejected as invalid.
+    pub invalid: u64,
+
+    /// Addresses which weren't in our cache, with `--cache-hits-only`.
+    pub not_cached: u64,
+
+    /// Addresses which failed because of errors, with `--on-error=continue`.
+    pub errors: u64,
+
+    /// `found / addresses`. Only filled in when we write our report.
+    pub match_rate: f64,
+
+    /// How many found addresses had each value of Smarty's `precision`
+    /// field, if we're outputting it.
+    pub precision: BTreeMap<String, u64>,
+}
+
+impl PrefixSummary {
+    /// Record `result`. If `precision_idx` is specified, it's the position of
+    /// the `precision` column in our geocoder output.
+    pub fn add(&mut self, result: &GeocodeResult, precision_idx: Option<usize>) {
+        self.addresses += 1;
+        match result {
+            Ok(geocoded) => {
+                self.found += 1;
+                let precision = precision_idx
+                    .and_then(|idx| geocoded.column_values.get(idx))
+                    .filter(|precision| !precision.is_empty());
+                if let Some(precision) = precision {
+                    *self.precision.entry(precision.to_owned()).or_default() += 1;
+                }
+            }
+            Err(Unmatched::NoMatch | Unmatched::CachedUnknown) => self.unknown += 1,
+            Err(Unmatched::InvalidRecord | Unmatched::RejectedByGeocoder) => {
+                self.invalid += 1
+            }
+            Err(Unmatched::NotCached) => self.not_cached += 1,
+            Err(Unmatched::GeocoderError) => self.errors += 1,
+        }
+    }
+
+    /// Add the counts in `other` to ours.
+    fn merge(&mut self, other: PrefixSummary) {
+        self.addresses += other.addresses;
+        self.found += other.found;
+        self.unknown += other.unknown;
+        self.invalid += other.invalid;
+        self.not_cached += other.not_cached;
+        self.errors += other.errors;
+        for (precision, count) in other.precision {
+            *self.precision.entry(precision).or_default() += count;
+        }
+    }
+}
+
+#[test]
+fn summarize_prefix() {
+    use crate::geocoders::Geocoded;
+
+    let found = |precision: &str| -> GeocodeResult {
+        Ok(Geocoded {
+            column_values: vec!["1.0".to_owned(), precision.to_owned()],
+        })
+    };
+    let mut summary = PrefixSummary::default();
+    summary.add(&found("Zip9"), Some(1));
+    summary.add(&Err(Unmatched::NoMatch), Some(1));
+    let mut other = PrefixSummary::default();
+    other.add(&found("Zip9"), Some(1));
+    other.add(&found(""), Some(1));
+    other.add(&Err(Unmatched::InvalidRecord), Some(1));
+    summary.merge(other);
+
+    assert_eq!(summary.addresses, 5);
+    assert_eq!(summary.found, 3);
+    assert_eq!(summary.unknown, 1);
+    assert_eq!(summary.invalid, 1);
+    assert_eq!(summary.precision.get("Zip9"), Some(&2));
+    assert_eq!(summary.precision.len(), 1);
+}
+
+/// Our `--summary-json` output.
+#[derive(Debug, Serialize)]
+struct Report {
+    /// Did the run succeed?
+    success: bool,
+    /// Did a signal stop us before we read all our input?
+    interrupted: bool,
+    wall_time_secs: f64,
+    rows: RowsReport,
+    prefixes: BTreeMap<String, PrefixSummary>,
+    cache: CacheReport,
+    chunks: ChunksReport,
+}
+
+/// Row counts for our `--summary-json` output.
+#[derive(Debug, Serialize)]
+struct RowsReport {
+    read: u64,
+    written: u64,
+}
+
+/// Cache statistics for our `--summary-json` output.
+#[derive(Debug, Serialize)]
+struct CacheReport {
+    hits: u64,
+    misses: u64,
+}
+
+/// Chunk statistics for our `--summary-json` output.
+#[derive(Debug, Serialize)]
+struct ChunksReport {
+    total: u64,
+    retried: u64,
+    throttled: u64,
+    split: u64,
+    failed: u64,
+}
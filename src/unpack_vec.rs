@@ -6,18 +6,18 @@ use crate::Result;
 
 /// Given a vector `input`, an expected output vector length `output_len` and a
 /// function `idx_fn` that maps input values to output indices, assemble an
-/// output vector with each element at the right index, or `None` if no element
-/// is found for an index.
+/// output vector with the elements for each index, in their original order.
+/// Indices with no elements get an empty vector.
 pub fn unpack_vec<T, F>(
     input: Vec<T>,
     output_len: usize,
     idx_fn: F,
-) -> Result<Vec<Option<T>>>
+) -> Result<Vec<Vec<T>>>
 where
     F: Fn(&T) -> usize,
-    T: Clone,
 {
-    let mut output = vec![None; output_len];
+    let mut output = Vec::with_capacity(output_len);
+    output.resize_with(output_len, Vec::new);
     for value in input {
         let idx = idx_fn(&value);
         if idx >= output_len {
@@ -26,10 +26,8 @@ where
                 output_len,
                 idx,
             ));
-        } else if output[idx].is_some() {
-            return Err(format_err!("index {} appears twice", idx));
         }
-        output[idx] = Some(value);
+        output[idx].push(value);
     }
     Ok(output)
 }
@@ -38,7 +36,7 @@ where
 fn unpack_to_correct_index() {
     assert_eq!(
         unpack_vec(vec![2, 4, 5], 7, |v| *v).unwrap(),
-        vec![None, None, Some(2), None, Some(4), Some(5), None],
+        vec![vec![], vec![], vec![2], vec![], vec![4], vec![5], vec![]],
     );
 }
 
@@ -48,6 +46,9 @@ fn error_if_output_too_short() {
 }
 
 #[test]
-fn error_on_duplicate_indices() {
-    assert!(unpack_vec(vec![0, 0], 2, |v| *v).is_err());
+fn group_duplicate_indices() {
+    assert_eq!(
+        unpack_vec(vec![(0, "a"), (1, "b"), (0, "c")], 2, |v| v.0).unwrap(),
+        vec![vec![(0, "a"), (0, "c")], vec![(1, "b")]],
+    );
 }
@@ -0,0 +1,54 @@
+//! Handling malformed input rows.
+
+use cli_test_dir::*;
+
+/// A CSV file with a row containing too many fields.
+const BAD_CSV: &str = "address,zip
+20 W 34th St,10118
+oops,10118,extra
+1224 S 760 W,84601
+";
+
+/// A spec file to use for our tests.
+const SIMPLE_SPEC: &str = r#"{
+    "gc": {
+        "house_number_and_street": "address",
+        "postcode": "zip"
+    }
+}"#;
+
+#[test]
+#[ignore]
+fn bad_rows_fail_by_default() {
+    let testdir = TestDir::new("geocode-csv", "bad_rows_fail_by_default");
+
+    testdir.create_file("spec.json", SIMPLE_SPEC);
+    let output = testdir
+        .cmd()
+        .arg("--geocoder=libpostal")
+        .arg("--spec=spec.json")
+        .output_with_stdin(BAD_CSV)
+        .expect("could not run geocode-csv");
+    assert!(!output.status.success());
+}
+
+#[test]
+#[ignore]
+fn bad_rows_quarantine() {
+    let testdir = TestDir::new("geocode-csv", "bad_rows_quarantine");
+
+    testdir.create_file("spec.json", SIMPLE_SPEC);
+    let output = testdir
+        .cmd()
+        .arg("--geocoder=libpostal")
+        .arg("--spec=spec.json")
+        .arg("--on-bad-row=quarantine")
+        .arg("--quarantine-path=bad.csv")
+        .output_with_stdin(BAD_CSV)
+        .expect_success();
+    assert!(output.stdout_str().contains("10118"));
+    assert!(output.stdout_str().contains("84601"));
+    assert!(!output.stdout_str().contains("oops"));
+    testdir.expect_contains("bad.csv", "3,");
+    testdir.expect_contains("bad.csv", "oops,10118,extra");
+}
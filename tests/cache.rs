@@ -0,0 +1,320 @@
+//! Testing our caches and cache administration, using a local mock Smarty
+//! server.
+
+use std::process::Command;
+
+use cli_test_dir::*;
+
+mod mock_smarty;
+
+use mock_smarty::MockSmarty;
+
+/// A CSV file to geocode. Contains one address with two candidates, one with
+/// a single candidate, and one which Smarty doesn't know about.
+const SIMPLE_CSV: &str = "address,city,state
+20 W 34th St,New York,NY
+1 Main St,Springfield,
+123 Nowhere Ln,Nowhere,ZZ
+";
+
+/// A spec file to use for our tests.
+const SIMPLE_SPEC: &str = r#"{
+    "gc": {
+        "house_number_and_street": "address",
+        "city": "city",
+        "state": "state"
+    }
+}"#;
+
+/// Build a command which geocodes using `mock`.
+fn smarty_cmd(testdir: &TestDir, mock: &MockSmarty) -> Command {
+    testdir.create_file("spec.json", SIMPLE_SPEC);
+    let mut cmd = testdir.cmd();
+    cmd.env("SMARTY_AUTH_ID", mock_smarty::AUTH_ID)
+        .env("SMARTY_AUTH_TOKEN", mock_smarty::AUTH_TOKEN)
+        .arg("--spec=spec.json")
+        .arg(format!("--smarty-url={}", mock.url()));
+    cmd
+}
+
+/// Parse CSV `output`, and return the values of `column` for each row.
+fn column_values(output: &str, column: &str) -> Vec<String> {
+    let mut rdr = csv::Reader::from_reader(output.as_bytes());
+    let idx = rdr
+        .headers()
+        .unwrap()
+        .iter()
+        .position(|h| h == column)
+        .unwrap_or_else(|| panic!("no column {:?}", column));
+    rdr.records()
+        .map(|row| row.unwrap()[idx].to_owned())
+        .collect()
+}
+
+#[test]
+fn sqlite_cache() {
+    let testdir = TestDir::new("geocode-csv", "sqlite_cache");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    // Our first run should fill the cache, and our second run should find
+    // everything there.
+    for _ in 0..2 {
+        let output = smarty_cmd(&testdir, &mock)
+            .arg("--cache=sqlite:cache.db")
+            .output_with_stdin(SIMPLE_CSV)
+            .expect_success();
+        assert_eq!(
+            column_values(output.stdout_str(), "gc_latitude"),
+            &["40.74842", "39.80172", ""],
+        );
+        assert_eq!(mock.request_count(), 1);
+    }
+}
+
+#[test]
+fn no_cache_unknown() {
+    let testdir = TestDir::new("geocode-csv", "no_cache_unknown");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    // Our second run should only look up the address we couldn't geocode.
+    for expected_requests in [1, 2] {
+        smarty_cmd(&testdir, &mock)
+            .args(["--cache=sqlite:cache.db", "--no-cache-unknown"])
+            .arg("--cache-ttl=3600")
+            .output_with_stdin(SIMPLE_CSV)
+            .expect_success();
+        assert_eq!(mock.request_count(), expected_requests);
+    }
+}
+
+#[test]
+fn cascade_cache_tier() {
+    let testdir = TestDir::new("geocode-csv", "cascade_cache_tier");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    // Cache our first address using Smarty on its own.
+    smarty_cmd(&testdir, &mock)
+        .arg("--cache=sqlite:cache.db")
+        .output_with_stdin("address,city,state\n20 W 34th St,New York,NY\n")
+        .expect_success();
+    assert_eq!(mock.request_count(), 1);
+
+    // Our cache tier should find our first address without calling Smarty.
+    let output = smarty_cmd(&testdir, &mock)
+        .arg("--cache=sqlite:cache.db")
+        .args(["--geocoder=cache", "--fallback-geocoder=smarty"])
+        .arg("--summary-json=summary.json")
+        .output_with_stdin(SIMPLE_CSV)
+        .expect_success();
+    let stdout = output.stdout_str();
+    assert_eq!(
+        column_values(stdout, "gc_latitude"),
+        &["40.74842", "39.80172", ""],
+    );
+    assert_eq!(column_values(stdout, "gc_source"), &["cache", "smarty", ""]);
+    assert_eq!(mock.request_count(), 2);
+    let summary: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string(testdir.path("summary.json")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(summary["prefixes"]["gc"]["precision"]["Zip9"], 2);
+
+    // Our Smarty tier shouldn't count its lookups of the same keys again.
+    assert_eq!(summary["cache"]["hits"], 1);
+    assert_eq!(summary["cache"]["misses"], 2);
+
+    // Our cache tier shouldn't need Smarty credentials. Since every address
+    // is cached, we never load libpostal.
+    let output = testdir
+        .cmd()
+        .env_remove("SMARTY_AUTH_ID")
+        .env_remove("SMARTY_AUTH_TOKEN")
+        .args(["--spec=spec.json", "--cache=sqlite:cache.db"])
+        .args(["--geocoder=cache", "--fallback-geocoder=libpostal"])
+        .output_with_stdin("address,city,state\n20 W 34th St,New York,NY\n")
+        .expect_success();
+    assert_eq!(column_values(output.stdout_str(), "gc_source"), &["cache"],);
+
+    // Our Smarty tier should have cached its results under the same keys as
+    // Smarty on its own.
+    let output = smarty_cmd(&testdir, &mock)
+        .args(["--cache=sqlite:cache.db", "--cache-hits-only"])
+        .output_with_stdin(SIMPLE_CSV)
+        .expect_success();
+    assert_eq!(
+        column_values(output.stdout_str(), "gc_latitude"),
+        &["40.74842", "39.80172", ""],
+    );
+    assert_eq!(mock.request_count(), 2);
+}
+
+#[test]
+#[ignore]
+fn cascade_cache_libpostal_smarty() {
+    let testdir = TestDir::new("geocode-csv", "cascade_cache_libpostal_smarty");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    // Cache our first address using Smarty on its own.
+    smarty_cmd(&testdir, &mock)
+        .arg("--cache=sqlite:cache.db")
+        .output_with_stdin("address,city,state\n20 W 34th St,New York,NY\n")
+        .expect_success();
+    assert_eq!(mock.request_count(), 1);
+
+    // libpostal should only match the complete address, leaving the one
+    // without a state for Smarty.
+    let output = smarty_cmd(&testdir, &mock)
+        .arg("--cache=sqlite:cache.db")
+        .arg("--geocoder=cache")
+        .args([
+            "--fallback-geocoder=libpostal",
+            "--fallback-geocoder=smarty",
+        ])
+        .output_with_stdin(
+            "address,city,state
+20 W 34th St,New York,NY
+1 Main St,Springfield,
+1224 S 760 W,Provo,UT
+",
+        )
+        .expect_success();
+    let stdout = output.stdout_str();
+    assert_eq!(
+        column_values(stdout, "gc_source"),
+        &["cache", "smarty", "libpostal"],
+    );
+    assert_eq!(
+        column_values(stdout, "gc_latitude"),
+        &["40.74842", "39.80172", ""],
+    );
+    assert_eq!(column_values(stdout, "gc_city")[2], "provo");
+    assert_eq!(mock.request_count(), 2);
+}
+
+#[test]
+fn cache_admin() {
+    let testdir = TestDir::new("geocode-csv", "cache_admin");
+    let mock = MockSmarty::start("addresses.json", 0);
+    let geocode = || {
+        smarty_cmd(&testdir, &mock)
+            .arg("--cache=sqlite:cache.db")
+            .output_with_stdin(SIMPLE_CSV)
+            .expect_success();
+    };
+    let cache_cmd = |args: &[&str]| {
+        testdir
+            .cmd()
+            .arg("--cache=sqlite:cache.db")
+            .arg("cache")
+            .args(args)
+            .output()
+            .expect_success()
+    };
+    let stats = || cache_cmd(&["stats"]).stdout_str().to_owned();
+
+    // Fill our cache, and check what's in it.
+    geocode();
+    let stats_before = stats();
+    assert_eq!(column_values(&stats_before, "keys"), &["3"]);
+    assert_eq!(column_values(&stats_before, "found"), &["2"]);
+    assert_eq!(column_values(&stats_before, "unknown"), &["1"]);
+    let prefix = column_values(&stats_before, "prefix").remove(0);
+    assert!(prefix.starts_with("sm:"));
+
+    // Export our entries.
+    cache_cmd(&["export", "--output=cache.ndjson"]);
+    testdir.expect_contains("cache.ndjson", r#""column_values":null"#);
+    testdir.expect_contains("cache.ndjson", "40.74842");
+
+    // Invalidate a single address.
+    testdir.create_file(
+        "addresses.csv",
+        "address,city,state\n123 Nowhere Ln,Nowhere,ZZ\n",
+    );
+    testdir
+        .cmd()
+        .args(["--spec=spec.json", "--cache=sqlite:cache.db", "cache"])
+        .args(["invalidate", "--addresses=addresses.csv"])
+        .arg(format!("--prefix={}", prefix))
+        .output()
+        .expect_success();
+    let stats_after = stats();
+    assert_eq!(column_values(&stats_after, "keys"), &["2"]);
+    assert_eq!(column_values(&stats_after, "unknown"), &["0"]);
+
+    // Invalidate everything for our prefix, which forces us to geocode again.
+    cache_cmd(&["invalidate", &format!("--prefix={}", prefix)]);
+    assert_eq!(column_values(&stats(), "keys"), Vec::<String>::new());
+    geocode();
+    assert_eq!(mock.request_count(), 2);
+
+    // Import our exported entries, and make sure they're used.
+    cache_cmd(&["invalidate", &format!("--prefix={}", prefix)]);
+    cache_cmd(&["import", "--input=cache.ndjson"]);
+    assert_eq!(stats(), stats_before);
+    geocode();
+    assert_eq!(mock.request_count(), 2);
+}
+
+#[test]
+fn cache_invalidate_uses_input_options() {
+    let testdir = TestDir::new("geocode-csv", "cache_invalidate_uses_input_options");
+    let mock = MockSmarty::start("addresses.json", 0);
+    smarty_cmd(&testdir, &mock)
+        .arg("--cache=sqlite:cache.db")
+        .output_with_stdin(SIMPLE_CSV)
+        .expect_success();
+    let output = testdir
+        .cmd()
+        .args(["--cache=sqlite:cache.db", "cache", "stats"])
+        .output()
+        .expect_success();
+    let prefix = column_values(output.stdout_str(), "prefix").remove(0);
+
+    // Our addresses file uses the same CSV options as our geocoding input.
+    testdir.create_file(
+        "addresses.csv",
+        "address;city;state\n20 W 34th St;New York;NY\n",
+    );
+    testdir
+        .cmd()
+        .args([
+            "--spec=spec.json",
+            "--cache=sqlite:cache.db",
+            "--delimiter=;",
+        ])
+        .args(["cache", "invalidate", "--addresses=addresses.csv"])
+        .arg(format!("--prefix={}", prefix))
+        .output()
+        .expect_success();
+    let output = testdir
+        .cmd()
+        .args(["--cache=sqlite:cache.db", "cache", "stats"])
+        .output()
+        .expect_success();
+    assert_eq!(column_values(output.stdout_str(), "keys"), &["2"]);
+    assert_eq!(column_values(output.stdout_str(), "found"), &["1"]);
+}
+
+#[test]
+fn memory_cache() {
+    let testdir = TestDir::new("geocode-csv", "memory_cache");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    // Repeated addresses should only be geocoded once, even when they're in
+    // different chunks.
+    let mut input = "address,city,state\n".to_owned();
+    for _ in 0..10 {
+        input.push_str("20 W 34th St,New York,NY\n1 Main St,Springfield,\n");
+    }
+    let output = smarty_cmd(&testdir, &mock)
+        .args(["--batch-size=4", "--memory-cache-mb=1"])
+        .output_with_stdin(&input)
+        .expect_success();
+    let latitudes = column_values(output.stdout_str(), "gc_latitude");
+    assert_eq!(latitudes.len(), 20);
+    assert!(latitudes
+        .chunks(2)
+        .all(|pair| pair == ["40.74842", "39.80172"]));
+    assert_eq!(mock.request_count(), 1);
+}
@@ -0,0 +1,84 @@
+//! Resuming interrupted runs.
+
+use std::{fs, io::Write};
+
+use cli_test_dir::*;
+
+/// A simple CSV file.
+const SIMPLE_CSV: &str = "address,zip
+20 W 34th St,10118
+1224 S 760 W,84601
+";
+
+/// A spec file to use for our tests.
+const SIMPLE_SPEC: &str = r#"{
+    "gc": {
+        "house_number_and_street": "address",
+        "postcode": "zip"
+    }
+}"#;
+
+#[test]
+#[ignore]
+fn resume_discards_output_after_checkpoint() {
+    let testdir =
+        TestDir::new("geocode-csv", "resume_discards_output_after_checkpoint");
+
+    testdir.create_file("spec.json", SIMPLE_SPEC);
+    testdir.create_file("in.csv", SIMPLE_CSV);
+    let run = |resume: bool| {
+        let mut cmd = testdir.cmd();
+        cmd.arg("--geocoder=libpostal")
+            .arg("--spec=spec.json")
+            .arg("--input=in.csv")
+            .arg("--output=out.csv")
+            .arg("--checkpoint=checkpoint.json")
+            .arg("--checkpoint-every=1");
+        if resume {
+            cmd.arg("--resume");
+        }
+        cmd.expect_success();
+    };
+    run(false);
+    let expected = fs::read(testdir.path("out.csv")).unwrap();
+
+    // Pretend we were interrupted after writing a partial row.
+    fs::OpenOptions::new()
+        .append(true)
+        .open(testdir.path("out.csv"))
+        .unwrap()
+        .write_all(b"partial")
+        .unwrap();
+    run(true);
+    assert_eq!(fs::read(testdir.path("out.csv")).unwrap(), expected);
+    testdir.expect_contains("checkpoint.json", r#""input_rows":2"#);
+}
+
+#[test]
+#[ignore]
+fn resume_requires_matching_options() {
+    let testdir = TestDir::new("geocode-csv", "resume_requires_matching_options");
+
+    testdir.create_file("spec.json", SIMPLE_SPEC);
+    testdir.create_file("in.csv", SIMPLE_CSV);
+    testdir
+        .cmd()
+        .arg("--geocoder=libpostal")
+        .arg("--spec=spec.json")
+        .arg("--input=in.csv")
+        .arg("--output=out.csv")
+        .arg("--checkpoint=checkpoint.json")
+        .expect_success();
+    let output = testdir
+        .cmd()
+        .arg("--geocoder=libpostal")
+        .arg("--spec=spec.json")
+        .arg("--input=in.csv")
+        .arg("--output=out.csv")
+        .arg("--duplicate-columns=replace")
+        .arg("--checkpoint=checkpoint.json")
+        .arg("--resume")
+        .output()
+        .expect("could not run geocode-csv");
+    assert!(!output.status.success());
+}
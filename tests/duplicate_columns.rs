@@ -1,7 +1,13 @@
 //! Specifying how to handle duplicate columns.
 
+use std::process::Command;
+
 use cli_test_dir::*;
 
+mod mock_smarty;
+
+use mock_smarty::MockSmarty;
+
 /// A CSV file to use for our tests.
 const SIMPLE_CSV: &str = r#"address,gc_addressee,zip
 20 W 34th St,,10118
@@ -15,14 +21,22 @@ const SIMPLE_SPEC: &str = r#"{
     }
 }"#;
 
+/// Create a command which geocodes using our mock Smarty server.
+fn smarty_cmd(testdir: &TestDir, mock: &MockSmarty) -> Command {
+    let mut cmd = testdir.cmd();
+    cmd.env("SMARTY_AUTH_ID", mock_smarty::AUTH_ID)
+        .env("SMARTY_AUTH_TOKEN", mock_smarty::AUTH_TOKEN)
+        .arg(format!("--smarty-url={}", mock.url()));
+    cmd
+}
+
 #[test]
-#[ignore]
 fn duplicate_columns_error() {
     let testdir = TestDir::new("geocode-csv", "duplicate_columns_error");
 
     testdir.create_file("spec.json", SIMPLE_SPEC);
-    let output = testdir
-        .cmd()
+    let mock = MockSmarty::start("addresses.json", 0);
+    let output = smarty_cmd(&testdir, &mock)
         .arg("--license=us-core-enterprise-cloud")
         .arg("--spec=spec.json")
         .arg("--duplicate-columns=error")
@@ -30,16 +44,16 @@ fn duplicate_columns_error() {
         .expect("could not run geocode-csv");
 
     assert!(!output.status.success());
+    assert_eq!(mock.request_count(), 0);
 }
 
 #[test]
-#[ignore]
 fn duplicate_columns_replace() {
     let testdir = TestDir::new("geocode-csv", "duplicate_columns_replace");
 
     testdir.create_file("spec.json", SIMPLE_SPEC);
-    let output = testdir
-        .cmd()
+    let mock = MockSmarty::start("addresses.json", 0);
+    let output = smarty_cmd(&testdir, &mock)
         .arg("--license=us-core-enterprise-cloud")
         .arg("--spec=spec.json")
         .arg("--duplicate-columns=replace")
@@ -51,13 +65,12 @@ fn duplicate_columns_replace() {
 }
 
 #[test]
-#[ignore]
 fn duplicate_columns_append() {
     let testdir = TestDir::new("geocode-csv", "duplicate_columns_append");
 
     testdir.create_file("spec.json", SIMPLE_SPEC);
-    let output = testdir
-        .cmd()
+    let mock = MockSmarty::start("addresses.json", 0);
+    let output = smarty_cmd(&testdir, &mock)
         .arg("--license=us-core-enterprise-cloud")
         .arg("--spec=spec.json")
         .arg("--duplicate-columns=append")
@@ -0,0 +1,93 @@
+//! Reading and writing different file formats.
+
+use cli_test_dir::*;
+
+/// A CSV file to geocode.
+const SIMPLE_CSV: &str = "address_1,city,state,zip_code
+20 W 34th St,New York,NY,10118
+";
+
+/// A spec file to use for our tests.
+const SIMPLE_SPEC: &str = r#"{
+    "gc": {
+        "house_number_and_street": "address_1",
+        "city": "city",
+        "state": "state",
+        "postcode": "zip_code"
+    }
+}"#;
+
+#[test]
+#[ignore]
+fn parquet_round_trip() {
+    let testdir = TestDir::new("geocode-csv", "parquet_round_trip");
+
+    testdir.create_file("spec.json", SIMPLE_SPEC);
+    testdir.create_file("in.csv", SIMPLE_CSV);
+    testdir
+        .cmd()
+        .arg("--geocoder=libpostal")
+        .arg("--spec=spec.json")
+        .arg("--input=in.csv")
+        .arg("--output=out.parquet")
+        .expect_success();
+
+    // Read our Parquet file back in, and convert it to CSV.
+    testdir.create_file("spec2.json", SIMPLE_SPEC.replace("\"gc\"", "\"gc2\""));
+    let output = testdir
+        .cmd()
+        .arg("--geocoder=libpostal")
+        .arg("--spec=spec2.json")
+        .arg("--input=out.parquet")
+        .expect_success();
+    assert!(output.stdout_str().contains("gc_city"));
+    assert!(output.stdout_str().contains("gc2_city"));
+    assert!(output.stdout_str().contains("new york"));
+}
+
+#[test]
+#[ignore]
+fn json_lines() {
+    let testdir = TestDir::new("geocode-csv", "json_lines");
+
+    testdir.create_file("spec.json", SIMPLE_SPEC);
+    testdir.create_file(
+        "in.jsonl",
+        r#"{"id": 1, "address_1": "20 W 34th St", "city": "New York", "state": "NY", "zip_code": "10118"}
+"#,
+    );
+    let output = testdir
+        .cmd()
+        .arg("--geocoder=libpostal")
+        .arg("--spec=spec.json")
+        .arg("--input=in.jsonl")
+        .arg("--output-format=jsonl")
+        .expect_success();
+    let line = output.stdout_str().lines().next().unwrap();
+    let object: serde_json::Value = serde_json::from_str(line).unwrap();
+    assert_eq!(object["id"], 1);
+    assert_eq!(object["gc"]["city"], "new york");
+}
+
+#[test]
+#[ignore]
+fn headerless_tsv_in_windows_1252() {
+    let testdir = TestDir::new("geocode-csv", "headerless_tsv_in_windows_1252");
+
+    testdir.create_file("spec.json", r#"{"gc": {"address": 0, "postcode": 1}}"#);
+    std::fs::write(testdir.path("in.tsv"), b"20 W 34th St\t10118\tCaf\xe9\n").unwrap();
+    let output = testdir
+        .cmd()
+        .arg("--geocoder=libpostal")
+        .arg("--spec=spec.json")
+        .arg("--input=in.tsv")
+        .arg("--delimiter=tab")
+        .arg("--no-headers")
+        .arg("--encoding=windows-1252")
+        .arg("--output-delimiter=|")
+        .expect_success();
+    assert!(output
+        .stdout_str()
+        .starts_with("column_0|column_1|column_2|gc_"));
+    assert!(output.stdout_str().contains("|Café|"));
+}
@@ -0,0 +1,163 @@
+//! A mock Smarty server for testing without credentials.
+//!
+//! The mock answers street address requests using a fixture file in
+//! `tests/fixtures/smarty`. Each key in the fixture is a street, and each
+//! value is either a list of candidates, or an object with a `status` and a
+//! `body`, which will be returned in place of a normal response. Streets which
+//! don't appear in the fixture have no candidates.
+
+use std::{
+    fs,
+    net::TcpListener,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+use axum::{
+    extract::State,
+    http::{header::RETRY_AFTER, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    routing::post,
+    Json, Router,
+};
+use serde_json::{Map, Value};
+
+/// Credentials to pass to `geocode-csv` when using our mock server.
+pub const AUTH_ID: &str = "mock-auth-id";
+pub const AUTH_TOKEN: &str = "mock-auth-token";
+
+/// State shared by our request handlers.
+struct MockState {
+    /// Our fixture, mapping streets to responses.
+    fixture: Map<String, Value>,
+
+    /// How many requests should we reject with 429 Too Many Requests before
+    /// answering normally? We ask clients to retry after 1 second.
+    rate_limited: usize,
+
+    /// How many requests have we received?
+    requests: AtomicUsize,
+}
+
+/// A running mock Smarty server. The server runs on a background thread until
+/// the test process exits.
+pub struct MockSmarty {
+    url: String,
+    state: Arc<MockState>,
+}
+
+impl MockSmarty {
+    /// Start a mock server which answers using `fixture_name`, after rejecting
+    /// the first `rate_limited` requests.
+    pub fn start(fixture_name: &str, rate_limited: usize) -> MockSmarty {
+        let fixture_path = format!(
+            "{}/tests/fixtures/smarty/{}",
+            env!("CARGO_MANIFEST_DIR"),
+            fixture_name,
+        );
+        let fixture = serde_json::from_slice(
+            &fs::read(&fixture_path).expect("could not read fixture"),
+        )
+        .expect("could not parse fixture");
+        let state = Arc::new(MockState {
+            fixture,
+            rate_limited,
+            requests: AtomicUsize::new(0),
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").expect("could not bind");
+        let url = format!(
+            "http://{}/street-address",
+            listener.local_addr().expect("no local address"),
+        );
+        let app = Router::new()
+            .route("/street-address", post(street_address))
+            .with_state(state.clone());
+        thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("could not create runtime");
+            runtime.block_on(async move {
+                axum::Server::from_tcp(listener)
+                    .expect("could not start server")
+                    .serve(app.into_make_service())
+                    .await
+                    .expect("server failed");
+            });
+        });
+        MockSmarty { url, state }
+    }
+
+    /// The URL to pass to `--smarty-url`.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// How many requests have we received?
+    pub fn request_count(&self) -> usize {
+        self.state.requests.load(Ordering::SeqCst)
+    }
+}
+
+/// Answer a batch of street address requests.
+async fn street_address(
+    State(state): State<Arc<MockState>>,
+    uri: Uri,
+    Json(requests): Json<Vec<Value>>,
+) -> Response {
+    let request_idx = state.requests.fetch_add(1, Ordering::SeqCst);
+    if request_idx < state.rate_limited {
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, "1")],
+            "Too Many Requests",
+        )
+            .into_response();
+    }
+
+    // Check our credentials.
+    let query = uri.query().unwrap_or("");
+    let mut auth_id = None;
+    let mut auth_token = None;
+    for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match &key[..] {
+            "auth-id" => auth_id = Some(value.into_owned()),
+            "auth-token" => auth_token = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    if auth_id.as_deref() != Some(AUTH_ID) || auth_token.as_deref() != Some(AUTH_TOKEN)
+    {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    // Look up each request in our fixture.
+    let mut candidates = vec![];
+    for (input_index, request) in requests.iter().enumerate() {
+        let street = request["street"].as_str().unwrap_or("");
+        let max_candidates = request["candidates"].as_u64().unwrap_or(1) as usize;
+        match state.fixture.get(street) {
+            Some(Value::Array(fixture_candidates)) => {
+                for candidate in fixture_candidates.iter().take(max_candidates) {
+                    let mut candidate = candidate.clone();
+                    candidate["input_index"] = input_index.into();
+                    candidates.push(candidate);
+                }
+            }
+            Some(error @ Value::Object(_)) => {
+                let status = error["status"]
+                    .as_u64()
+                    .and_then(|s| StatusCode::from_u16(s as u16).ok())
+                    .expect("fixture error should have a status");
+                return (status, Json(error["body"].clone())).into_response();
+            }
+            Some(other) => panic!("unexpected fixture value: {:?}", other),
+            None => {}
+        }
+    }
+    Json(candidates).into_response()
+}
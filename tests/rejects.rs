@@ -0,0 +1,38 @@
+//! Writing rows we couldn't geocode to a rejects file.
+
+use cli_test_dir::*;
+
+/// A CSV file with a row that has no address.
+const CSV_WITH_BLANK_ADDRESS: &str = "address,zip
+20 W 34th St,10118
+,
+1224 S 760 W,84601
+";
+
+/// A spec file to use for our tests.
+const SIMPLE_SPEC: &str = r#"{
+    "gc": {
+        "house_number_and_street": "address",
+        "postcode": "zip"
+    }
+}"#;
+
+#[test]
+#[ignore]
+fn rejects_path() {
+    let testdir = TestDir::new("geocode-csv", "rejects_path");
+
+    testdir.create_file("spec.json", SIMPLE_SPEC);
+    let output = testdir
+        .cmd()
+        .arg("--geocoder=libpostal")
+        .arg("--spec=spec.json")
+        .arg("--rejects-path=rejects.csv")
+        .output_with_stdin(CSV_WITH_BLANK_ADDRESS)
+        .expect_success();
+    assert!(output.stdout_str().contains("10118"));
+    testdir.expect_file_contents(
+        "rejects.csv",
+        "address,zip,geocode_prefix,geocode_failure\n,,gc,invalid_record\n",
+    );
+}
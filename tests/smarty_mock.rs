@@ -0,0 +1,416 @@
+//! Testing the Smarty geocoder against a local mock server.
+
+use std::process::Command;
+
+use cli_test_dir::*;
+
+mod mock_smarty;
+
+use mock_smarty::MockSmarty;
+
+/// A CSV file to geocode. Contains one address with two candidates, one with
+/// a single candidate, and one which Smarty doesn't know about.
+const SIMPLE_CSV: &str = "address,city,state
+20 W 34th St,New York,NY
+1 Main St,Springfield,
+123 Nowhere Ln,Nowhere,ZZ
+";
+
+/// A spec file to use for our tests.
+const SIMPLE_SPEC: &str = r#"{
+    "gc": {
+        "house_number_and_street": "address",
+        "city": "city",
+        "state": "state"
+    }
+}"#;
+
+/// Build a command which geocodes using `mock`.
+fn smarty_cmd(testdir: &TestDir, mock: &MockSmarty) -> Command {
+    testdir.create_file("spec.json", SIMPLE_SPEC);
+    let mut cmd = testdir.cmd();
+    cmd.env("SMARTY_AUTH_ID", mock_smarty::AUTH_ID)
+        .env("SMARTY_AUTH_TOKEN", mock_smarty::AUTH_TOKEN)
+        .arg("--spec=spec.json")
+        .arg(format!("--smarty-url={}", mock.url()));
+    cmd
+}
+
+/// Parse CSV `output`, and return the values of `column` for each row.
+fn column_values(output: &str, column: &str) -> Vec<String> {
+    let mut rdr = csv::Reader::from_reader(output.as_bytes());
+    let idx = rdr
+        .headers()
+        .unwrap()
+        .iter()
+        .position(|h| h == column)
+        .unwrap_or_else(|| panic!("no column {:?}", column));
+    rdr.records()
+        .map(|row| row.unwrap()[idx].to_owned())
+        .collect()
+}
+
+#[test]
+fn geocode_with_mock_smarty() {
+    let testdir = TestDir::new("geocode-csv", "geocode_with_mock_smarty");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    let output = smarty_cmd(&testdir, &mock)
+        .output_with_stdin(SIMPLE_CSV)
+        .expect_success();
+    let stdout = output.stdout_str();
+    assert_eq!(
+        column_values(stdout, "gc_latitude"),
+        &["40.74842", "39.80172", ""],
+    );
+    assert_eq!(
+        column_values(stdout, "gc_rdi"),
+        &["Commercial", "Residential", ""],
+    );
+}
+
+#[test]
+fn multiple_candidates_with_mock_smarty() {
+    let testdir = TestDir::new("geocode-csv", "multiple_candidates_with_mock_smarty");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    let output = smarty_cmd(&testdir, &mock)
+        .arg("--max-candidates=2")
+        .output_with_stdin(SIMPLE_CSV)
+        .expect_success();
+    let stdout = output.stdout_str();
+    assert_eq!(
+        column_values(stdout, "gc_1_state_abbreviation"),
+        &["NY", "IL", ""],
+    );
+    assert_eq!(
+        column_values(stdout, "gc_2_state_abbreviation"),
+        &["", "MA", ""],
+    );
+}
+
+#[test]
+fn isolate_address_rejected_by_mock_smarty() {
+    let testdir =
+        TestDir::new("geocode-csv", "isolate_address_rejected_by_mock_smarty");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    // Our mock rejects any request containing "Unit 5" with a 422 error, so we
+    // need to split our request to geocode the other addresses.
+    let output = smarty_cmd(&testdir, &mock)
+        .arg("--rejects-path=rejects.csv")
+        .arg("--on-error=continue")
+        .output_with_stdin(
+            "address,city,state
+20 W 34th St,New York,NY
+Unit 5,New York,NY
+1 Main St,Springfield,
+",
+        )
+        .expect_success();
+    let stdout = output.stdout_str();
+    assert_eq!(
+        column_values(stdout, "gc_latitude"),
+        &["40.74842", "", "39.80172"],
+    );
+    assert_eq!(
+        column_values(stdout, "gc_error"),
+        &["", "rejected_by_geocoder", ""],
+    );
+    testdir.expect_file_contents(
+        "rejects.csv",
+        "address,city,state,geocode_prefix,geocode_failure
+Unit 5,New York,NY,gc,rejected_by_geocoder
+",
+    );
+}
+
+#[test]
+fn retry_when_rate_limited_by_mock_smarty() {
+    let testdir =
+        TestDir::new("geocode-csv", "retry_when_rate_limited_by_mock_smarty");
+    let mock = MockSmarty::start("addresses.json", 1);
+
+    let output = smarty_cmd(&testdir, &mock)
+        .arg("--max-retries=1")
+        .output_with_stdin(SIMPLE_CSV)
+        .expect_success();
+    assert!(output.stdout_str().contains("40.74842"));
+    assert_eq!(mock.request_count(), 2);
+}
+
+#[test]
+fn bad_credentials_fail_fast_with_mock_smarty() {
+    let testdir =
+        TestDir::new("geocode-csv", "bad_credentials_fail_fast_with_mock_smarty");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    let output = smarty_cmd(&testdir, &mock)
+        .env("SMARTY_AUTH_TOKEN", "wrong")
+        .output_with_stdin(SIMPLE_CSV)
+        .expect("could not run geocode-csv");
+    assert!(!output.status.success());
+    assert!(output.stderr_str().contains("SMARTY_AUTH_TOKEN"));
+    assert_eq!(mock.request_count(), 1);
+}
+
+/// A CSV file containing an address which always causes a server error.
+const SERVER_ERROR_CSV: &str = "address,city,state
+20 W 34th St,New York,NY
+500 Error St,Nowhere,ZZ
+";
+
+#[test]
+fn continue_after_errors_with_mock_smarty() {
+    let testdir =
+        TestDir::new("geocode-csv", "continue_after_errors_with_mock_smarty");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    let output = smarty_cmd(&testdir, &mock)
+        .arg("--max-retries=0")
+        .arg("--on-error=continue")
+        .output_with_stdin(SERVER_ERROR_CSV)
+        .expect_success();
+    let stdout = output.stdout_str();
+    assert_eq!(column_values(stdout, "gc_latitude"), &["", ""]);
+    assert_eq!(column_values(stdout, "gc_error"), &["http_500", "http_500"]);
+}
+
+#[test]
+fn unavailable_without_retry_after_is_an_error_with_mock_smarty() {
+    let testdir = TestDir::new(
+        "geocode-csv",
+        "unavailable_without_retry_after_is_an_error_with_mock_smarty",
+    );
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    // A 503 without `Retry-After` isn't throttling, so it counts against
+    // `--max-retries`.
+    let output = smarty_cmd(&testdir, &mock)
+        .arg("--max-retries=0")
+        .arg("--on-error=continue")
+        .output_with_stdin("address,city,state\n503 Error St,Nowhere,ZZ\n")
+        .expect_success();
+    assert_eq!(
+        column_values(output.stdout_str(), "gc_error"),
+        &["http_503"]
+    );
+    assert_eq!(mock.request_count(), 1);
+}
+
+#[test]
+fn fail_when_max_errors_exceeded_with_mock_smarty() {
+    let testdir = TestDir::new(
+        "geocode-csv",
+        "fail_when_max_errors_exceeded_with_mock_smarty",
+    );
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    let output = smarty_cmd(&testdir, &mock)
+        .arg("--max-retries=0")
+        .arg("--on-error=continue")
+        .arg("--max-errors=1")
+        .output_with_stdin(SERVER_ERROR_CSV)
+        .expect("could not run geocode-csv");
+    assert!(!output.status.success());
+    assert!(output.stderr_str().contains("--max-errors=1"));
+    // We still write all our output.
+    assert_eq!(
+        column_values(output.stdout_str(), "gc_error"),
+        &["http_500", "http_500"],
+    );
+}
+
+#[test]
+fn summary_json_with_mock_smarty() {
+    let testdir = TestDir::new("geocode-csv", "summary_json_with_mock_smarty");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    smarty_cmd(&testdir, &mock)
+        .arg("--summary-json=summary.json")
+        .output_with_stdin(SIMPLE_CSV)
+        .expect_success();
+    let summary: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string(testdir.path("summary.json")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(summary["success"], true);
+    assert_eq!(summary["rows"]["read"], 3);
+    assert_eq!(summary["rows"]["written"], 3);
+    let gc = &summary["prefixes"]["gc"];
+    assert_eq!(gc["addresses"], 3);
+    assert_eq!(gc["found"], 2);
+    assert_eq!(gc["unknown"], 1);
+    assert_eq!(gc["precision"]["Zip9"], 2);
+    assert_eq!(summary["chunks"]["failed"], 0);
+}
+
+#[test]
+#[cfg(unix)]
+fn stop_gracefully_on_sigterm_with_mock_smarty() {
+    use std::{process::Stdio, thread, time::Duration};
+
+    let testdir =
+        TestDir::new("geocode-csv", "stop_gracefully_on_sigterm_with_mock_smarty");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    // Build an input large enough to take a few seconds at our rate limit.
+    let mut input = "address,city,state\n".to_owned();
+    for _ in 0..20_000 {
+        input.push_str("20 W 34th St,New York,NY\n");
+    }
+    testdir.create_file("input.csv", &input);
+
+    let child = smarty_cmd(&testdir, &mock)
+        .args(["--input=input.csv", "--output=output.csv"])
+        .arg("--checkpoint=checkpoint.json")
+        .arg("--summary-json=summary.json")
+        .arg("--max-addresses-per-second=7200")
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("could not start geocode-csv");
+    thread::sleep(Duration::from_millis(500));
+    let killed = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .expect("could not run kill");
+    assert!(killed.success());
+    let output = child.wait_with_output().expect("could not wait for child");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+
+    // Our output should be valid, and should contain exactly the rows that we
+    // report having processed.
+    let written = column_values(
+        &std::fs::read_to_string(testdir.path("output.csv")).unwrap(),
+        "gc_latitude",
+    );
+    assert!(written.len() < 20_000);
+    assert!(written.iter().all(|lat| lat == "40.74842"));
+    assert!(
+        stderr.contains(&format!(
+            "stopped early after writing {} rows in this run",
+            written.len(),
+        )),
+        "unexpected stderr: {}",
+        stderr,
+    );
+    let read_json = |path| -> serde_json::Value {
+        serde_json::from_str(&std::fs::read_to_string(testdir.path(path)).unwrap())
+            .unwrap()
+    };
+    let summary = read_json("summary.json");
+    assert_eq!(summary["interrupted"], true);
+    assert_eq!(summary["rows"]["read"], written.len());
+    assert_eq!(read_json("checkpoint.json")["input_rows"], written.len());
+}
+
+#[test]
+fn resume_truncates_side_files_with_mock_smarty() {
+    use std::fs;
+
+    let testdir = TestDir::new(
+        "geocode-csv",
+        "resume_truncates_side_files_with_mock_smarty",
+    );
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    // Insert a malformed row after our first address.
+    let mut lines = SIMPLE_CSV.lines().collect::<Vec<_>>();
+    lines.insert(2, "oops,Nowhere,ZZ,extra");
+    testdir.create_file("input.csv", format!("{}\n", lines.join("\n")));
+    let run = |resume: bool| {
+        let mut cmd = smarty_cmd(&testdir, &mock);
+        cmd.args(["--input=input.csv", "--output=output.csv"])
+            .args(["--batch-size=1", "--concurrency=1"])
+            .arg("--checkpoint=checkpoint.json")
+            .arg("--checkpoint-every=1")
+            .arg("--on-bad-row=quarantine")
+            .arg("--quarantine-path=quarantine.csv")
+            .arg("--rejects-path=rejects.csv")
+            .arg("--summary-json=summary.json");
+        if resume {
+            cmd.arg("--resume");
+        }
+        cmd.expect_success();
+    };
+    run(false);
+    let read = |path| fs::read_to_string(testdir.path(path)).unwrap();
+    let expected_output = read("output.csv");
+    let expected_quarantine = read("quarantine.csv");
+    assert_eq!(expected_quarantine.matches("oops").count(), 1);
+    let expected_rejects = "address,city,state,geocode_prefix,geocode_failure
+123 Nowhere Ln,Nowhere,ZZ,gc,no_match
+";
+    assert_eq!(read("rejects.csv"), expected_rejects);
+
+    // Pretend we were interrupted after the checkpoint for our first address,
+    // but after we had already written everything else.
+    let mut checkpoint =
+        serde_json::from_str::<serde_json::Value>(&read("checkpoint.json")).unwrap();
+    let output_bytes = expected_output
+        .split_inclusive('\n')
+        .take(2)
+        .map(str::len)
+        .sum::<usize>();
+    checkpoint["input_rows"] = 1.into();
+    checkpoint["output_bytes"] = output_bytes.into();
+    checkpoint["quarantine_bytes"] = "line,error,raw\n".len().into();
+    checkpoint["rejects_bytes"] = 0.into();
+    testdir.create_file("checkpoint.json", checkpoint.to_string());
+
+    run(true);
+    assert_eq!(read("output.csv"), expected_output);
+    assert_eq!(read("quarantine.csv"), expected_quarantine);
+    assert_eq!(read("rejects.csv"), expected_rejects);
+
+    // Our summary shouldn't count the row we skipped.
+    let summary =
+        serde_json::from_str::<serde_json::Value>(&read("summary.json")).unwrap();
+    assert_eq!(summary["rows"]["read"], 3);
+}
+
+#[test]
+fn batch_size_with_mock_smarty() {
+    let testdir = TestDir::new("geocode-csv", "batch_size_with_mock_smarty");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    let output = smarty_cmd(&testdir, &mock)
+        .args(["--batch-size=1", "--concurrency=1", "--channel-depth=1"])
+        .output_with_stdin(SIMPLE_CSV)
+        .expect_success();
+    assert_eq!(
+        column_values(output.stdout_str(), "gc_latitude"),
+        &["40.74842", "39.80172", ""],
+    );
+    assert_eq!(mock.request_count(), 3);
+}
+
+#[test]
+fn fixed_concurrency_with_mock_smarty() {
+    let testdir = TestDir::new("geocode-csv", "fixed_concurrency_with_mock_smarty");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    let output = smarty_cmd(&testdir, &mock)
+        .args(["--batch-size=1", "--concurrency=2", "--fixed-concurrency"])
+        .output_with_stdin(SIMPLE_CSV)
+        .expect_success();
+    assert_eq!(
+        column_values(output.stdout_str(), "gc_latitude"),
+        &["40.74842", "39.80172", ""],
+    );
+}
+
+#[test]
+fn batch_size_too_large_for_smarty() {
+    let testdir = TestDir::new("geocode-csv", "batch_size_too_large_for_smarty");
+    let mock = MockSmarty::start("addresses.json", 0);
+
+    let output = smarty_cmd(&testdir, &mock)
+        .arg("--batch-size=101")
+        .output_with_stdin(SIMPLE_CSV)
+        .expect("could not run geocode-csv");
+    assert!(!output.status.success());
+    assert!(output.stderr_str().contains("--batch-size"));
+    assert_eq!(mock.request_count(), 0);
+}
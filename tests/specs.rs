@@ -1,15 +1,29 @@
 //! Specifying columns to geocode.
 
+use std::process::Command;
+
 use cli_test_dir::*;
 
+mod mock_smarty;
+
+use mock_smarty::MockSmarty;
+
 /// A CSV file to geocode. Contains the empire state building.
 const SIMPLE_CSV: &str = "address_1,address_2,city,state,zip_code
 20 W 34th St,,New York,NY,10118
 1224 S 760 W,,Provo,UT,
 ";
 
+/// Create a command which geocodes using our mock Smarty server.
+fn smarty_cmd(testdir: &TestDir, mock: &MockSmarty) -> Command {
+    let mut cmd = testdir.cmd();
+    cmd.env("SMARTY_AUTH_ID", mock_smarty::AUTH_ID)
+        .env("SMARTY_AUTH_TOKEN", mock_smarty::AUTH_TOKEN)
+        .arg(format!("--smarty-url={}", mock.url()));
+    cmd
+}
+
 #[test]
-#[ignore]
 fn all_fields() {
     let testdir = TestDir::new("geocode-csv", "all_fields");
 
@@ -27,8 +41,8 @@ fn all_fields() {
     }
 }"#,
     );
-    let output = testdir
-        .cmd()
+    let mock = MockSmarty::start("addresses.json", 0);
+    let output = smarty_cmd(&testdir, &mock)
         .arg("--license=us-core-enterprise-cloud")
         .arg("--spec=spec.json")
         .output_with_stdin(SIMPLE_CSV)
@@ -71,7 +85,6 @@ fn all_fields() {
 // }
 
 #[test]
-#[ignore]
 fn single_address_field() {
     let testdir = TestDir::new("geocode-csv", "single_address_field");
 
@@ -87,8 +100,8 @@ fn single_address_field() {
 }"#,
     );
 
-    let output = testdir
-        .cmd()
+    let mock = MockSmarty::start("addresses.json", 0);
+    let output = smarty_cmd(&testdir, &mock)
         .arg("--license=us-core-enterprise-cloud")
         .arg("--spec=spec.json")
         .output_with_stdin(SIMPLE_CSV)
@@ -98,7 +111,6 @@ fn single_address_field() {
 }
 
 #[test]
-#[ignore]
 fn no_city_or_state() {
     let testdir = TestDir::new("geocode-csv", "no_city_or_state");
 
@@ -115,8 +127,8 @@ fn no_city_or_state() {
 }"#,
     );
 
-    let output = testdir
-        .cmd()
+    let mock = MockSmarty::start("addresses.json", 0);
+    let output = smarty_cmd(&testdir, &mock)
         .arg("--license=us-core-enterprise-cloud")
         .arg("--spec=spec.json")
         .output_with_stdin(SIMPLE_CSV)
@@ -126,7 +138,6 @@ fn no_city_or_state() {
 }
 
 #[test]
-#[ignore]
 fn freeform() {
     let testdir = TestDir::new("geocode-csv", "freeform");
 
@@ -145,8 +156,8 @@ fn freeform() {
 }"#,
     );
 
-    let output = testdir
-        .cmd()
+    let mock = MockSmarty::start("addresses.json", 0);
+    let output = smarty_cmd(&testdir, &mock)
         .arg("--license=us-core-enterprise-cloud")
         .arg("--spec=spec.json")
         .output_with_stdin(SIMPLE_CSV)
@@ -156,7 +167,6 @@ fn freeform() {
 }
 
 #[test]
-#[ignore]
 fn multiple_addresses() {
     let testdir = TestDir::new("geocode-csv", "multiple_addresses");
 
@@ -180,8 +190,8 @@ fn multiple_addresses() {
 }"#,
     );
 
-    let output = testdir
-        .cmd()
+    let mock = MockSmarty::start("addresses.json", 0);
+    let output = smarty_cmd(&testdir, &mock)
         .arg("--license=us-core-enterprise-cloud")
         .arg("--spec=spec.json")
         .output_with_stdin(SIMPLE_CSV)
@@ -191,7 +201,6 @@ fn multiple_addresses() {
 }
 
 #[test]
-#[ignore]
 fn rate_limiter() {
     let testdir = TestDir::new("geocode-csv", "rate_limiter");
 
@@ -208,8 +217,8 @@ fn rate_limiter() {
 }"#,
     );
 
-    let output = testdir
-        .cmd()
+    let mock = MockSmarty::start("addresses.json", 0);
+    let output = smarty_cmd(&testdir, &mock)
         .arg("--license=us-core-enterprise-cloud")
         .arg("--spec=spec.json")
         .arg("--max-addresses-per-second=300")
@@ -219,7 +228,6 @@ fn rate_limiter() {
 }
 
 #[test]
-#[ignore]
 fn skip_records_with_empty_house_number_and_street() {
     let testdir = TestDir::new(
         "geocode-csv",
@@ -239,8 +247,8 @@ fn skip_records_with_empty_house_number_and_street() {
 }"#,
     );
 
-    let output = testdir
-        .cmd()
+    let mock = MockSmarty::start("addresses.json", 0);
+    let output = smarty_cmd(&testdir, &mock)
         .arg("--license=us-core-enterprise-cloud")
         .arg("--spec=spec.json")
         .output_with_stdin(
@@ -254,8 +262,10 @@ fn skip_records_with_empty_house_number_and_street() {
     assert!(output.stdout_str().contains("shipping_addressee"));
     assert!(output.stdout_str().contains("New York"));
     assert!(output.stdout_str().contains("Provo"));
+    assert_eq!(mock.request_count(), 0);
 }
 
+// This needs libpostal data, so run it using `just test-full`.
 #[test]
 #[ignore]
 fn append_libpostal() {
@@ -275,8 +285,8 @@ fn append_libpostal() {
     }
 }"#,
     );
-    let output = testdir
-        .cmd()
+    let mock = MockSmarty::start("addresses.json", 0);
+    let output = smarty_cmd(&testdir, &mock)
         .arg("--license=us-core-enterprise-cloud")
         .arg("--spec=spec.json")
         .arg("--include-libpostal")